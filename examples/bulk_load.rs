@@ -0,0 +1,42 @@
+use nuclia_vectors::vectors::{BulkLoader, Reader, Writer};
+use rand::prelude::*;
+use std::{env, time::Instant};
+use tempfile::TempDir;
+
+fn random_vector(rng: &mut impl Rng, n_dim: usize) -> Vec<f32> {
+    (0..n_dim).map(|_| rng.gen()).collect()
+}
+
+fn main() {
+    let t0 = Instant::now();
+    let n_dim: usize = env::var("DIMENSIONS")
+        .unwrap_or("100".to_string())
+        .parse()
+        .unwrap();
+    let n_vectors: usize = env::var("N_VECTORS")
+        .unwrap_or("200000".to_string())
+        .parse()
+        .unwrap();
+
+    eprintln!("n_dim: {}, n_vectors: {}", n_dim, n_vectors);
+
+    let tmpdir = TempDir::new().unwrap();
+    let mut writer = Writer::open(tmpdir.path()).unwrap();
+
+    // The vectors are generated as they are loaded, never all of them at once.
+    let mut rng = StdRng::seed_from_u64(0);
+    let vectors = (0..n_vectors).map(|doc_id| (doc_id, random_vector(&mut rng, n_dim)));
+
+    eprintln!("Loading vectors - {:?}", t0.elapsed());
+    let mut loader = BulkLoader::new(&mut writer).unwrap();
+    let report = loader.load(vectors).unwrap();
+    eprintln!("Loaded {} vectors, building the index - {:?}", report.pushed, t0.elapsed());
+    let generation = loader.finish().unwrap();
+    eprintln!("Committed generation {} - {:?}", generation, t0.elapsed());
+
+    let reader = Reader::open(tmpdir.path()).unwrap();
+    let mut rng = StdRng::seed_from_u64(0);
+    let first = random_vector(&mut rng, n_dim);
+    let res = reader.search_vec(first).unwrap();
+    eprintln!("Closest to document 0: {:?} - {:?}", &res[..res.len().min(5)], t0.elapsed());
+}
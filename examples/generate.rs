@@ -1,6 +1,6 @@
-use std::{time::Instant, env};
+use std::{env, io::Write, time::Instant};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use granne::{angular::{self, Vector}, GranneBuilder, BuildConfig, Index, Granne};
+use granne::{angular::{self, Vector}, GranneBuilder, BuildConfig, Index, Writeable};
 use rand::prelude::*;
 
 fn random_vector(n_dim: usize) -> Vector<'static> {
@@ -61,26 +61,31 @@ fn main() {
     
 
 
+    // The vectors of each chunk are appended to the file, after its header and the vectors written
+    // before, instead of mapping the file and rewriting all of it.
+    let mut elements_file = std::fs::OpenOptions::new()
+        .append(true)
+        .open("data/elements.dat")
+        .unwrap();
     for i_chunk in 0..n_chunks {
         eprintln!("Chunk {}/{}", i_chunk, n_chunks);
-        
-        let elements_file = std::fs::File::open("data/elements.dat").unwrap();
-    
-        eprintln!("loading (memory-mapping) vectors - {:?}", t0.elapsed());
-        let mut elements = unsafe { angular::Vectors::from_file(&elements_file).unwrap() };        
-        
+
         eprintln!("Generating vectors in parallel");
         let vectors: Vec<_> = (0..n_vectors/n_chunks).into_par_iter().map(|_| random_vector(n_dim)).collect();
-    
+
         eprintln!("Inserting vectors into the collection - {:?}", t0.elapsed());
+        let mut elements: angular::Vectors = granne::angular::Vectors::new();
         for v in vectors {
             elements.push(&v);
         }
-    
-        let mut elements_file = std::fs::File::create("data/elements.dat").unwrap();
-        let builder = GranneBuilder::new(BuildConfig::default(), elements);
-        eprintln!("Writing elements to file - {:?}", t0.elapsed());
-        builder.write_elements(&mut elements_file).unwrap();
+
+        eprintln!("Appending elements to file - {:?}", t0.elapsed());
+        let mut chunk = Vec::new();
+        elements.write(&mut chunk).unwrap();
+        elements_file.write_all(&chunk[std::mem::size_of::<u64>()..]).unwrap();
     }
 
-}
\ No newline at end of file
+    let elements_file = std::fs::File::open("data/elements.dat").unwrap();
+    let elements = unsafe { angular::Vectors::from_file(&elements_file).unwrap() };
+    eprintln!("{} vectors in the file - {:?}", elements.len(), t0.elapsed());
+}
@@ -11,9 +11,7 @@ This data is made available under the Public Domain Dedication and License v1.0
 full text can be found at: http://www.opendatacommons.org/licenses/pddl/1.0/
 */
 
-use granne::{self, Builder, ElementContainer};
 use nuclia_vectors::vectors::{Writer, Reader};
-use rand::distributions::uniform::SampleBorrow;
 use tempfile::TempDir;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
@@ -46,11 +44,11 @@ fn main() -> std::io::Result<()> {
 
 
     writer.push_batch(&idxs[0..10000], &vectors[0..10000]).unwrap();
-    writer.commit();
+    writer.commit().unwrap();
 
     let reader = Reader::open(tmpdir.path()).unwrap();
     for &i in &[0, 134, 5555, 9999] {
-        let res = reader.search(&vectors[i]);
+        let res = reader.search(&vectors[i]).unwrap();
         let res: Vec<_> = res.into_iter().map(|(j, d)| (&tokens[j], d)).collect();
 
         println!("\nThe closest words to \"{}\" are: \n{:?}", &tokens[i], res);
@@ -0,0 +1,113 @@
+/*!
+Compares the recall and the size of an index searched through its graph and through its
+product-quantization codes.
+# Example
+```
+DIMENSIONS=128 N_VECTORS=20000 SUBSPACES=16 cargo run --release --example pq_benchmark
+```
+*/
+
+use nuclia_vectors::vectors::{Metric, PqConfig, Reader, ReaderConfig, Writer, WriterConfig};
+use rand::prelude::*;
+use std::{env, path::Path, time::Instant};
+use tempfile::TempDir;
+
+fn var(name: &str, default: usize) -> usize {
+    env::var(name).unwrap_or(default.to_string()).parse().unwrap()
+}
+
+fn normalized(values: &[f32]) -> Vec<f32> {
+    let norm = values.iter().map(|x| x * x).sum::<f32>().sqrt();
+    values.iter().map(|x| x / norm).collect()
+}
+
+fn size(dir: &Path, file: &str) -> u64 {
+    std::fs::metadata(dir.join(file)).map(|m| m.len()).unwrap_or(0)
+}
+
+fn main() {
+    let n_dim = var("DIMENSIONS", 128);
+    let n_vectors = var("N_VECTORS", 20000);
+    let n_queries = var("N_QUERIES", 100);
+    let n_results = var("N_RESULTS", 10);
+    let subspaces = var("SUBSPACES", 16);
+    let rerank_depth = var("RERANK_DEPTH", 200);
+
+    eprintln!(
+        "n_dim: {}, n_vectors: {}, n_queries: {}, n_results: {}, subspaces: {}, rerank_depth: {}",
+        n_dim, n_vectors, n_queries, n_results, subspaces, rerank_depth
+    );
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let vectors: Vec<Vec<f32>> = (0..n_vectors)
+        .map(|_| (0..n_dim).map(|_| rng.gen_range(-1.0..1.0)).collect())
+        .collect();
+    let queries: Vec<Vec<f32>> = (0..n_queries)
+        .map(|_| (0..n_dim).map(|_| rng.gen_range(-1.0..1.0)).collect())
+        .collect();
+
+    let tmpdir = TempDir::new().unwrap();
+    let t0 = Instant::now();
+    let config = WriterConfig::new().product_quantization(PqConfig::new(subspaces));
+    let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+    for (doc_id, vector) in vectors.iter().enumerate() {
+        writer.push_vec(doc_id, vector.clone()).unwrap();
+    }
+    writer.commit().unwrap();
+    eprintln!("Index built in {:?}", t0.elapsed());
+
+    let normalized_vectors: Vec<_> = vectors.iter().map(|v| normalized(v)).collect();
+    let exact: Vec<Vec<usize>> = queries
+        .iter()
+        .map(|query| {
+            let query = normalized(query);
+            let mut distances: Vec<_> = normalized_vectors
+                .iter()
+                .enumerate()
+                .map(|(doc_id, v)| (doc_id, Metric::Angular.distance(v, &query)))
+                .collect();
+            distances.sort_by(|a, b| a.1.total_cmp(&b.1));
+            distances.into_iter().take(n_results).map(|(doc_id, _)| doc_id).collect()
+        })
+        .collect();
+
+    let configs = [
+        ("graph", ReaderConfig::new().num_neighbors(n_results)),
+        (
+            "pq scan",
+            ReaderConfig::new().num_neighbors(n_results).product_quantization(true),
+        ),
+        (
+            "pq scan + rerank",
+            ReaderConfig::new()
+                .num_neighbors(n_results)
+                .rerank_depth(rerank_depth)
+                .product_quantization(true),
+        ),
+    ];
+    for (name, config) in configs {
+        let reader = Reader::open_with_config(tmpdir.path(), config).unwrap();
+        let t0 = Instant::now();
+        let mut hits = 0;
+        for (query, expected) in queries.iter().zip(&exact) {
+            let res = reader.search_vec(query.clone()).unwrap();
+            hits += res.iter().filter(|(doc_id, _)| expected.contains(doc_id)).count();
+        }
+        println!(
+            "{:>16}: recall@{} {:.3}, {:?} per query",
+            name,
+            n_results,
+            hits as f32 / (n_queries * n_results) as f32,
+            t0.elapsed() / n_queries as u32
+        );
+    }
+
+    let elements_size = size(tmpdir.path(), "elements.dat");
+    let pq_size = size(tmpdir.path(), "pq.bin");
+    println!(
+        "elements.dat: {} bytes, pq.bin: {} bytes ({:.1}x smaller)",
+        elements_size,
+        pq_size,
+        elements_size as f32 / pq_size as f32
+    );
+}
@@ -22,12 +22,12 @@ fn main() {
         println!("{}", v.text);
         writer.push_vec(i, v.encoding.clone()).unwrap();
     }
-    writer.commit();
+    writer.commit().unwrap();
     println!("==============================");
 
 
     let reader = Reader::open(tmpdir.path()).unwrap();
-    let res = reader.search_vec(vecs[0].encoding.clone());
+    let res = reader.search_vec(vecs[0].encoding.clone()).unwrap();
 
     for (doc_id, score) in res {
         let doc = &vecs[doc_id].text;
@@ -0,0 +1,318 @@
+//! Async facades for tokio applications.
+//!
+//! Searches, pushes and commits block on mmap'd files, LMDB and granne, so they must not run on
+//! the threads of the runtime. The `AsyncReader` runs its searches on a pool of its own and the
+//! `AsyncWriter` hands its commands to a thread that owns the `Writer`. Both accept a bounded
+//! number of operations and make callers wait beyond it.
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+use granne::angular::Vector;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+
+use super::{AsyncConfig, Filter, Metadata, Reader, ReaderConfig, VectorError, Writer, WriterConfig};
+
+fn stopped(message: &str) -> VectorError {
+    VectorError::Stopped(message.to_string())
+}
+
+/// Runs blocking code of an `open` on the blocking threads of the runtime.
+async fn open_blocking<T, F>(f: F) -> Result<T, VectorError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, VectorError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| VectorError::Stopped(e.to_string()))?
+}
+
+/// A `Reader` searched from async code, on a dedicated thread pool.
+///
+/// The threads of the pool share the reader: a reload swaps in the next generation of the index
+/// while the searches running keep the one they started with.
+pub struct AsyncReader {
+    reader: Arc<Reader<'static>>,
+    pool: rayon::ThreadPool,
+    permits: Semaphore,
+}
+
+impl AsyncReader {
+    pub async fn open<T: Into<PathBuf>>(
+        location: T,
+        config: ReaderConfig,
+        async_config: AsyncConfig,
+    ) -> Result<Self, VectorError> {
+        let location = location.into();
+        let reader = open_blocking(move || Reader::open_with_config(location, config)).await?;
+        AsyncReader::new(reader, async_config)
+    }
+
+    /// Serves `reader` with `config.threads` threads and up to `config.queue_size` searches in
+    /// flight.
+    pub fn new(reader: Reader<'static>, config: AsyncConfig) -> Result<Self, VectorError> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(config.threads)
+            .thread_name(|i| format!("vectors-search-{}", i))
+            .panic_handler(|_| error!("A search panicked"))
+            .build()
+            .map_err(|e| VectorError::Stopped(e.to_string()))?;
+
+        Ok(AsyncReader {
+            reader: Arc::new(reader),
+            pool,
+            permits: Semaphore::new(config.queue_size.max(1)),
+        })
+    }
+
+    /// Runs `f` on the pool, once a search slot is free.
+    async fn run<T, F>(&self, f: F) -> Result<T, VectorError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Reader<'static>) -> Result<T, VectorError> + Send + 'static,
+    {
+        let _permit = self.permits.acquire().await.map_err(|_| stopped("the reader is closed"))?;
+        let (sender, receiver) = oneshot::channel();
+        let reader = self.reader.clone();
+        self.pool.spawn(move || {
+            let _ = sender.send(f(&reader));
+        });
+        receiver.await.map_err(|_| stopped("the search panicked"))?
+    }
+
+    pub async fn search(&self, query_vector: Vector<'static>) -> Result<Vec<(usize, f32)>, VectorError> {
+        self.run(move |reader| reader.search(&query_vector)).await
+    }
+
+    pub async fn search_vec(&self, query_vector: Vec<f32>) -> Result<Vec<(usize, f32)>, VectorError> {
+        self.run(move |reader| reader.search_vec(query_vector)).await
+    }
+
+    pub async fn search_filtered(
+        &self,
+        query_vector: Vector<'static>,
+        filter: Filter,
+    ) -> Result<Vec<(usize, f32)>, VectorError> {
+        self.run(move |reader| reader.search_filtered(&query_vector, &filter)).await
+    }
+}
+
+/// Operations sent to the thread of an `AsyncWriter`, with the channel of their result.
+enum Command {
+    Push {
+        doc_id: usize,
+        vector: Vector<'static>,
+        metadata: Metadata,
+        reply: oneshot::Sender<Result<(), VectorError>>,
+    },
+    PushVec {
+        doc_id: usize,
+        vector: Vec<f32>,
+        reply: oneshot::Sender<Result<(), VectorError>>,
+    },
+    Delete {
+        doc_id: usize,
+        reply: oneshot::Sender<Result<(), VectorError>>,
+    },
+    Commit {
+        reply: oneshot::Sender<Result<u64, VectorError>>,
+    },
+}
+
+/// A `Writer` owned by a thread of its own, which runs the commands sent from async code in
+/// order.
+pub struct AsyncWriter {
+    commands: mpsc::Sender<Command>,
+    actor: JoinHandle<()>,
+}
+
+impl AsyncWriter {
+    pub async fn open<T: Into<PathBuf>>(
+        location: T,
+        config: WriterConfig,
+        async_config: AsyncConfig,
+    ) -> Result<Self, VectorError> {
+        let location = location.into();
+        let writer = open_blocking(move || Writer::open_with_config(location, config)).await?;
+        AsyncWriter::new(writer, async_config)
+    }
+
+    /// Moves `writer` to its own thread, with up to `config.queue_size` commands queued.
+    pub fn new(writer: Writer<'static>, config: AsyncConfig) -> Result<Self, VectorError> {
+        let (commands, receiver) = mpsc::channel(config.queue_size.max(1));
+        let actor = thread::Builder::new()
+            .name("vectors-writer".to_string())
+            .spawn(move || AsyncWriter::run(writer, receiver))?;
+
+        Ok(AsyncWriter { commands, actor })
+    }
+
+    fn run(mut writer: Writer<'static>, mut receiver: mpsc::Receiver<Command>) {
+        while let Some(command) = receiver.blocking_recv() {
+            match command {
+                Command::Push {
+                    doc_id,
+                    vector,
+                    metadata,
+                    reply,
+                } => {
+                    let _ = reply.send(writer.push_with_metadata(doc_id, &vector, &metadata));
+                }
+                Command::PushVec { doc_id, vector, reply } => {
+                    let _ = reply.send(writer.push_vec(doc_id, vector));
+                }
+                Command::Delete { doc_id, reply } => {
+                    let _ = reply.send(writer.delete(doc_id));
+                }
+                Command::Commit { reply } => {
+                    let _ = reply.send(writer.commit());
+                }
+            }
+        }
+        debug!("Writer thread stopped");
+    }
+
+    /// Queues a command, waiting while the queue is full, and waits for its result.
+    async fn send<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> Result<T, VectorError> {
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .await
+            .map_err(|_| stopped("the writer thread stopped"))?;
+        result.await.map_err(|_| stopped("the writer thread stopped"))
+    }
+
+    pub async fn push(&self, doc_id: usize, vector: Vector<'static>) -> Result<(), VectorError> {
+        self.push_with_metadata(doc_id, vector, Metadata::default()).await
+    }
+
+    pub async fn push_with_metadata(
+        &self,
+        doc_id: usize,
+        vector: Vector<'static>,
+        metadata: Metadata,
+    ) -> Result<(), VectorError> {
+        self.send(|reply| Command::Push {
+            doc_id,
+            vector,
+            metadata,
+            reply,
+        })
+        .await?
+    }
+
+    pub async fn push_vec(&self, doc_id: usize, vector: Vec<f32>) -> Result<(), VectorError> {
+        self.send(|reply| Command::PushVec { doc_id, vector, reply }).await?
+    }
+
+    pub async fn delete(&self, doc_id: usize) -> Result<(), VectorError> {
+        self.send(|reply| Command::Delete { doc_id, reply }).await?
+    }
+
+    /// Commits once the commands queued before are done, see `Writer::commit`.
+    pub async fn commit(&self) -> Result<u64, VectorError> {
+        self.send(|reply| Command::Commit { reply }).await?
+    }
+
+    /// Runs the queued commands and closes the writer, releasing its lock.
+    pub async fn close(self) -> Result<(), VectorError> {
+        let AsyncWriter { commands, actor } = self;
+        drop(commands);
+        open_blocking(move || actor.join().map_err(|_| stopped("the writer thread panicked"))).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use futures::future::join_all;
+    use tempfile::TempDir;
+
+    use super::{AsyncReader, AsyncWriter};
+    use crate::vectors::{AsyncConfig, ReaderConfig, Schema, VectorError, Writer, WriterConfig};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn writer_and_reader() {
+        let tmpdir = TempDir::new().unwrap();
+        let config = AsyncConfig::new().threads(2).queue_size(2);
+        let writer = Arc::new(AsyncWriter::open(tmpdir.path(), WriterConfig::new(), config).await.unwrap());
+
+        // More concurrent pushes than the queue holds.
+        let pushes = (0..50).map(|doc_id| {
+            let writer = writer.clone();
+            tokio::spawn(async move { writer.push_vec(doc_id, vec![doc_id as f32 + 1.0, 1.0, -1.0]).await })
+        });
+        for push in join_all(pushes).await {
+            push.unwrap().unwrap();
+        }
+        assert!(writer.push_vec(99, vec![1.0]).await.is_err());
+        writer.delete(3).await.unwrap();
+        assert_eq!(writer.commit().await.unwrap(), 1);
+
+        let reader_config = ReaderConfig::new().num_neighbors(5);
+        let reader = AsyncReader::open(tmpdir.path(), reader_config, config).await.unwrap();
+        let searches = (0..20).map(|doc_id| reader.search_vec(vec![doc_id as f32 + 1.0, 1.0, -1.0]));
+        for (doc_id, res) in join_all(searches).await.into_iter().enumerate() {
+            let res = res.unwrap();
+            assert!(!res.is_empty() && res.len() <= 5);
+            assert!(res.iter().all(|(found, _)| *found != 3));
+            if doc_id != 3 {
+                assert_eq!(res[0].0, doc_id);
+            }
+        }
+
+        // Closing releases the lock of the index.
+        Arc::try_unwrap(writer).ok().unwrap().close().await.unwrap();
+        assert!(Writer::open(tmpdir.path()).is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn searches_during_commits() {
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        writer.push_vec(0, vec![1.0, 1.0, -1.0]).unwrap();
+        writer.commit().unwrap();
+
+        let config = AsyncConfig::new().threads(4).queue_size(8);
+        let reader = AsyncReader::open(tmpdir.path(), ReaderConfig::new(), config).await.unwrap();
+        let commits = tokio::task::spawn_blocking(move || {
+            for doc_id in 1..20 {
+                writer.push_vec(doc_id, vec![doc_id as f32 + 1.0, 1.0, -1.0]).unwrap();
+                writer.commit().unwrap();
+            }
+        });
+
+        // Every search of a batch runs on its own thread, some of them reloading the index.
+        while !commits.is_finished() {
+            let searches = (0..8).map(|_| reader.search_vec(vec![1.0, 1.0, -1.0]));
+            for res in join_all(searches).await {
+                assert_eq!(res.unwrap()[0].0, 0);
+            }
+        }
+        commits.await.unwrap();
+        assert_eq!(reader.search_vec(vec![20.0, 1.0, -1.0]).await.unwrap()[0].0, 19);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commit_errors() {
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        writer.add_space("title", Schema::new(2)).unwrap();
+        writer.push_to_space("title", 0, vec![1.0, 0.0]).unwrap();
+        writer.commit().unwrap();
+
+        // The graph of the space can't be extended.
+        let index_path = tmpdir.path().join("spaces/title/index.dat");
+        std::fs::remove_file(&index_path).unwrap();
+        std::fs::create_dir_all(index_path.join("blocked")).unwrap();
+        writer.push_to_space("title", 1, vec![0.0, 1.0]).unwrap();
+
+        let writer = AsyncWriter::new(writer, AsyncConfig::new()).unwrap();
+        assert!(matches!(writer.commit().await, Err(VectorError::Io(_))));
+    }
+}
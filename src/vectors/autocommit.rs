@@ -0,0 +1,327 @@
+//! Commits made by a thread of their own, following the `CommitPolicy` of a writer.
+//!
+//! The thread only holds the writer while it takes the snapshot of a commit, see
+//! `Writer::commit_async`, so writes go on while the graph is built. The ones arriving meanwhile
+//! are committed together once the build is over: a burst of writes costs a build or two, not
+//! one per write.
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use super::{CommitPolicy, VectorError, Writer};
+
+/// Longest time between two checks of the policy.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Longest wait before retrying a commit that failed, see `AutoCommit::run`.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Writes of a `Writer` that are not committed yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PendingWrites {
+    /// Vectors pushed, to any space or namespace.
+    pub vectors: usize,
+    /// Size of the vectors pushed, as they were pushed.
+    pub bytes: usize,
+    /// Time of the first write, deletions included. `None` when there is nothing to commit.
+    pub since: Option<Instant>,
+}
+
+impl PendingWrites {
+    pub fn is_empty(&self) -> bool {
+        self.since.is_none()
+    }
+
+    /// Whether the writes reach one of the limits of `policy`.
+    pub fn is_due(&self, policy: &CommitPolicy) -> bool {
+        let Some(since) = self.since else {
+            return false;
+        };
+        policy.max_vectors.is_some_and(|max| self.vectors >= max)
+            || policy.max_bytes.is_some_and(|max| self.bytes >= max)
+            || policy.max_age.is_some_and(|max| since.elapsed() >= max)
+    }
+
+    pub(crate) fn record(&mut self, vectors: usize, bytes: usize) {
+        self.vectors += vectors;
+        self.bytes += bytes;
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    /// Adds back the writes of a commit that didn't publish them.
+    pub(crate) fn restore(&mut self, other: PendingWrites) {
+        self.vectors += other.vectors;
+        self.bytes += other.bytes;
+        self.since = match (self.since, other.since) {
+            (Some(since), Some(other)) => Some(since.min(other)),
+            (since, other) => since.or(other),
+        };
+    }
+}
+
+/// Commits a shared writer whenever its `CommitPolicy` says it is due.
+///
+/// Stopping it, or dropping it, commits the writes left. `stop` returns the error of that commit;
+/// when the writer is dropped instead, the writes of a failed commit are still pending.
+pub struct AutoCommit {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+    /// Error of the last commit, if it failed.
+    error: Arc<Mutex<Option<VectorError>>>,
+}
+
+impl AutoCommit {
+    pub fn start(writer: Arc<Mutex<Writer<'static>>>) -> Result<Self, VectorError> {
+        let interval = writer
+            .lock()
+            .unwrap()
+            .commit_policy()
+            .max_age
+            .map_or(POLL_INTERVAL, |max_age| (max_age / 4).min(POLL_INTERVAL));
+        let (stop, stopped) = mpsc::channel();
+        let error = Arc::new(Mutex::new(None));
+        let thread_error = error.clone();
+        let thread = thread::Builder::new()
+            .name("vectors-autocommit".to_string())
+            .spawn(move || AutoCommit::run(writer, stopped, interval, thread_error))?;
+
+        Ok(AutoCommit {
+            stop: Some(stop),
+            thread: Some(thread),
+            error,
+        })
+    }
+
+    /// Commits when the policy says so. A commit that fails for another reason than a commit
+    /// running elsewhere is retried after a wait that doubles with every failure, up to
+    /// `MAX_BACKOFF`, and its error is kept for `take_error`.
+    fn run(
+        writer: Arc<Mutex<Writer<'static>>>,
+        stopped: mpsc::Receiver<()>,
+        interval: Duration,
+        error: Arc<Mutex<Option<VectorError>>>,
+    ) {
+        let mut failures = 0;
+        let mut retry_at = Instant::now();
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            if Instant::now() < retry_at {
+                continue;
+            }
+            let handle = {
+                let mut writer = writer.lock().unwrap();
+                if !writer.needs_commit() {
+                    continue;
+                }
+                writer.commit_async()
+            };
+
+            match handle.and_then(|handle| handle.wait()) {
+                Ok(generation) => {
+                    debug!("Committed generation {} automatically", generation);
+                    failures = 0;
+                    *error.lock().unwrap() = None;
+                }
+                Err(VectorError::Locked(message)) => trace!("Commit postponed: {}", message),
+                Err(e) => {
+                    // The writes of the commit are pending again, to be retried.
+                    writer.lock().unwrap().settle_commit();
+                    failures += 1;
+                    let backoff = interval.saturating_mul(1 << failures.min(16)).min(MAX_BACKOFF);
+                    error!("Error committing automatically, retrying in {:?}: {}", backoff, e);
+                    retry_at = Instant::now() + backoff;
+                    *error.lock().unwrap() = Some(e);
+                }
+            }
+        }
+
+        let mut writer = writer.lock().unwrap();
+        if !writer.pending().is_empty() {
+            let committed = writer.commit();
+            if let Err(e) = &committed {
+                error!("Error committing the writes left: {}", e);
+            }
+            *error.lock().unwrap() = committed.err();
+        }
+        debug!("Automatic commits stopped");
+    }
+
+    /// Returns the error of the last automatic commit if it failed, and forgets it. Failed commits
+    /// are retried with the writes they didn't publish.
+    pub fn take_error(&self) -> Option<VectorError> {
+        self.error.lock().unwrap().take()
+    }
+
+    /// Stops the thread once it committed the writes left, and returns the error of the last
+    /// commit if it failed.
+    pub fn stop(mut self) -> Result<(), VectorError> {
+        self.shutdown();
+        match self.take_error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("The automatic commit thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for AutoCommit {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use tempfile::TempDir;
+
+    use super::{AutoCommit, PendingWrites};
+    use crate::vectors::{CommitPolicy, Metric, Reader, VectorError, Writer, WriterConfig};
+
+    fn wait_for(reader: &Reader, generation: u64) {
+        let t0 = Instant::now();
+        while reader.generation() < generation {
+            assert!(t0.elapsed() < Duration::from_secs(30), "generation {} never came", generation);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn policy() {
+        let mut pending = PendingWrites::default();
+        let policy = CommitPolicy::new().max_vectors(2).max_bytes(100);
+        assert!(!pending.is_due(&policy));
+
+        pending.record(1, 12);
+        assert!(!pending.is_due(&policy));
+        assert!(pending.is_due(&CommitPolicy::new().max_age(Duration::ZERO)));
+        assert!(!pending.is_due(&CommitPolicy::new()));
+        pending.record(1, 12);
+        assert!(pending.is_due(&policy));
+        assert!(PendingWrites { bytes: 100, ..pending }.is_due(&CommitPolicy::new().max_bytes(100)));
+
+        // A deletion starts the clock without adding vectors.
+        let mut deleted = PendingWrites::default();
+        deleted.record(0, 0);
+        assert!(!deleted.is_empty());
+        assert!(!deleted.is_due(&policy));
+    }
+
+    #[test]
+    fn commits_when_due() {
+        let tmpdir = TempDir::new().unwrap();
+        let policy = CommitPolicy::new().max_vectors(10);
+        let config = WriterConfig::new().metric(Metric::Euclidean).commit_policy(policy);
+        let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+        writer.commit().unwrap();
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        let writer = Arc::new(Mutex::new(writer));
+        let auto_commit = AutoCommit::start(writer.clone()).unwrap();
+
+        for doc_id in 0..9 {
+            writer.lock().unwrap().push_vec(doc_id, vec![doc_id as f32 + 1.0, 1.0]).unwrap();
+        }
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(reader.generation(), 1);
+
+        // A burst of writes is committed by a single build.
+        {
+            let mut writer = writer.lock().unwrap();
+            for doc_id in 9..200 {
+                writer.push_vec(doc_id, vec![doc_id as f32 + 1.0, 1.0]).unwrap();
+            }
+        }
+        wait_for(&reader, 2);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(writer.lock().unwrap().generation(), 2);
+
+        // Stopping commits what is left, even below the limits.
+        writer.lock().unwrap().push_vec(200, vec![201.0, 1.0]).unwrap();
+        auto_commit.stop().unwrap();
+        let mut writer = writer.lock().unwrap();
+        assert!(writer.pending().is_empty());
+        writer.push_vec(500, vec![1.0, 2.0]).unwrap();
+        assert!(!writer.needs_commit());
+        assert_eq!(writer.generation(), 3);
+        assert_eq!(reader.search_vec(vec![201.0, 1.0]).unwrap()[0].0, 200);
+    }
+
+    #[test]
+    fn commits_after_max_age() {
+        let tmpdir = TempDir::new().unwrap();
+        let policy = CommitPolicy::new().max_vectors(1000).max_age(Duration::from_millis(20));
+        let config = WriterConfig::new().commit_policy(policy);
+        let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+        writer.commit().unwrap();
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        let writer = Arc::new(Mutex::new(writer));
+        let _auto_commit = AutoCommit::start(writer.clone()).unwrap();
+
+        writer.lock().unwrap().push_vec(3, vec![1.0, 2.0]).unwrap();
+        wait_for(&reader, 2);
+        assert_eq!(reader.search_vec(vec![1.0, 2.0]).unwrap()[0].0, 3);
+
+        // Deletions are writes too.
+        writer.lock().unwrap().delete(3).unwrap();
+        wait_for(&reader, 3);
+        assert!(reader.search_vec(vec![1.0, 2.0]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn backs_off_after_errors() {
+        let tmpdir = TempDir::new().unwrap();
+        let policy = CommitPolicy::new().max_vectors(1);
+        let config = WriterConfig::new().binary_quantization(true).commit_policy(policy);
+        let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+        writer.push_vec(1, vec![2.0, 1.0]).unwrap();
+        writer.commit().unwrap();
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        assert_eq!(reader.generation(), 1);
+        let writer = Arc::new(Mutex::new(writer));
+        let auto_commit = AutoCommit::start(writer.clone()).unwrap();
+
+        // The sign codes of the previous generation can't be read, so every commit fails until
+        // they are removed.
+        let codes_path = tmpdir.path().join("binary.dat");
+        std::fs::remove_file(&codes_path).unwrap();
+        std::fs::create_dir_all(codes_path.join("blocked")).unwrap();
+        writer.lock().unwrap().push_vec(3, vec![1.0, 2.0]).unwrap();
+        let t0 = Instant::now();
+        let error = loop {
+            if let Some(error) = auto_commit.take_error() {
+                break error;
+            }
+            assert!(t0.elapsed() < Duration::from_secs(30), "the commit never failed");
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert!(matches!(error, VectorError::Io(_)));
+        assert_eq!(reader.generation(), 1);
+
+        std::fs::remove_dir_all(&codes_path).unwrap();
+        wait_for(&reader, 2);
+        assert_eq!(reader.search_vec(vec![1.0, 2.0]).unwrap()[0].0, 3);
+        assert!(auto_commit.take_error().is_none());
+
+        // The commit of the writes left fails too, and they stay pending.
+        std::fs::remove_file(&codes_path).unwrap();
+        std::fs::create_dir_all(codes_path.join("blocked")).unwrap();
+        writer.lock().unwrap().push_vec(4, vec![1.0, 3.0]).unwrap();
+        assert!(matches!(auto_commit.stop(), Err(VectorError::Io(_))));
+        assert_eq!(writer.lock().unwrap().pending().vectors, 1);
+    }
+}
@@ -0,0 +1,365 @@
+use std::{fs::File, io, path::Path, sync::Arc};
+
+use granne::{BuildConfig, Builder, ElementContainer, Granne, GranneBuilder, Index};
+use memmap::Mmap;
+use ordered_float::NotNan;
+
+use super::{Elements, VectorError};
+
+/// How a `Reader` uses the sign codes of an index, when it has them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BinaryPrefilter {
+    /// The codes are not used.
+    #[default]
+    Off,
+    /// All the codes are compared with the query.
+    Scan,
+    /// The graph built over the codes is searched.
+    Graph,
+}
+
+/// Size of the header of the codes file, the number of words per code as a little-endian `u64`.
+const HEADER_LEN: usize = 8;
+
+#[derive(Clone)]
+enum Data {
+    Owned(Vec<u8>),
+    Mapped(Arc<Mmap>),
+}
+
+/// Sign bit of every component of a set of vectors, packed in `u64` words. Vectors are compared
+/// with the Hamming distance between their codes.
+///
+/// Words are little-endian after the header, both on disk and in memory.
+#[derive(Clone)]
+pub struct SignCodes {
+    words: usize,
+    data: Data,
+}
+
+impl Default for SignCodes {
+    fn default() -> Self {
+        SignCodes {
+            words: 0,
+            data: Data::Owned(Vec::new()),
+        }
+    }
+}
+
+impl std::fmt::Debug for SignCodes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignCodes").field("words", &self.words).field("len", &self.len()).finish()
+    }
+}
+
+impl PartialEq for SignCodes {
+    fn eq(&self, other: &Self) -> bool {
+        self.words == other.words && self.bytes() == other.bytes()
+    }
+}
+
+impl SignCodes {
+    pub fn new() -> Self {
+        SignCodes::default()
+    }
+
+    /// Memory-maps the codes stored in `file`.
+    ///
+    /// ## Safety
+    ///
+    /// The file must not be modified while it is mapped.
+    pub unsafe fn from_file(file: &File) -> io::Result<Self> {
+        if file.metadata()?.len() < HEADER_LEN as u64 {
+            return Ok(SignCodes::new());
+        }
+
+        let mmap = Mmap::map(file)?;
+        let mut header = [0; HEADER_LEN];
+        header.copy_from_slice(&mmap[..HEADER_LEN]);
+        Ok(SignCodes {
+            words: u64::from_le_bytes(header) as usize,
+            data: Data::Mapped(Arc::new(mmap)),
+        })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.data {
+            Data::Owned(bytes) => bytes,
+            Data::Mapped(mmap) => &mmap[HEADER_LEN..],
+        }
+    }
+
+    /// One bit per component, set when the component is positive.
+    pub fn encode(vector: &[f32]) -> Vec<u64> {
+        vector
+            .chunks(64)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, x)| **x > 0.0)
+                    .fold(0, |word, (bit, _)| word | 1 << bit)
+            })
+            .collect()
+    }
+
+    /// Pushes the code of `vector`. Mapped codes are copied to memory first.
+    pub fn push(&mut self, vector: &[f32]) {
+        if let Data::Mapped(mmap) = &self.data {
+            self.data = Data::Owned(mmap[HEADER_LEN..].to_vec());
+        }
+        let code = SignCodes::encode(vector);
+        self.words = code.len();
+        if let Data::Owned(bytes) = &mut self.data {
+            bytes.extend(code.iter().flat_map(|word| word.to_le_bytes()));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes().len().checked_div(8 * self.words).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn words(&self, idx: usize) -> impl Iterator<Item = u64> + '_ {
+        let row = 8 * self.words;
+        self.bytes()[idx * row..(idx + 1) * row]
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+    }
+
+    pub fn code(&self, idx: usize) -> Vec<u64> {
+        self.words(idx).collect()
+    }
+
+    /// Number of bits that differ between the code at `idx` and `code`.
+    pub fn hamming(&self, idx: usize, code: &[u64]) -> u32 {
+        self.words(idx).zip(code).map(|(a, b)| (a ^ b).count_ones()).sum()
+    }
+
+    /// Compares all the codes with the one of `query` and returns the `k` closest, in increasing
+    /// order of Hamming distance.
+    pub fn scan(&self, query: &[f32], k: usize) -> Vec<(usize, f32)> {
+        let code = SignCodes::encode(query);
+        let mut results: Vec<_> = (0..self.len()).map(|idx| (idx, self.hamming(idx, &code))).collect();
+
+        if k < results.len() {
+            results.select_nth_unstable_by_key(k, |(idx, distance)| (*distance, *idx));
+            results.truncate(k);
+        }
+        results.sort_unstable_by_key(|(idx, distance)| (*distance, *idx));
+        results.into_iter().map(|(idx, distance)| (idx, distance as f32)).collect()
+    }
+
+    pub fn write<B: io::Write>(&self, buffer: &mut B) -> io::Result<usize> {
+        buffer.write_all(&(self.words as u64).to_le_bytes())?;
+        buffer.write_all(self.bytes())?;
+        Ok(HEADER_LEN + self.bytes().len())
+    }
+}
+
+impl ElementContainer for SignCodes {
+    type Element = Vec<u64>;
+
+    fn get(&self, idx: usize) -> Self::Element {
+        self.code(idx)
+    }
+
+    fn len(&self) -> usize {
+        SignCodes::len(self)
+    }
+
+    fn dist_to_element(&self, idx: usize, element: &Self::Element) -> NotNan<f32> {
+        NotNan::new(self.hamming(idx, element) as f32).unwrap()
+    }
+}
+
+/// Sign codes of the vectors of an index and the graph built over them, both mapped from their
+/// files.
+pub struct BinaryIndex {
+    index: Granne<'static, SignCodes>,
+}
+
+impl BinaryIndex {
+    pub fn codes(&self) -> &SignCodes {
+        self.index.get_elements()
+    }
+
+    pub fn len(&self) -> usize {
+        self.codes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Brute-force search over the codes, see `SignCodes::scan`.
+    pub fn scan(&self, query: &[f32], k: usize) -> Vec<(usize, f32)> {
+        self.codes().scan(query, k)
+    }
+
+    /// Searches the graph for the `k` closest codes to the one of `query`.
+    pub fn search(&self, query: &[f32], max_search: usize, k: usize) -> Vec<(usize, f32)> {
+        self.index.search(&SignCodes::encode(query), max_search, k)
+    }
+
+    /// Maps the codes stored at `codes_path` and the graph stored at `graph_path`, if there are
+    /// any.
+    pub fn load(codes_path: &Path, graph_path: &Path) -> Result<Option<Self>, VectorError> {
+        let (codes, graph) = match (open(codes_path)?, open(graph_path)?) {
+            (Some(codes), Some(graph)) => (codes, graph),
+            _ => return Ok(None),
+        };
+
+        let codes = unsafe { SignCodes::from_file(&codes)? };
+        let index = unsafe { Granne::from_file(&graph, codes)? };
+        Ok(Some(BinaryIndex { index }))
+    }
+}
+
+/// Graph over the sign codes of the vectors of an index, as a commit extends it.
+pub struct BinaryBuilder {
+    builder: GranneBuilder<SignCodes>,
+}
+
+impl BinaryBuilder {
+    /// Starts from the codes and the graph stored at `codes_path` and `graph_path` by the previous
+    /// generation, if there are any, and encodes the vectors of `elements` pushed since. `build`
+    /// then only inserts the new codes in the graph.
+    pub fn open(
+        config: BuildConfig,
+        codes_path: &Path,
+        graph_path: &Path,
+        elements: &Elements,
+    ) -> Result<Self, VectorError> {
+        let mut codes = match open(codes_path)? {
+            Some(file) => unsafe { SignCodes::from_file(&file)? },
+            None => SignCodes::new(),
+        };
+        let graph = match open(graph_path)? {
+            Some(graph) if codes.len() <= elements.len() => Some(graph),
+            _ => None,
+        };
+        if graph.is_none() {
+            codes = SignCodes::new();
+        }
+
+        for idx in codes.len()..elements.len() {
+            codes.push(&elements.get_element(idx).0);
+        }
+        let builder = match graph {
+            Some(graph) => GranneBuilder::from_file(config, &graph, codes)?,
+            None => GranneBuilder::new(config, codes),
+        };
+        Ok(BinaryBuilder { builder })
+    }
+
+    /// Inserts the codes that are not in the graph yet.
+    pub fn build(&mut self) {
+        self.builder.build();
+    }
+
+    pub fn codes(&self) -> &SignCodes {
+        self.builder.get_elements()
+    }
+
+    /// Writes the codes and the graph over them.
+    pub fn save<W: io::Write + io::Seek>(&self, codes: W, graph: W) -> Result<(), VectorError> {
+        let mut codes = io::BufWriter::new(codes);
+        self.codes().write(&mut codes)?;
+        io::Write::flush(&mut codes)?;
+
+        let mut graph = io::BufWriter::new(graph);
+        self.builder.write_index(&mut graph)?;
+        io::Write::flush(&mut graph)?;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> Result<Option<File>, VectorError> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use granne::{angular::Vector, BuildConfig, Index};
+    use tempfile::NamedTempFile;
+
+    use super::{BinaryBuilder, BinaryIndex, SignCodes};
+    use crate::vectors::{Elements, Metric, Storage};
+
+    #[test]
+    fn encode_and_scan() {
+        let mut vector = vec![-1.0; 70];
+        vector[0] = 1.0;
+        vector[65] = 0.5;
+        assert_eq!(SignCodes::encode(&vector), [1, 2]);
+
+        let mut codes = SignCodes::new();
+        codes.push(&[1.0, 1.0, 1.0]);
+        codes.push(&[1.0, -1.0, 1.0]);
+        codes.push(&[-1.0, -1.0, -1.0]);
+        assert_eq!(codes.len(), 3);
+        assert_eq!(codes.hamming(2, &SignCodes::encode(&[1.0, 1.0, 1.0])), 3);
+
+        let res = codes.scan(&[0.5, -2.0, -1.0], 2);
+        assert_eq!(res, [(1, 1.0), (2, 1.0)]);
+    }
+
+    #[test]
+    fn build_search_and_load() {
+        let mut elements = Elements::new(Metric::Angular, Storage::F32);
+        for i in 0..200 {
+            let vector: Vec<_> = (0..16).map(|j| ((i * 31 + j * 17 + i * j) % 97) as f32 - 48.0).collect();
+            elements.push(&Vector::from(vector)).unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let (codes_path, graph_path) = (dir.path().join("binary.dat"), dir.path().join("binary_index.dat"));
+        assert!(BinaryIndex::load(&codes_path, &graph_path).unwrap().is_none());
+
+        let save = |builder: &BinaryBuilder| {
+            let (codes, graph) = (NamedTempFile::new().unwrap(), NamedTempFile::new().unwrap());
+            builder.save(codes.as_file(), graph.as_file()).unwrap();
+            codes.persist(&codes_path).unwrap();
+            graph.persist(&graph_path).unwrap();
+        };
+
+        let mut builder = BinaryBuilder::open(BuildConfig::default(), &codes_path, &graph_path, &elements).unwrap();
+        builder.build();
+        save(&builder);
+
+        let index = BinaryIndex::load(&codes_path, &graph_path).unwrap().unwrap();
+        assert_eq!(index.len(), 200);
+        assert_eq!(index.codes(), builder.codes());
+
+        let query = elements.get_element(42).0.to_vec();
+        assert_eq!(index.scan(&query, 1)[0].1, 0.0);
+        let res = index.search(&query, 50, 5);
+        assert_eq!(res.len(), 5);
+        assert_eq!(res[0].1, 0.0);
+
+        // A later generation extends the codes and the graph of the previous one.
+        for i in 200..250 {
+            let vector: Vec<_> = (0..16).map(|j| ((i * 13 + j * 29 + i * j) % 89) as f32 - 44.0).collect();
+            elements.push(&Vector::from(vector)).unwrap();
+        }
+        let mut builder = BinaryBuilder::open(BuildConfig::default(), &codes_path, &graph_path, &elements).unwrap();
+        assert_eq!(builder.builder.len(), 200);
+        builder.build();
+        assert_eq!(builder.builder.len(), 250);
+        save(&builder);
+
+        let index = BinaryIndex::load(&codes_path, &graph_path).unwrap().unwrap();
+        assert_eq!(index.len(), 250);
+        assert_eq!(index.codes().code(42), SignCodes::encode(&query));
+        let query = elements.get_element(240).0.to_vec();
+        assert_eq!(index.search(&query, 50, 5)[0].1, 0.0);
+    }
+}
@@ -0,0 +1,353 @@
+//! Loading of large datasets into the main space of an index.
+//!
+//! `Writer::push_batch` holds every vector in memory until the commit, and so does the commit,
+//! which copies the vectors not committed yet. A `BulkLoader` writes the vectors to a file of the
+//! index every `chunk_size` vectors and builds the graph over the committed vectors and that file,
+//! both mapped, so it only holds one chunk in memory besides the graph.
+
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use granne::{angular::Vector, Writeable};
+use tempfile::NamedTempFile;
+
+use super::{elements, namespaces, BatchReport, Elements, Metadata, Schema, VectorError, Writer};
+
+/// Vectors written to the spill file at once by default.
+pub const DEFAULT_CHUNK_SIZE: usize = 10_000;
+
+/// Writes everything but the first `skip` bytes to `inner`.
+struct Headless<W> {
+    inner: W,
+    skip: usize,
+}
+
+impl<W: Write> Write for Headless<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let skipped = self.skip.min(buf.len());
+        self.skip -= skipped;
+        if skipped == buf.len() {
+            return Ok(skipped);
+        }
+        Ok(skipped + self.inner.write(&buf[skipped..])?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A file of vectors written one chunk at a time, in the format of `Elements::write`, holding
+/// the vectors that follow the ones mapped by a writer.
+struct Spill<'a> {
+    file: NamedTempFile,
+    chunk: Elements<'a>,
+    /// Whether the file has its header, the width of the vectors, which granne writes before
+    /// every collection and only the first chunk keeps.
+    started: bool,
+}
+
+impl<'a> Spill<'a> {
+    /// Starts a file in `dir`, the directory of the index, with the vectors of `elements` that are
+    /// held in memory.
+    fn new(elements: &Elements, dir: &Path) -> io::Result<Self> {
+        let mut file = NamedTempFile::new_in(dir)?;
+        let mapped = elements.mapped_len();
+        let started = elements.len() > mapped;
+        if started {
+            file.write_all(&(elements.dim() as u64).to_le_bytes())?;
+            elements.write_from(mapped, &mut file)?;
+        }
+        Ok(Spill {
+            file,
+            chunk: Elements::new(elements.metric(), elements.storage()),
+            started,
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+
+        let skip = match self.started {
+            true => std::mem::size_of::<u64>(),
+            false => 0,
+        };
+        self.chunk.write(&mut Headless {
+            inner: self.file.as_file_mut(),
+            skip,
+        })?;
+        self.started = true;
+        self.chunk = Elements::new(self.chunk.metric(), self.chunk.storage());
+        Ok(())
+    }
+
+    /// Maps the vectors written so far, after the ones mapped by `elements`, the ones the file
+    /// was started with.
+    fn map(&self, elements: &Elements<'a>) -> io::Result<Elements<'a>> {
+        unsafe { elements.join_file(self.file.as_file()) }
+    }
+}
+
+/// Loads vectors into the main space of a writer from an iterator, with a bounded amount of
+/// memory.
+///
+/// Documents and their vectors are mapped in LMDB as chunks are written, and the graph is built by
+/// `finish`, which commits the writer. A loader dropped before finishing leaves the vectors loaded
+/// so far in the writer, to be committed with its next commit.
+pub struct BulkLoader<'w, 'a> {
+    writer: &'w mut Writer<'a>,
+    elements: Spill<'a>,
+    full_precision: Option<Spill<'a>>,
+    schema: Option<Schema>,
+    chunk_size: usize,
+    doc_ids: Vec<usize>,
+    metadata: Vec<Metadata>,
+    bytes: usize,
+    loaded: usize,
+    finished: bool,
+}
+
+impl<'w, 'a> BulkLoader<'w, 'a> {
+    /// Starts loading into `writer`, after the vectors it already has.
+    pub fn new(writer: &'w mut Writer<'a>) -> Result<Self, VectorError> {
+        if writer.elements().sum_embeddings().is_some() {
+            return Err(VectorError::Schema("the index stores terms, see Writer::push_terms".to_string()));
+        }
+        let dir = &writer.location().path();
+        let elements = Spill::new(writer.elements(), dir)?;
+        let full_precision = match writer.full_precision() {
+            Some(full_precision) => Some(Spill::new(full_precision, dir)?),
+            None => None,
+        };
+
+        Ok(BulkLoader {
+            schema: writer.schema(),
+            writer,
+            elements,
+            full_precision,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            doc_ids: Vec::new(),
+            metadata: Vec::new(),
+            bytes: 0,
+            loaded: 0,
+            finished: false,
+        })
+    }
+
+    /// Vectors held in memory before they are written to the file.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Number of vectors loaded so far.
+    pub fn len(&self) -> usize {
+        self.loaded
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.loaded == 0
+    }
+
+    /// Loads a vector of `doc_id`, which goes through the ingestion pipeline of the writer.
+    pub fn push(&mut self, doc_id: usize, vector: Vec<f32>) -> Result<(), VectorError> {
+        self.push_with_metadata(doc_id, vector, &Metadata::default())
+    }
+
+    /// Loads a vector of `doc_id` with the metadata searches filter on, see
+    /// `Writer::push_with_metadata`.
+    pub fn push_with_metadata(&mut self, doc_id: usize, vector: Vec<f32>, metadata: &Metadata) -> Result<(), VectorError> {
+        namespaces::check_doc_id(doc_id)?;
+        let vector = elements::vector_for(self.elements.chunk.metric(), vector);
+        let (vector, schema) = self.writer.prepare(self.schema, &vector.0)?;
+        self.schema = Some(schema);
+        self.push_prepared(doc_id, &vector, metadata)
+    }
+
+    fn push_prepared(&mut self, doc_id: usize, vector: &Vector, metadata: &Metadata) -> Result<(), VectorError> {
+        self.elements.chunk.push(vector)?;
+        if let Some(full_precision) = &mut self.full_precision {
+            full_precision.chunk.push(vector)?;
+        }
+        self.doc_ids.push(doc_id);
+        self.metadata.push(metadata.clone());
+        self.bytes += std::mem::size_of_val(&vector.0[..]);
+        self.loaded += 1;
+
+        if self.doc_ids.len() >= self.chunk_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Loads the pairs of document and vector of `vectors`, reporting the vectors rejected by the
+    /// ingestion pipeline by their position. A `Receiver` can be loaded as it is, until its
+    /// senders are dropped.
+    pub fn load<I>(&mut self, vectors: I) -> Result<BatchReport, VectorError>
+    where
+        I: IntoIterator<Item = (usize, Vec<f32>)>,
+    {
+        let mut report = BatchReport::default();
+        for (position, (doc_id, vector)) in vectors.into_iter().enumerate() {
+            let vector = elements::vector_for(self.elements.chunk.metric(), vector);
+            match namespaces::check_doc_id(doc_id).and_then(|_| self.writer.prepare(self.schema, &vector.0)) {
+                Ok((vector, schema)) => {
+                    self.schema = Some(schema);
+                    self.push_prepared(doc_id, &vector, &Metadata::default())?;
+                    report.pushed += 1;
+                }
+                Err(e) => {
+                    debug!("Rejected vector {} of doc {}: {}", position, doc_id, e);
+                    report.rejected.push((position, e));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Writes the chunk in memory to the file and maps its documents.
+    fn flush(&mut self) -> Result<(), VectorError> {
+        if self.doc_ids.is_empty() {
+            return Ok(());
+        }
+
+        trace!("Writing a chunk of {} vectors", self.doc_ids.len());
+        let first = self.writer.elements().len() + self.loaded - self.doc_ids.len();
+        let vec_ids: Vec<usize> = (first..first + self.doc_ids.len()).collect();
+        self.elements.flush()?;
+        if let Some(full_precision) = &mut self.full_precision {
+            full_precision.flush()?;
+        }
+        self.writer.map_loaded(&self.doc_ids, &vec_ids, &self.metadata, self.bytes)?;
+        self.doc_ids.clear();
+        self.metadata.clear();
+        self.bytes = 0;
+        Ok(())
+    }
+
+    /// Maps the files written so far and hands them to the writer. Returns them as well, sharing
+    /// the mapping, for `commit_mapped`.
+    fn hand_over(&mut self) -> Result<(Elements<'a>, Option<Elements<'a>>), VectorError> {
+        self.finished = true;
+        self.flush()?;
+        let elements = self.elements.map(self.writer.elements())?;
+        let full_precision = match (&self.full_precision, self.writer.full_precision()) {
+            (Some(spill), Some(full_precision)) => Some(spill.map(full_precision)?),
+            _ => None,
+        };
+        self.writer.adopt(elements.clone(), full_precision.clone(), self.schema);
+        Ok((elements, full_precision))
+    }
+
+    /// Builds the graph over the vectors of the index and the ones loaded and commits the writer.
+    /// Returns the generation committed.
+    pub fn finish(mut self) -> Result<u64, VectorError> {
+        debug!("Finishing bulk load of {} vectors", self.loaded);
+        let (elements, full_precision) = self.hand_over()?;
+        self.writer.commit_mapped(elements, full_precision)
+    }
+}
+
+impl Drop for BulkLoader<'_, '_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Err(e) = self.hand_over() {
+            error!("Error keeping the vectors of an unfinished bulk load: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeSet, sync::mpsc, thread};
+
+    use granne::angular::Vector;
+    use tempfile::TempDir;
+
+    use super::BulkLoader;
+    use crate::vectors::{Filter, Metadata, Metric, Reader, ReaderConfig, Storage, Writer, WriterConfig};
+
+    fn vector(i: usize) -> Vec<f32> {
+        vec![i as f32, (i % 7) as f32, 1.0]
+    }
+
+    #[test]
+    fn load_from_channel() {
+        let tmpdir = TempDir::new().unwrap();
+        let config = WriterConfig::new().metric(Metric::Euclidean).storage(Storage::F16).full_precision(true);
+        let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+        writer.push_vec(0, vector(0)).unwrap();
+        writer.commit().unwrap();
+        writer.push_vec(3000, vector(3000)).unwrap();
+
+        let (sender, receiver) = mpsc::sync_channel(16);
+        let producer = thread::spawn(move || {
+            for doc_id in 1..1050 {
+                sender.send((doc_id, vector(doc_id))).unwrap();
+            }
+            sender.send((2000, vec![1.0])).unwrap();
+        });
+
+        let mut loader = BulkLoader::new(&mut writer).unwrap().chunk_size(100);
+        // The files of the loader are in the index and only hold the vectors not committed.
+        let spills = || {
+            std::fs::read_dir(tmpdir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with(".tmp"))
+                .map(|entry| entry.metadata().unwrap().len())
+                .collect::<BTreeSet<_>>()
+        };
+        assert_eq!(spills(), BTreeSet::from([8 + 3 * 2, 8 + 3 * 4]));
+        let report = loader.load(receiver).unwrap();
+        producer.join().unwrap();
+        assert_eq!(report.pushed, 1049);
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].0, 1049);
+        loader.push_with_metadata(3001, vector(3001), &Metadata::new().tag("source", "bulk")).unwrap();
+        assert_eq!(loader.len(), 1050);
+        assert_eq!(loader.finish().unwrap(), 2);
+        assert!(spills().is_empty());
+
+        let reader = Reader::open_with_config(tmpdir.path(), ReaderConfig::new().num_neighbors(1)).unwrap();
+        for doc_id in [0, 1, 99, 100, 101, 777, 1049, 3000, 3001] {
+            assert_eq!(reader.search_vec(vector(doc_id)).unwrap()[0].0, doc_id);
+        }
+        let bulk = reader.search_filtered(&Vector(vector(0).into()), &Filter::tag("source", "bulk")).unwrap();
+        assert_eq!(bulk[0].0, 3001);
+
+        // The writer goes on after the vectors it loaded.
+        writer.push_vec(5000, vec![-3.0, 5.0, 5.0]).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(reader.search_vec(vec![-3.0, 5.0, 5.0]).unwrap()[0].0, 5000);
+        assert_eq!(reader.search_vec(vector(500)).unwrap()[0].0, 500);
+    }
+
+    #[test]
+    fn unfinished_load() {
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open_with_config(tmpdir.path(), WriterConfig::new().metric(Metric::Euclidean)).unwrap();
+        {
+            let mut loader = BulkLoader::new(&mut writer).unwrap().chunk_size(10);
+            for doc_id in 0..25 {
+                loader.push(doc_id, vector(doc_id)).unwrap();
+            }
+            assert!(loader.push(99, vec![1.0]).is_err());
+        }
+        assert_eq!(writer.pending().vectors, 25);
+
+        // The vectors loaded are committed with the next pushes.
+        writer.push_vec(25, vector(25)).unwrap();
+        writer.commit().unwrap();
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        for doc_id in [0, 12, 24, 25] {
+            assert_eq!(reader.search_vec(vector(doc_id)).unwrap()[0].0, doc_id);
+        }
+    }
+}
@@ -0,0 +1,514 @@
+//! Commits of a `Writer`, in the foreground or in the background.
+//!
+//! A commit takes a snapshot of the writer and builds the files of the next generation from it,
+//! in temporary files. They replace the files of the index once all of them are written, under
+//! the commit lock, so a commit that is cancelled leaves the previous generation as it was. Files
+//! of vectors are not replaced but appended to: the commit only writes the vectors pushed since
+//! the last one, after the ones the readers of the previous generation have mapped. Graphs are
+//! extended the same way, starting from the graph of the previous generation, but their files are
+//! written whole.
+//!
+//! Readers only map the files under the commit lock, so they never see the vectors of a commit
+//! without its graphs. A commit that fails while appending cuts the files back to their length
+//! before it, which no reader has mapped beyond. Files are never cut below that length, since
+//! readers may have mapped all of it.
+//!
+//! `Writer::commit` runs the snapshot on the calling thread and `Writer::commit_async` on a
+//! thread of its own, reporting its progress to a `CommitHandle`.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Seek, SeekFrom},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use granne::{BuildConfig, Builder, Granne, GranneBuilder, Index, Writeable};
+use tempfile::NamedTempFile;
+
+use super::{
+    directory::Location, BinaryBuilder, Elements, Lock, PqConfig, ProductQuantization, VectorError,
+};
+
+/// Vectors a background commit indexes between two checks for cancellation and updates of its
+/// progress.
+const BUILD_CHUNK: usize = 1000;
+
+/// Generation of the index whose generation file is at `path`, 0 before the first commit.
+pub fn load_generation(path: &Path) -> Result<u64, VectorError> {
+    match std::fs::read_to_string(path) {
+        Ok(generation) => generation
+            .trim()
+            .parse()
+            .map_err(|e| VectorError::Schema(format!("{:?}: {}", path, e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Progress of a commit, shared by the thread running it and its handle.
+pub(crate) struct CommitProgress {
+    generation: u64,
+    started: Instant,
+    /// Whether graphs are built in chunks, checking for cancellation in between.
+    cancellable: bool,
+    indexed: AtomicUsize,
+    /// Vectors to index, less the ones of the graphs of the previous generation once they are
+    /// loaded.
+    total: AtomicUsize,
+    cancelled: AtomicBool,
+    /// Whether the commit published its generation, once it is over.
+    outcome: Mutex<Option<bool>>,
+    finished: Condvar,
+}
+
+impl CommitProgress {
+    pub(crate) fn new(generation: u64, total: usize, cancellable: bool) -> Self {
+        CommitProgress {
+            generation,
+            started: Instant::now(),
+            cancellable,
+            indexed: AtomicUsize::new(0),
+            total: AtomicUsize::new(total),
+            cancelled: AtomicBool::new(false),
+            outcome: Mutex::new(None),
+            finished: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn check(&self) -> Result<(), VectorError> {
+        match self.cancelled.load(Ordering::Relaxed) {
+            true => Err(VectorError::Cancelled),
+            false => Ok(()),
+        }
+    }
+
+    /// Builds the graph of `builder`, inserting the vectors it doesn't index yet. Background
+    /// commits build it in chunks of `BUILD_CHUNK` vectors: a partial build only inserts the
+    /// vectors past the ones already indexed, so the chunks add no work and bound the time a
+    /// cancellation waits.
+    fn build(&self, builder: &mut GranneBuilder<Elements>) -> Result<(), VectorError> {
+        let total = builder.num_elements();
+        let mut indexed = builder.len();
+        self.total.fetch_sub(indexed, Ordering::Relaxed);
+        if !self.cancellable {
+            builder.build();
+            self.indexed.fetch_add(total - indexed, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        while indexed < total {
+            self.check()?;
+            let next = (indexed + BUILD_CHUNK).min(total);
+            builder.build_partial(next);
+            self.indexed.fetch_add(next - indexed, Ordering::Relaxed);
+            indexed = next;
+        }
+        Ok(())
+    }
+
+    fn finish(&self, published: bool) {
+        *self.outcome.lock().unwrap() = Some(published);
+        self.finished.notify_all();
+    }
+
+    /// Whether the commit published its generation, or `None` while it runs.
+    pub(crate) fn outcome(&self) -> Option<bool> {
+        *self.outcome.lock().unwrap()
+    }
+
+    /// Waits for the commit and returns whether it published its generation.
+    pub(crate) fn wait(&self) -> bool {
+        let mut outcome = self.outcome.lock().unwrap();
+        loop {
+            match *outcome {
+                Some(published) => return published,
+                None => outcome = self.finished.wait(outcome).unwrap(),
+            }
+        }
+    }
+}
+
+/// A commit running in the background, see `Writer::commit_async`.
+pub struct CommitHandle {
+    progress: Arc<CommitProgress>,
+    thread: JoinHandle<Result<u64, VectorError>>,
+}
+
+impl CommitHandle {
+    /// Runs `commit` on a thread of its own.
+    pub(crate) fn spawn(commit: PendingCommit<'static>, progress: Arc<CommitProgress>) -> Result<Self, VectorError> {
+        let shared = progress.clone();
+        let thread = thread::Builder::new()
+            .name("vectors-commit".to_string())
+            .spawn(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| commit.run(&shared)));
+                shared.finish(matches!(result, Ok(Ok(_))));
+                result.unwrap_or_else(|_| Err(VectorError::Stopped("the commit panicked".to_string())))
+            })?;
+
+        Ok(CommitHandle { progress, thread })
+    }
+
+    /// Generation the commit publishes when it finishes.
+    pub fn generation(&self) -> u64 {
+        self.progress.generation
+    }
+
+    /// Fraction of the vectors of the commit that are indexed, from 0 to 1.
+    pub fn progress(&self) -> f32 {
+        match self.progress.total.load(Ordering::Relaxed) {
+            0 => 1.0,
+            total => self.progress.indexed.load(Ordering::Relaxed) as f32 / total as f32,
+        }
+    }
+
+    /// Estimated time until the graphs are built, from the pace so far. `None` until some
+    /// vectors are indexed.
+    pub fn eta(&self) -> Option<Duration> {
+        let indexed = self.progress.indexed.load(Ordering::Relaxed);
+        let left = self.progress.total.load(Ordering::Relaxed).saturating_sub(indexed);
+        match (indexed, left) {
+            (_, 0) => Some(Duration::ZERO),
+            (0, _) => None,
+            (indexed, left) => Some(self.progress.started.elapsed().mul_f64(left as f64 / indexed as f64)),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.progress.outcome().is_some()
+    }
+
+    /// Stops the commit, unless it is already moving its files in place. The index keeps its
+    /// previous generation and the next commit writes what this one didn't.
+    pub fn cancel(&self) {
+        debug!("Cancelling commit of generation {}", self.progress.generation);
+        self.progress.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Waits for the commit and returns the generation it published, or `VectorError::Cancelled`.
+    pub fn wait(self) -> Result<u64, VectorError> {
+        self.thread
+            .join()
+            .map_err(|_| VectorError::Stopped("the commit panicked".to_string()))?
+    }
+}
+
+/// Vectors to add to a file of vectors, written to a temporary file.
+pub(crate) struct Append {
+    tmpfile: NamedTempFile,
+    dest: PathBuf,
+    /// Where the vectors go in `dest`, right after the ones it holds. `None` when the temporary
+    /// file replaces `dest` instead, for sums of embeddings, whose format can't be appended to.
+    offset: Option<u64>,
+}
+
+/// What a commit writes, taken from the writer when the commit starts.
+pub(crate) struct PendingCommit<'a> {
+    pub(crate) location: Location,
+    pub(crate) generation: u64,
+    pub(crate) build_config: BuildConfig,
+    pub(crate) elements: Elements<'a>,
+    pub(crate) full_precision: Option<Elements<'a>>,
+    pub(crate) product_quantization: Option<PqConfig>,
+    pub(crate) binary_quantization: bool,
+    /// Vectors of the spaces and namespaces that get a graph, with the location of their files.
+    pub(crate) graphs: Vec<(Location, Elements<'a>)>,
+    /// Files already written by the writer, with their destination.
+    pub(crate) files: Vec<(NamedTempFile, PathBuf)>,
+    /// Vectors of the namespaces without a graph, already written by the writer.
+    pub(crate) appends: Vec<Append>,
+    pub(crate) removed_namespaces: Vec<Location>,
+}
+
+impl<'a> PendingCommit<'a> {
+    /// Number of vectors indexed by the graphs of the commit.
+    pub(crate) fn num_vectors(&self) -> usize {
+        self.elements.len() + self.graphs.iter().map(|(_, elements)| elements.len()).sum::<usize>()
+    }
+
+    /// Builds the files of the generation and moves them in place. Returns the generation.
+    pub(crate) fn run(self, progress: &CommitProgress) -> Result<u64, VectorError> {
+        let PendingCommit {
+            location,
+            generation,
+            build_config,
+            elements,
+            full_precision,
+            product_quantization,
+            binary_quantization,
+            graphs,
+            mut files,
+            mut appends,
+            removed_namespaces,
+        } = self;
+        let removed = |location: &Location| removed_namespaces.iter().any(|removed| removed.path() == location.path());
+
+        let t0 = Instant::now();
+        let mut builder = open_builder(build_config, &location.index_path(), elements, false)?;
+        debug!("Builder made in {:?}", t0.elapsed());
+
+        debug!("Start building index!");
+        let t0 = Instant::now();
+        progress.build(&mut builder)?;
+        debug!("Index built in {:?}", t0.elapsed());
+
+        appends.push(save_elements(builder.get_elements(), location.elements_path(), false));
+        files.push((save_index(&builder), location.index_path()));
+        if let Some(full_precision) = &full_precision {
+            appends.push(save_elements(full_precision, location.full_precision_path(), false));
+        }
+
+        let elements = full_precision.as_ref().unwrap_or_else(|| builder.get_elements());
+        if let Some(config) = product_quantization {
+            progress.check()?;
+            if let Some(tmp_pq) = save_product_quantization(&config, elements, &location)? {
+                files.push((tmp_pq, location.pq_path()));
+            }
+        }
+        if binary_quantization {
+            progress.check()?;
+            let (codes, graph) = save_binary_index(build_config, elements, &location)?;
+            files.push((codes, location.binary_path()));
+            files.push((graph, location.binary_index_path()));
+        }
+
+        for (graph_location, elements) in graphs {
+            let t0 = Instant::now();
+            let fresh = removed(&graph_location);
+            let mut builder = open_builder(build_config, &graph_location.index_path(), elements, fresh)?;
+            progress.build(&mut builder)?;
+            debug!("Graph of {:?} built in {:?}", graph_location, t0.elapsed());
+
+            appends.push(save_elements(builder.get_elements(), graph_location.elements_path(), fresh));
+            files.push((save_index(&builder), graph_location.index_path()));
+        }
+
+        progress.check()?;
+        files.push((save_generation(generation), location.generation_path()));
+        commit_files(&location, removed_namespaces, appends, files)?;
+        debug!("Committed generation {}", generation);
+        Ok(generation)
+    }
+}
+
+// Elements are only read by the thread running the commit.
+unsafe impl Send for PendingCommit<'_> {}
+
+/// Moves the files of a new generation to their place in the index, pairs of temporary file and
+/// destination, after removing the directories of the deleted namespaces and appending the new
+/// vectors to their files. The commit lock is released whatever happens.
+fn commit_files(
+    location: &Location,
+    removed_namespaces: Vec<Location>,
+    appends: Vec<Append>,
+    files: Vec<(NamedTempFile, PathBuf)>,
+) -> Result<(), VectorError> {
+    std::fs::create_dir_all(location.path())?;
+    let commit_lock = Lock::open(location.commit_lock_path()).map_err(io::Error::other)?;
+
+    debug!("Adquiring commit lock");
+    commit_lock.lock();
+    let moved = (|| -> io::Result<()> {
+        for removed in removed_namespaces {
+            match std::fs::remove_dir_all(removed.path()) {
+                Ok(_) => debug!("Removed {:?}", removed),
+                Err(e) => error!("Error removing {:?}: {}", removed, e),
+            }
+        }
+        append_files(&appends)?;
+        for (tmpfile, dest) in &files {
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            swap_files(tmpfile.path(), dest)?;
+        }
+        Ok(())
+    })();
+    debug!("Releasing commit lock");
+    commit_lock.unlock();
+    Ok(moved?)
+}
+
+/// Appends the vectors of `appends` to their files, or cuts the files back to their previous
+/// length if one of them fails. No reader has mapped them beyond it, since they map the files
+/// under the commit lock.
+fn append_files(appends: &[Append]) -> io::Result<()> {
+    let mut appended = Vec::with_capacity(appends.len());
+    let result = appends.iter().try_for_each(|append| {
+        if let Some(parent) = append.dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        match append.offset {
+            Some(offset) => appended.push((&append.dest, append_file(append.tmpfile.path(), &append.dest, offset)?)),
+            None => swap_files(append.tmpfile.path(), &append.dest)?,
+        }
+        Ok(())
+    });
+    if result.is_err() {
+        for (dest, len) in appended {
+            if let Err(e) = OpenOptions::new().write(true).open(dest).and_then(|file| file.set_len(len)) {
+                error!("Error cutting {:?} back to {} bytes: {}", dest, len, e);
+            }
+        }
+    }
+    result
+}
+
+fn swap_files(orig: &Path, dest: &Path) -> io::Result<()> {
+    remove_file(dest);
+    debug!("Moving {:?} -> {:?}", orig, dest);
+    std::fs::rename(orig, dest)
+}
+
+/// Writes the vectors in `orig` at `offset` of `dest`, over whatever a commit that didn't finish
+/// left after it, and returns the length `dest` had. The file is never cut: readers may have
+/// mapped all of it.
+fn append_file(orig: &Path, dest: &Path, offset: u64) -> io::Result<u64> {
+    let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(dest)?;
+    let len = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+    let appended = io::copy(&mut File::open(orig)?, &mut file)?;
+    debug!("Appended {} bytes to {:?} at {}", appended, dest, offset);
+    if offset + appended < len {
+        warn!("{:?} keeps {} bytes of a commit that didn't finish", dest, len - offset - appended);
+    }
+    Ok(len)
+}
+
+/// Builder of the graph at `path` over `elements`, holding the graph of the previous generation
+/// so that building it only inserts the vectors pushed since. The graphs of `fresh` namespaces,
+/// removed and created again by the commit, and graphs of more vectors than `elements` start
+/// empty.
+fn open_builder<'a>(
+    config: BuildConfig,
+    path: &Path,
+    elements: Elements<'a>,
+    fresh: bool,
+) -> Result<GranneBuilder<Elements<'a>>, VectorError> {
+    let file = match File::open(path) {
+        Ok(_) if fresh => return Ok(GranneBuilder::new(config, elements)),
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(GranneBuilder::new(config, elements)),
+        Err(e) => return Err(e.into()),
+    };
+
+    // The length of a graph only depends on its layers.
+    let indexed = unsafe { Granne::from_file(&file, Elements::new(elements.metric(), elements.storage()))? }.len();
+    if indexed > elements.len() {
+        warn!("The graph {:?} has {} vectors, more than the {} of the index", path, indexed, elements.len());
+        return Ok(GranneBuilder::new(config, elements));
+    }
+    Ok(GranneBuilder::from_file(config, &file, elements)?)
+}
+
+fn remove_file(path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(_) => debug!("Removed {:?}", path),
+        Err(_) => trace!("Remove ignored, {:?} doesn't exist", path),
+    }
+}
+
+fn save_generation(generation: u64) -> NamedTempFile {
+    let mut tmpfile = NamedTempFile::new().unwrap();
+    io::Write::write_all(&mut tmpfile, generation.to_string().as_bytes()).unwrap();
+
+    tmpfile
+}
+
+fn save_index(builder: &GranneBuilder<Elements>) -> NamedTempFile {
+    let mut tmpfile = NamedTempFile::new().unwrap();
+
+    let t0 = Instant::now();
+    debug!("Writing index to file...");
+    builder.write_index(&mut tmpfile).unwrap();
+    trace!("Index wrote in {:?}", t0.elapsed());
+
+    tmpfile
+}
+
+/// Writes the vectors of `elements` that the file at `dest` lacks, to be appended to it. Files of
+/// namespaces removed by the commit are written anew, since the commit removes them first.
+pub(crate) fn save_elements(elements: &Elements, dest: PathBuf, fresh: bool) -> Append {
+    let mut tmpfile = NamedTempFile::new().unwrap();
+
+    let t0 = Instant::now();
+    if elements.sum_embeddings().is_some() {
+        debug!("Writing elements to file...");
+        elements.write(&mut tmpfile).unwrap();
+        trace!("Elements wrote in {:?}", t0.elapsed());
+        return Append { tmpfile, dest, offset: None };
+    }
+
+    let stored = match std::fs::metadata(&dest) {
+        Ok(metadata) if !fresh => elements.stored_in(metadata.len()),
+        _ => 0,
+    };
+    debug!("Writing {} new elements to file...", elements.len() - stored);
+    elements.write_from(stored, &mut tmpfile).unwrap();
+    trace!("Elements wrote in {:?}", t0.elapsed());
+
+    let offset = match stored {
+        0 => 0,
+        stored => elements.offset(stored),
+    };
+    Append { tmpfile, dest, offset: Some(offset) }
+}
+
+/// Encodes the vectors of this generation with the product quantizer of the previous one, trained
+/// again while the index is small, using the full-precision copy when the index keeps one.
+fn save_product_quantization(
+    config: &PqConfig,
+    elements: &Elements,
+    location: &Location,
+) -> Result<Option<NamedTempFile>, VectorError> {
+    if elements.is_empty() {
+        return Ok(None);
+    }
+
+    // The codes are derived data: a quantization that can't be read is trained again.
+    let previous = ProductQuantization::load(&location.pq_path()).unwrap_or_else(|e| {
+        warn!("Training the product quantizer again, the previous one can't be read: {}", e);
+        None
+    });
+
+    let t0 = Instant::now();
+    debug!("Encoding {} vectors with the product quantizer...", elements.len());
+    let pq = ProductQuantization::update(config, previous, elements);
+    trace!("Product quantization updated in {:?}", t0.elapsed());
+
+    let tmpfile = NamedTempFile::new()?;
+    pq.save(tmpfile.as_file())?;
+
+    Ok(Some(tmpfile))
+}
+
+/// Encodes the vectors pushed since the previous generation to their sign codes and inserts them
+/// in the graph of the previous generation.
+fn save_binary_index(
+    build_config: BuildConfig,
+    elements: &Elements,
+    location: &Location,
+) -> Result<(NamedTempFile, NamedTempFile), VectorError> {
+    let t0 = Instant::now();
+    debug!("Building binary index...");
+    let (codes_path, graph_path) = (location.binary_path(), location.binary_index_path());
+    let mut binary = BinaryBuilder::open(build_config, &codes_path, &graph_path, elements)?;
+    binary.build();
+    trace!("Binary index built in {:?}", t0.elapsed());
+
+    let (codes, graph) = (NamedTempFile::new()?, NamedTempFile::new()?);
+    binary.save(codes.as_file(), graph.as_file())?;
+
+    Ok((codes, graph))
+}
@@ -0,0 +1,304 @@
+use std::time::Duration;
+
+use super::{namespaces, storage, BinaryPrefilter, IngestConfig, Metric, PqConfig, Storage};
+
+/// Options used when opening a `Writer`.
+#[derive(Debug, Clone, Copy)]
+pub struct WriterConfig {
+    pub(crate) map_size: usize,
+    pub(crate) dimension: Option<usize>,
+    pub(crate) metric: Option<Metric>,
+    pub(crate) storage: Option<Storage>,
+    pub(crate) full_precision: bool,
+    pub(crate) product_quantization: Option<PqConfig>,
+    pub(crate) binary_quantization: bool,
+    pub(crate) namespace_graph_threshold: usize,
+    pub(crate) commit_policy: CommitPolicy,
+    pub(crate) ingest: IngestConfig,
+}
+
+impl Default for WriterConfig {
+    fn default() -> Self {
+        WriterConfig {
+            map_size: storage::DEFAULT_MAP_SIZE,
+            dimension: None,
+            metric: None,
+            storage: None,
+            full_precision: false,
+            product_quantization: None,
+            binary_quantization: false,
+            namespace_graph_threshold: namespaces::DEFAULT_GRAPH_THRESHOLD,
+            commit_policy: CommitPolicy::default(),
+            ingest: IngestConfig::default(),
+        }
+    }
+}
+
+impl WriterConfig {
+    pub fn new() -> Self {
+        WriterConfig::default()
+    }
+
+    /// Initial size in bytes of the LMDB memory map. The map grows automatically when full.
+    pub fn map_size(mut self, map_size: usize) -> Self {
+        self.map_size = map_size;
+        self
+    }
+
+    /// Dimension of the vectors of a new index. Without it, the dimension of the first vector pushed
+    /// is used. Opening an existing index with a different dimension fails.
+    pub fn dimension(mut self, dimension: usize) -> Self {
+        self.dimension = Some(dimension);
+        self
+    }
+
+    /// Metric of a new index, `Metric::Angular` by default. Opening an existing index with a
+    /// different metric fails.
+    pub fn metric(mut self, metric: Metric) -> Self {
+        self.metric = Some(metric);
+        self
+    }
+
+    /// Format of the vectors of a new index, `Storage::F32` by default. Opening an existing index
+    /// with a different storage fails.
+    pub fn storage(mut self, storage: Storage) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Keeps a full-precision copy of the vectors of a new index next to the quantized ones, so
+    /// searches can rerank their results with exact scores. Ignored for existing indexes.
+    pub fn full_precision(mut self, full_precision: bool) -> Self {
+        self.full_precision = full_precision;
+        self
+    }
+
+    /// Stores product-quantization codes of the vectors of a new index, which readers can scan
+    /// instead of the graph. Each commit encodes its new vectors with the codebook of the previous
+    /// one, trained again only while the index is small. Ignored for existing indexes.
+    pub fn product_quantization(mut self, config: PqConfig) -> Self {
+        self.product_quantization = Some(config);
+        self
+    }
+
+    /// Stores the sign bit of every component of the vectors of a new index, with a graph over
+    /// them that each commit extends, as a cheap first stage for readers. Only for the angular
+    /// metric. Ignored for existing indexes.
+    pub fn binary_quantization(mut self, binary_quantization: bool) -> Self {
+        self.binary_quantization = binary_quantization;
+        self
+    }
+
+    /// Number of vectors from which a namespace gets a graph on commit. Smaller namespaces are
+    /// searched by brute force.
+    pub fn namespace_graph_threshold(mut self, threshold: usize) -> Self {
+        self.namespace_graph_threshold = threshold;
+        self
+    }
+
+    /// When the writer is due for a commit, applied by an `AutoCommit`. Never by default.
+    pub fn commit_policy(mut self, commit_policy: CommitPolicy) -> Self {
+        self.commit_policy = commit_policy;
+        self
+    }
+
+    /// Validation and transformations applied to the vectors before they are pushed.
+    pub fn ingest(mut self, ingest: IngestConfig) -> Self {
+        self.ingest = ingest;
+        self
+    }
+}
+
+/// Limits on the writes a `Writer` holds before it is due for a commit. The first limit reached
+/// makes it due, and limits that are not set don't apply.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitPolicy {
+    pub(crate) max_vectors: Option<usize>,
+    pub(crate) max_age: Option<Duration>,
+    pub(crate) max_bytes: Option<usize>,
+}
+
+impl CommitPolicy {
+    pub fn new() -> Self {
+        CommitPolicy::default()
+    }
+
+    /// Vectors pushed since the last commit.
+    pub fn max_vectors(mut self, max_vectors: usize) -> Self {
+        self.max_vectors = Some(max_vectors);
+        self
+    }
+
+    /// Time since the first write after the last commit, deletions included.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Size of the vectors pushed since the last commit, as they were pushed.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+}
+
+/// Options used when opening a `Reader`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReaderConfig {
+    pub(crate) max_search: usize,
+    pub(crate) num_neighbors: usize,
+    pub(crate) rerank_depth: usize,
+    pub(crate) product_quantization: bool,
+    pub(crate) binary_prefilter: BinaryPrefilter,
+    pub(crate) ingest: IngestConfig,
+    pub(crate) max_namespaces: usize,
+}
+
+impl Default for ReaderConfig {
+    fn default() -> Self {
+        ReaderConfig {
+            max_search: 200,
+            num_neighbors: 30,
+            rerank_depth: 0,
+            product_quantization: false,
+            binary_prefilter: BinaryPrefilter::default(),
+            ingest: IngestConfig::default(),
+            max_namespaces: 64,
+        }
+    }
+}
+
+impl ReaderConfig {
+    pub fn new() -> Self {
+        ReaderConfig::default()
+    }
+
+    /// Size of the candidate list explored in the graph.
+    pub fn max_search(mut self, max_search: usize) -> Self {
+        self.max_search = max_search;
+        self
+    }
+
+    /// Number of results returned by a search.
+    pub fn num_neighbors(mut self, num_neighbors: usize) -> Self {
+        self.num_neighbors = num_neighbors;
+        self
+    }
+
+    /// Number of candidates fetched from the graph and scored again with the stored vectors
+    /// before keeping the best `num_neighbors`. Depths smaller than `num_neighbors` rerank only
+    /// the results.
+    pub fn rerank_depth(mut self, rerank_depth: usize) -> Self {
+        self.rerank_depth = rerank_depth;
+        self
+    }
+
+    /// Searches by scanning the product-quantization codes of the index, when it has them, instead
+    /// of walking the graph. The scan is linear but reads only the codes; the vectors stay mapped
+    /// and only the reranked candidates are read. Use it with a `rerank_depth` so the approximate
+    /// candidates are scored exactly.
+    pub fn product_quantization(mut self, product_quantization: bool) -> Self {
+        self.product_quantization = product_quantization;
+        self
+    }
+
+    /// Fetches the candidates from the sign codes of the index, when it has them, instead of the
+    /// graph or the product-quantization codes. Use it with a `rerank_depth` so the candidates are
+    /// scored exactly.
+    pub fn binary_prefilter(mut self, binary_prefilter: BinaryPrefilter) -> Self {
+        self.binary_prefilter = binary_prefilter;
+        self
+    }
+
+    /// Pipeline the queries go through before searching, which should be the `IngestConfig` of
+    /// the writer so they are truncated and normalized like the indexed vectors.
+    pub fn ingest(mut self, ingest: IngestConfig) -> Self {
+        self.ingest = ingest;
+        self
+    }
+
+    /// Number of namespaces kept mapped by the reader. The least recently searched one is unmapped
+    /// when another one is loaded.
+    pub fn max_namespaces(mut self, max_namespaces: usize) -> Self {
+        self.max_namespaces = max_namespaces;
+        self
+    }
+}
+
+/// Options used when opening an `IndexManager`.
+#[derive(Debug, Clone, Copy)]
+pub struct ManagerConfig {
+    pub(crate) max_readers: usize,
+    pub(crate) reader: ReaderConfig,
+    pub(crate) writer: WriterConfig,
+}
+
+impl Default for ManagerConfig {
+    fn default() -> Self {
+        ManagerConfig {
+            max_readers: 16,
+            reader: ReaderConfig::default(),
+            writer: WriterConfig::default(),
+        }
+    }
+}
+
+impl ManagerConfig {
+    pub fn new() -> Self {
+        ManagerConfig::default()
+    }
+
+    /// Number of open readers kept by the manager. The least recently used one is closed when a
+    /// reader of another index is opened, once it is no longer in use.
+    pub fn max_readers(mut self, max_readers: usize) -> Self {
+        self.max_readers = max_readers;
+        self
+    }
+
+    /// Config of the readers opened by the manager.
+    pub fn reader(mut self, reader: ReaderConfig) -> Self {
+        self.reader = reader;
+        self
+    }
+
+    /// Config of the writers opened by the manager, also used to create indexes.
+    pub fn writer(mut self, writer: WriterConfig) -> Self {
+        self.writer = writer;
+        self
+    }
+}
+
+/// Options of the `AsyncReader` and `AsyncWriter` facades.
+#[derive(Debug, Clone, Copy)]
+pub struct AsyncConfig {
+    pub(crate) threads: usize,
+    pub(crate) queue_size: usize,
+}
+
+impl Default for AsyncConfig {
+    fn default() -> Self {
+        AsyncConfig {
+            threads: num_cpus::get(),
+            queue_size: 64,
+        }
+    }
+}
+
+impl AsyncConfig {
+    pub fn new() -> Self {
+        AsyncConfig::default()
+    }
+
+    /// Threads of the pool running the searches of an `AsyncReader`.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Operations accepted before callers have to wait: searches in flight for an `AsyncReader`,
+    /// commands queued for an `AsyncWriter`.
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+}
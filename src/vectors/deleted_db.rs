@@ -1,32 +1,30 @@
+use std::{ops::Range, sync::Arc};
+
 use lmdb::Database;
 extern crate lmdb_zero as lmdb;
 
+use super::{storage, Tombstones};
+
+pub(crate) const DELETED_DB: &str = "deleted";
+
 #[derive(Debug)]
 pub struct DeletedDBReader<'a> {
     db: Database<'a>,
 }
 
 impl<'a> DeletedDBReader<'a> {
-    pub fn open(path: &str) -> Result<Self, lmdb::Error> {
-        std::fs::create_dir_all(path).unwrap();
-        let env = unsafe {
-            lmdb::EnvBuilder::new()
-                .unwrap()
-                .open(path, lmdb::open::Flags::empty(), 0o444)
-                .unwrap()
-        };
-
-        let db = lmdb::Database::open(env, None, &lmdb::DatabaseOptions::defaults())?;
+    pub fn open(env: &Arc<lmdb::Environment>) -> Result<Self, lmdb::Error> {
+        let db = storage::open_db(env, DELETED_DB, lmdb::db::Flags::empty())?;
 
         Ok(DeletedDBReader { db })
     }
 
     pub fn _contains(&self, idx: usize) -> Result<bool, lmdb::Error> {
         trace!("Check if contains: {}", idx);
-        let key = bincode::serialize(&idx).unwrap();
+        let key = storage::encode_id(idx);
         let env = self.db.env();
 
-        let txn = lmdb::ReadTransaction::new(env).unwrap();
+        let txn = storage::read_txn(env)?;
         let access = txn.access();
 
         match access.get::<[u8], [u8]>(&self.db, &key) {
@@ -38,18 +36,31 @@ impl<'a> DeletedDBReader<'a> {
     pub fn filter(&self, idxs: &[usize]) -> Result<Vec<usize>, lmdb::Error> {
         trace!("Filtering indexes");
         let env = self.db.env();
-        let txn = lmdb::ReadTransaction::new(env).unwrap();
+        let txn = storage::read_txn(env)?;
         let access = txn.access();
 
         Ok(idxs
             .iter()
             .filter(|idx| {
-                let key = bincode::serialize(&idx).unwrap();
+                let key = storage::encode_id(**idx);
                 access.get::<[u8], [u8]>(&self.db, &key).is_err()
             })
             .copied()
             .collect())
     }
+
+    /// Loads every deleted vec id into a bitmap.
+    pub fn tombstones(&self) -> Result<Tombstones, lmdb::Error> {
+        load_tombstones(&self.db)
+    }
+
+    /// Returns the deleted vec ids in `range`.
+    pub fn deleted_in_range(&self, range: Range<usize>) -> Result<Vec<usize>, lmdb::Error> {
+        let txn = storage::read_txn(self.db.env())?;
+        let mut results = Vec::new();
+        storage::scan_range(&txn, &self.db, range, |idx, _| results.push(idx))?;
+        Ok(results)
+    }
 }
 
 #[derive(Debug)]
@@ -58,56 +69,70 @@ pub struct DeletedDBWriter<'a> {
 }
 
 impl<'a> DeletedDBWriter<'a> {
-    pub fn open(path: &str) -> Result<Self, lmdb::Error> {
-        std::fs::create_dir_all(path).unwrap();
-        let env = unsafe {
-            lmdb::EnvBuilder::new()
-                .unwrap()
-                .open(path, lmdb::open::Flags::empty(), 0o666)
-                .unwrap()
-        };
-
-        let db = lmdb::Database::open(env, None, &lmdb::DatabaseOptions::defaults())?;
+    pub fn open(env: &Arc<lmdb::Environment>) -> Result<Self, lmdb::Error> {
+        let db = storage::open_db(env, DELETED_DB, lmdb::db::Flags::empty())?;
 
         Ok(DeletedDBWriter { db })
     }
 
+    /// Loads every deleted vec id into a bitmap.
+    pub fn tombstones(&self) -> Result<Tombstones, lmdb::Error> {
+        load_tombstones(&self.db)
+    }
+
     pub fn add(&self, idx: usize) -> Result<(), lmdb::Error> {
         trace!("Add: {:?}", idx);
-        let env = self.db.env();
-        let txn = lmdb::WriteTransaction::new(env)?;
-        {
-            let mut access = txn.access();
-            let key = bincode::serialize(&idx).unwrap();
-            access.put(&self.db, &key, &1, lmdb::put::Flags::empty())?;
-        }
-        txn.commit()?;
-        Ok(())
+        self.add_batch(std::iter::once(idx))
     }
 
     pub fn add_batch(&self, idxs: impl Iterator<Item = usize>) -> Result<(), lmdb::Error> {
+        let idxs: Vec<_> = idxs.collect();
+        storage::write(self.db.env(), |txn| self.add_batch_in(txn, idxs.iter().copied()))
+    }
+
+    /// Marks `idxs` as deleted as part of `txn`.
+    pub fn add_batch_in(&self, txn: &lmdb::WriteTransaction, idxs: impl Iterator<Item = usize>) -> Result<(), lmdb::Error> {
         trace!("Adding batch");
-        let env = self.db.env();
-        let txn = lmdb::WriteTransaction::new(env)?;
-        {
-            let mut access = txn.access();
-            for idx in idxs {
-                trace!("\tAdd: {:?}", idx);
-                let key = bincode::serialize(&idx).unwrap();
-                access.put(&self.db, &key, &1, lmdb::put::Flags::empty())?;
+        let mut access = txn.access();
+        for idx in idxs {
+            trace!("\tAdd: {:?}", idx);
+            let key = storage::encode_id(idx);
+            access.put::<[u8], [u8]>(&self.db, &key, &[], lmdb::put::Flags::empty())?;
+        }
+        Ok(())
+    }
+
+    /// Lifts the tombstones of `idxs` as part of `txn`.
+    pub fn remove_batch_in(&self, txn: &lmdb::WriteTransaction, idxs: impl Iterator<Item = usize>) -> Result<(), lmdb::Error> {
+        let mut access = txn.access();
+        for idx in idxs {
+            trace!("\tRemove: {:?}", idx);
+            let key = storage::encode_id(idx);
+            match access.del_key::<[u8]>(&self.db, &key) {
+                Ok(()) | Err(lmdb::Error::Code(lmdb::error::NOTFOUND)) => (),
+                Err(e) => return Err(e),
             }
         }
-        txn.commit()?;
         Ok(())
     }
 }
 
+fn load_tombstones(db: &Database) -> Result<Tombstones, lmdb::Error> {
+    let txn = storage::read_txn(db.env())?;
+    let mut tombstones = Tombstones::new();
+    storage::scan_range(&txn, db, 0..usize::MAX, |idx, _| {
+        tombstones.insert(idx);
+    })?;
+    Ok(tombstones)
+}
+
 #[cfg(test)]
 mod test {
     use log::LevelFilter;
     use tempfile::tempdir;
 
     use super::{DeletedDBReader, DeletedDBWriter};
+    use crate::vectors::storage;
 
     fn init() {
         let _ = env_logger::builder()
@@ -126,9 +151,10 @@ mod test {
     fn add_and_search() {
         init();
         let tempdir = tempdir().unwrap();
-        let path = tempdir.path().to_str().unwrap();
-        let writer = DeletedDBWriter::open(path).unwrap();
-        let reader = DeletedDBReader::open(path).unwrap();
+        let writer_env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let reader_env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let writer = DeletedDBWriter::open(&writer_env).unwrap();
+        let reader = DeletedDBReader::open(&reader_env).unwrap();
 
         writer.add(1).unwrap();
         writer.add(2).unwrap();
@@ -139,15 +165,24 @@ mod test {
         assert!(reader._contains(256).unwrap());
 
         assert_eq!(reader.filter(&[1, 2, 3, 4, 5, 6, 256]).unwrap(), [4, 5, 6]);
+        assert_eq!(reader.deleted_in_range(2..257).unwrap(), [2, 3, 256]);
+
+        let tombstones = writer.tombstones().unwrap();
+        assert_eq!(tombstones.iter().collect::<Vec<_>>(), [1, 2, 3, 256]);
+
+        storage::write(&writer_env, |txn| writer.remove_batch_in(txn, vec![2, 4].into_iter())).unwrap();
+        assert!(!reader._contains(2).unwrap());
+        assert_eq!(reader.filter(&[1, 2, 3]).unwrap(), [2]);
     }
 
     #[test]
     fn thousand_read_and_write() {
         init();
         let tempdir = tempdir().unwrap();
-        let path = tempdir.path().to_str().unwrap();
-        let writer = DeletedDBWriter::open(path).unwrap();
-        let reader = DeletedDBReader::open(path).unwrap();
+        let writer_env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let reader_env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let writer = DeletedDBWriter::open(&writer_env).unwrap();
+        let reader = DeletedDBReader::open(&reader_env).unwrap();
 
         std::thread::spawn(move || {
             for i in 0..1_000 {
@@ -170,9 +205,10 @@ mod test {
     fn batch_insert() {
         init();
         let tempdir = tempdir().unwrap();
-        let path = tempdir.path().to_str().unwrap();
-        let writer = DeletedDBWriter::open(path).unwrap();
-        let reader = DeletedDBReader::open(path).unwrap();
+        let writer_env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let reader_env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let writer = DeletedDBWriter::open(&writer_env).unwrap();
+        let reader = DeletedDBReader::open(&reader_env).unwrap();
 
         std::thread::spawn(move || {
             writer.add_batch(0..1000).unwrap();
@@ -1,11 +1,12 @@
 use std::path::PathBuf;
 
 use super::{
-    COMMIT_LOCK_PATH, DELETED_PATH, DIRTY_PATH, ELEMENTS_PATH, INDEX_MAP_PATH, INDEX_PATH,
-    WRITER_LOCK_PATH,
+    BINARY_INDEX_PATH, BINARY_PATH, COMMIT_LOCK_PATH, ELEMENTS_PATH, EMBEDDINGS_PATH, FULL_PRECISION_PATH, GENERATION_PATH, INDEX_PATH,
+    LMDB_PATH, NAMESPACES_PATH, NAMESPACE_REGISTRY_PATH, PQ_PATH, SCHEMA_PATH, SPACES_PATH, SPACE_REGISTRY_PATH,
+    TOMBSTONES_PATH, WRITER_LOCK_PATH,
 };
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Location(pub PathBuf);
 
 impl Location {
@@ -13,12 +14,12 @@ impl Location {
         self.0.join(ELEMENTS_PATH)
     }
 
-    pub fn index_path(&self) -> PathBuf {
-        self.0.join(INDEX_PATH)
+    pub fn full_precision_path(&self) -> PathBuf {
+        self.0.join(FULL_PRECISION_PATH)
     }
 
-    pub fn dirty_path(&self) -> PathBuf {
-        self.0.join(DIRTY_PATH)
+    pub fn index_path(&self) -> PathBuf {
+        self.0.join(INDEX_PATH)
     }
 
     pub fn commit_lock_path(&self) -> PathBuf {
@@ -29,12 +30,52 @@ impl Location {
         self.0.join(WRITER_LOCK_PATH)
     }
 
-    pub fn deleted_path(&self) -> PathBuf {
-        self.0.join(DELETED_PATH)
+    pub fn lmdb_path(&self) -> PathBuf {
+        self.0.join(LMDB_PATH)
+    }
+
+    pub fn tombstones_path(&self) -> PathBuf {
+        self.0.join(TOMBSTONES_PATH)
+    }
+
+    pub fn schema_path(&self) -> PathBuf {
+        self.0.join(SCHEMA_PATH)
+    }
+
+    pub fn pq_path(&self) -> PathBuf {
+        self.0.join(PQ_PATH)
+    }
+
+    pub fn binary_path(&self) -> PathBuf {
+        self.0.join(BINARY_PATH)
+    }
+
+    pub fn binary_index_path(&self) -> PathBuf {
+        self.0.join(BINARY_INDEX_PATH)
+    }
+
+    pub fn embeddings_path(&self) -> PathBuf {
+        self.0.join(EMBEDDINGS_PATH)
+    }
+
+    pub fn spaces_path(&self) -> PathBuf {
+        self.0.join(SPACES_PATH)
+    }
+
+    pub fn space_registry_path(&self) -> PathBuf {
+        self.0.join(SPACE_REGISTRY_PATH)
+    }
+
+    pub fn namespaces_path(&self) -> PathBuf {
+        self.0.join(NAMESPACES_PATH)
+    }
+
+    pub fn namespace_registry_path(&self) -> PathBuf {
+        self.0.join(NAMESPACE_REGISTRY_PATH)
     }
 
-    pub fn index_map_path(&self) -> PathBuf {
-        self.0.join(INDEX_MAP_PATH)
+    pub fn generation_path(&self) -> PathBuf {
+        self.0.join(GENERATION_PATH)
     }
 
     pub fn path(&self) -> PathBuf {
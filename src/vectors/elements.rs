@@ -0,0 +1,790 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+use granne::{
+    angular::{self, Vector},
+    angular_int,
+    embeddings::SumEmbeddings,
+    Dist, ElementContainer, Writeable,
+};
+use ordered_float::NotNan;
+
+use super::{HalfFormat, HalfVectors, InvalidVector, Metric, Storage, VectorError};
+
+/// Size of the header of the files of vectors, their width as a little-endian `u64`. Files of
+/// empty collections are only the header.
+const HEADER_LEN: u64 = 8;
+
+/// Lanes of the accumulators of `int8_angular_distance`, wide enough for 256-bit registers.
+const LANES: usize = 8;
+
+/// `1 - cos(x, y)` between a quantized vector and a query, 1 if either is zero.
+///
+/// The sums are accumulated in `LANES` independent lanes, which the compiler turns into SIMD
+/// instructions, unlike a single accumulator whose additions have to stay in order.
+fn int8_angular_distance(x: &[i8], y: &[f32]) -> f32 {
+    let mut dot = [0.0f32; LANES];
+    let mut norm_x = [0.0f32; LANES];
+    let mut norm_y = [0.0f32; LANES];
+    let (x_chunks, y_chunks) = (x.chunks_exact(LANES), y.chunks_exact(LANES));
+    let (x_rest, y_rest) = (x_chunks.remainder(), y_chunks.remainder());
+    for (x, y) in x_chunks.zip(y_chunks) {
+        for lane in 0..LANES {
+            let xi = x[lane] as f32;
+            dot[lane] += xi * y[lane];
+            norm_x[lane] += xi * xi;
+            norm_y[lane] += y[lane] * y[lane];
+        }
+    }
+
+    let (mut dot, mut norm_x, mut norm_y): (f32, f32, f32) = (dot.iter().sum(), norm_x.iter().sum(), norm_y.iter().sum());
+    for (x, y) in x_rest.iter().zip(y_rest) {
+        let x = *x as f32;
+        dot += x * y;
+        norm_x += x * x;
+        norm_y += y * y;
+    }
+    match norm_x * norm_y {
+        norms if norms > 0.0 => (1.0 - dot / norms.sqrt()).max(0.0),
+        _ => 1.0,
+    }
+}
+
+#[derive(Clone)]
+enum Vectors<'a> {
+    F32(angular::Vectors<'a>),
+    Int8(angular_int::Vectors<'a>),
+    Half(HalfVectors),
+    /// The elements and term embeddings, with the dimension of the embeddings, 0 while there are
+    /// none.
+    Sum(SumEmbeddings<'a>, usize),
+}
+
+/// Bytes taken by each component of the vectors of `storage`, 0 for sums of embeddings, whose
+/// elements are lists of terms.
+fn component_len(storage: Storage) -> usize {
+    match storage {
+        Storage::F32 => 4,
+        Storage::Int8 => 1,
+        Storage::F16 | Storage::Bf16 => 2,
+        Storage::SumEmbeddings => 0,
+    }
+}
+
+impl<'a> Vectors<'a> {
+    fn new(storage: Storage) -> Self {
+        match storage {
+            Storage::F32 => Vectors::F32(angular::Vectors::new()),
+            Storage::Int8 => Vectors::Int8(angular_int::Vectors::new()),
+            Storage::F16 => Vectors::Half(HalfVectors::new(HalfFormat::F16)),
+            Storage::Bf16 => Vectors::Half(HalfVectors::new(HalfFormat::Bf16)),
+            Storage::SumEmbeddings => Vectors::Sum(SumEmbeddings::new(), 0),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Vectors::F32(vectors) => vectors.len(),
+            Vectors::Int8(vectors) => vectors.len(),
+            Vectors::Half(vectors) => vectors.len(),
+            Vectors::Sum(embeddings, _) => embeddings.len(),
+        }
+    }
+
+    fn dim(&self) -> usize {
+        match self {
+            Vectors::F32(vectors) => vectors.dim(),
+            Vectors::Int8(vectors) => vectors.dim(),
+            Vectors::Half(vectors) => vectors.dim(),
+            Vectors::Sum(_, dim) => *dim,
+        }
+    }
+
+    fn push(&mut self, vector: &Vector) -> Result<(), VectorError> {
+        match self {
+            Vectors::F32(vectors) => vectors.push(vector),
+            Vectors::Int8(vectors) => vectors.push(&angular_int::Vector::from(vector.0.to_vec())),
+            Vectors::Half(vectors) => vectors.push(&vector.0),
+            Vectors::Sum(..) => {
+                let message = "the index stores terms, see Elements::push_terms";
+                return Err(VectorError::Schema(message.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Pushes the vector at `idx` of `other`, which has the same storage, as it is stored.
+    fn push_from(&mut self, other: &Vectors, idx: usize) {
+        match (self, other) {
+            (Vectors::F32(vectors), Vectors::F32(other)) => vectors.push(&other.get_element(idx)),
+            (Vectors::Int8(vectors), Vectors::Int8(other)) => vectors.push(&other.get_element(idx)),
+            (Vectors::Half(vectors), Vectors::Half(other)) => vectors.push(&other.get_element(idx)),
+            _ => panic!("Vectors can only be copied between collections with the same storage"),
+        }
+    }
+
+    fn get_element(&self, idx: usize) -> Vector<'_> {
+        match self {
+            Vectors::F32(vectors) => vectors.get_element(idx),
+            Vectors::Int8(vectors) => {
+                let values = vectors.get_element(idx).0.iter().map(|x| *x as f32).collect();
+                Vector(Cow::Owned(values))
+            }
+            Vectors::Half(vectors) => Vector(Cow::Owned(vectors.get_element(idx))),
+            Vectors::Sum(embeddings, _) => Vector::from(embeddings.get_embedding(idx)),
+        }
+    }
+
+    fn distance(&self, metric: Metric, idx: usize, vector: &[f32]) -> f32 {
+        match self {
+            Vectors::F32(vectors) => metric.distance(&vectors.get_element(idx).0, vector),
+            // Quantized vectors lose their norm, only the cosine is meaningful.
+            Vectors::Int8(vectors) => int8_angular_distance(&vectors.get_element(idx).0, vector),
+            Vectors::Half(vectors) => {
+                let components = vectors.components(idx).zip(vector.iter().copied());
+                metric.distance_between(components)
+            }
+            Vectors::Sum(embeddings, _) => {
+                let element = Vector::from(embeddings.get_embedding(idx));
+                metric.distance(&element.0, vector)
+            }
+        }
+    }
+
+    /// Distance from the vector at `i` to the vector at `j` of `other`, which has the same storage,
+    /// read where they are stored. Angular distances between `F32` or `Int8` vectors are the ones
+    /// of granne, computed with its SIMD dot products.
+    fn distance_to(&self, metric: Metric, i: usize, other: &Vectors, j: usize) -> f32 {
+        match (self, other) {
+            (Vectors::F32(vectors), Vectors::F32(other)) => {
+                let (x, y) = (vectors.get_element(i), other.get_element(j));
+                match metric {
+                    Metric::Angular => x.dist(&y).into_inner(),
+                    Metric::DotProduct | Metric::Euclidean => metric.distance(&x.0, &y.0),
+                }
+            }
+            (Vectors::Int8(vectors), Vectors::Int8(other)) => vectors.get_element(i).dist(&other.get_element(j)).into_inner(),
+            (Vectors::Half(vectors), Vectors::Half(other)) => {
+                metric.distance_between(vectors.components(i).zip(other.components(j)))
+            }
+            _ => self.distance(metric, i, &other.get_element(j).0),
+        }
+    }
+
+    /// Writes the components of the vector at `idx` as granne stores them, in the byte order of
+    /// the machine.
+    fn write_element<B: Write>(&self, idx: usize, buffer: &mut B) -> io::Result<()> {
+        match self {
+            Vectors::F32(vectors) => {
+                for x in vectors.get_element(idx).0.iter() {
+                    buffer.write_all(&x.to_ne_bytes())?;
+                }
+                Ok(())
+            }
+            Vectors::Int8(vectors) => {
+                for x in vectors.get_element(idx).0.iter() {
+                    buffer.write_all(&x.to_ne_bytes())?;
+                }
+                Ok(())
+            }
+            Vectors::Half(vectors) => buffer.write_all(vectors.raw(idx)),
+            Vectors::Sum(..) => {
+                let message = "sums of embeddings are written as a whole";
+                Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+            }
+        }
+    }
+
+    fn write<B: Write>(&self, buffer: &mut B) -> io::Result<usize> {
+        match self {
+            Vectors::F32(vectors) => vectors.write(buffer),
+            Vectors::Int8(vectors) => vectors.write(buffer),
+            Vectors::Half(vectors) => vectors.write(buffer),
+            Vectors::Sum(embeddings, _) => embeddings.write(buffer),
+        }
+    }
+}
+
+/// Vectors of an index, stored in the format of its schema and compared with its metric.
+///
+/// `F32` vectors are stored as they are pushed, `Int8` vectors are quantized by `angular_int` and
+/// `F16` and `Bf16` vectors are rounded to 16 bits. Queries are always `f32`, so quantized vectors
+/// are compared against full-precision queries.
+///
+/// `SumEmbeddings` elements are lists of term ids instead, and their vectors are the normalized
+/// sums of the embeddings of their terms, computed when they are read.
+///
+/// Files of vectors are append-only: the graphs refer to vectors by their id, which is their
+/// position in the file, see `offset`, so a commit appends the vectors pushed since the last one
+/// instead of rewriting the file. Elements mapped from a file keep the vectors pushed after it in
+/// memory, leaving the mapped ones where they are.
+///
+/// Clones share the mapped vectors and only copy the ones in memory, so a commit builds its graph
+/// over the files of the writer without holding a second copy of the index.
+#[derive(Clone)]
+pub struct Elements<'a> {
+    metric: Metric,
+    /// Shared by the clones, and copied by a push to elements held in memory while it is shared.
+    vectors: Arc<Vectors<'a>>,
+    /// Vectors pushed after the ones mapped in `vectors`, `None` for elements held in memory.
+    appended: Option<Vectors<'a>>,
+}
+
+impl<'a> Elements<'a> {
+    pub fn new(metric: Metric, storage: Storage) -> Self {
+        Elements {
+            metric,
+            vectors: Arc::new(Vectors::new(storage)),
+            appended: None,
+        }
+    }
+
+    /// Memory-maps the vectors stored in `file`.
+    ///
+    /// ## Safety
+    ///
+    /// The vectors in the file must not be modified while it is mapped, see
+    /// `angular::Vectors::from_file`. Vectors can be appended to it.
+    pub unsafe fn from_file(file: &File, metric: Metric, storage: Storage) -> io::Result<Self> {
+        // An empty collection is written as a header with a width of 0, which granne can't map.
+        if file.metadata()?.len() <= HEADER_LEN && storage != Storage::SumEmbeddings {
+            return Ok(Elements::new(metric, storage));
+        }
+        let vectors = match storage {
+            Storage::F32 => Vectors::F32(angular::Vectors::from_file(file)?),
+            Storage::Int8 => Vectors::Int8(angular_int::Vectors::from_file(file)?),
+            Storage::F16 => Vectors::Half(HalfVectors::from_file(file, HalfFormat::F16)?),
+            Storage::Bf16 => Vectors::Half(HalfVectors::from_file(file, HalfFormat::Bf16)?),
+            Storage::SumEmbeddings => {
+                let message = "sums of embeddings are loaded with their embeddings";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+            }
+        };
+        Ok(Elements {
+            metric,
+            vectors: Arc::new(vectors),
+            appended: Some(Vectors::new(storage)),
+        })
+    }
+
+    /// Memory-maps the term embeddings stored in `embeddings` and the elements stored in `elements`,
+    /// if they were committed.
+    ///
+    /// ## Safety
+    ///
+    /// The files must not be modified while they are mapped, see `SumEmbeddings::from_files`.
+    pub unsafe fn sum_embeddings_from_files(
+        embeddings: &File,
+        elements: Option<&File>,
+        metric: Metric,
+    ) -> io::Result<Self> {
+        let embeddings = SumEmbeddings::from_files(embeddings, elements)?;
+        // The sum of no terms is a zero vector with the width of the embeddings.
+        let dim = embeddings.create_embedding(&[]).len();
+        let vectors = Vectors::Sum(embeddings, dim);
+        Ok(Elements {
+            metric,
+            vectors: Arc::new(vectors),
+            appended: None,
+        })
+    }
+
+    /// Cuts the vector a commit that didn't finish left half-written at the end of `file`, which
+    /// granne refuses to map.
+    pub fn repair(file: &File, storage: Storage) -> io::Result<()> {
+        let len = file.metadata()?.len();
+        if len <= HEADER_LEN || storage == Storage::SumEmbeddings {
+            return Ok(());
+        }
+
+        let mut header = [0; HEADER_LEN as usize];
+        let mut reader = file;
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut header)?;
+        let element_len = u64::from_le_bytes(header) * component_len(storage) as u64;
+        if element_len == 0 {
+            return Ok(());
+        }
+
+        let whole = HEADER_LEN + (len - HEADER_LEN) / element_len * element_len;
+        if whole < len {
+            warn!("Cutting {} bytes of a vector half-written by a commit", len - whole);
+            file.set_len(whole)?;
+        }
+        Ok(())
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    pub fn storage(&self) -> Storage {
+        match self.vectors.as_ref() {
+            Vectors::F32(_) => Storage::F32,
+            Vectors::Int8(_) => Storage::Int8,
+            Vectors::Half(vectors) => match vectors.format() {
+                HalfFormat::F16 => Storage::F16,
+                HalfFormat::Bf16 => Storage::Bf16,
+            },
+            Vectors::Sum(..) => Storage::SumEmbeddings,
+        }
+    }
+
+    /// The term embeddings and term ids of the elements, for `SumEmbeddings` storage.
+    pub fn sum_embeddings(&self) -> Option<&SumEmbeddings<'a>> {
+        match self.vectors.as_ref() {
+            Vectors::Sum(embeddings, _) => Some(embeddings),
+            _ => None,
+        }
+    }
+
+    /// Adds `embedding` to the table of term embeddings and returns its term id.
+    ///
+    /// ## Panics
+    ///
+    /// If the storage is not `SumEmbeddings`.
+    pub fn push_embedding(&mut self, embedding: &[f32]) -> usize {
+        match Arc::make_mut(&mut self.vectors) {
+            Vectors::Sum(embeddings, dim) => {
+                *dim = embedding.len();
+                embeddings.push_embedding(embedding);
+                embeddings.num_embeddings() - 1
+            }
+            _ => panic!("Embeddings can only be pushed with {:?} storage", Storage::SumEmbeddings),
+        }
+    }
+
+    /// Pushes an element made of `terms`, which should be checked with `embed` first.
+    ///
+    /// ## Panics
+    ///
+    /// If the storage is not `SumEmbeddings`.
+    pub fn push_terms(&mut self, terms: &[usize]) {
+        match Arc::make_mut(&mut self.vectors) {
+            Vectors::Sum(embeddings, _) => embeddings.push(terms),
+            _ => panic!("Terms can only be pushed with {:?} storage", Storage::SumEmbeddings),
+        }
+    }
+
+    /// Normalized sum of the embeddings of `terms`.
+    pub fn embed(&self, terms: &[usize]) -> Result<Vector<'static>, VectorError> {
+        let embeddings = self.sum_embeddings().ok_or_else(|| {
+            VectorError::Schema(format!("{:?} storage doesn't store terms", self.storage()))
+        })?;
+        if terms.is_empty() {
+            return Err(InvalidVector::Empty.into());
+        }
+        if let Some(term) = terms.iter().find(|term| **term >= embeddings.num_embeddings()) {
+            return Err(InvalidVector::UnknownTerm(*term).into());
+        }
+        let sum = embeddings.create_embedding(terms);
+        // Terms that cancel each other out have no direction to normalize.
+        if sum.iter().all(|x| *x == 0.0) {
+            return Err(InvalidVector::Zero.into());
+        }
+        Ok(Vector::from(sum))
+    }
+
+    /// Pushes `vector`, converted to the storage. Fails with `SumEmbeddings` storage, which takes
+    /// terms instead, see `push_terms`.
+    pub fn push(&mut self, vector: &Vector) -> Result<(), VectorError> {
+        match &mut self.appended {
+            Some(appended) => appended.push(vector),
+            None => Arc::make_mut(&mut self.vectors).push(vector),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.vectors.len() + self.appended.as_ref().map_or(0, Vectors::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Dimension of the vectors, `0` while there are none.
+    pub fn dim(&self) -> usize {
+        match self.locate(0) {
+            _ if self.is_empty() => 0,
+            (vectors, _) => vectors.dim(),
+        }
+    }
+
+    /// The collection holding the vector at `idx`, with its position in it.
+    fn locate(&self, idx: usize) -> (&Vectors<'a>, usize) {
+        match &self.appended {
+            Some(appended) if idx >= self.vectors.len() => (appended, idx - self.vectors.len()),
+            _ => (&self.vectors, idx),
+        }
+    }
+
+    /// Returns the vector at `idx`, converted back to `f32`. `Int8` vectors keep only their
+    /// direction.
+    pub fn get_element(&self, idx: usize) -> Vector<'_> {
+        let (vectors, idx) = self.locate(idx);
+        vectors.get_element(idx)
+    }
+
+    /// Distance from the vector at `idx` to `vector`.
+    pub fn distance(&self, idx: usize, vector: &[f32]) -> f32 {
+        let (vectors, idx) = self.locate(idx);
+        vectors.distance(self.metric, idx, vector)
+    }
+
+    /// Bytes taken by each vector in a file, 0 while there are none or for sums of embeddings.
+    pub fn element_len(&self) -> usize {
+        self.dim() * component_len(self.storage())
+    }
+
+    /// Position of the vector at `idx` in a file written by `write`.
+    pub fn offset(&self, idx: usize) -> u64 {
+        HEADER_LEN + (idx * self.element_len()) as u64
+    }
+
+    /// Number of the first of these vectors held by a file of `len` bytes written by `write`.
+    pub fn stored_in(&self, len: u64) -> usize {
+        match self.element_len() {
+            0 => 0,
+            element_len => (len.saturating_sub(HEADER_LEN) / element_len as u64) as usize,
+        }
+        .min(self.len())
+    }
+
+    /// Writes the vectors from `first` on, in the format of `write` when `first` is 0 and without
+    /// its header otherwise, so they can be appended to a file holding the first `first` ones.
+    /// Returns the number of bytes written.
+    ///
+    /// Sums of embeddings can only be written as a whole, by `write`.
+    pub fn write_from<B: Write>(&self, first: usize, buffer: &mut B) -> io::Result<usize> {
+        let first = first.min(self.len());
+        let mut buffer = io::BufWriter::new(buffer);
+        let mut written = 0;
+        if first == 0 {
+            buffer.write_all(&(self.dim() as u64).to_le_bytes())?;
+            written += HEADER_LEN as usize;
+        }
+        for idx in first..self.len() {
+            let (vectors, idx) = self.locate(idx);
+            vectors.write_element(idx, &mut buffer)?;
+        }
+        buffer.flush()?;
+        Ok(written + (self.len() - first) * self.element_len())
+    }
+
+    /// Number of the first vectors, the ones mapped from a file.
+    pub fn mapped_len(&self) -> usize {
+        match self.appended {
+            Some(_) => self.vectors.len(),
+            None => 0,
+        }
+    }
+
+    /// Maps `file`, written by `write` with the vectors after the first `mapped_len`, and returns
+    /// the elements made of the mapped vectors of both. Pushes copy the vectors of `file` to memory.
+    ///
+    /// ## Safety
+    ///
+    /// See `from_file`.
+    pub unsafe fn join_file(&self, file: &File) -> io::Result<Self> {
+        let tail = Elements::from_file(file, self.metric, self.storage())?;
+        if self.mapped_len() == 0 {
+            return Ok(tail);
+        }
+        let tail = Arc::try_unwrap(tail.vectors).unwrap_or_else(|vectors| (*vectors).clone());
+        Ok(Elements {
+            metric: self.metric,
+            vectors: self.vectors.clone(),
+            appended: Some(tail),
+        })
+    }
+
+    /// Maps `file`, which holds the first of these vectors, keeping in memory only the ones after
+    /// them.
+    ///
+    /// ## Safety
+    ///
+    /// See `from_file`.
+    pub unsafe fn remap(&mut self, file: &File) -> io::Result<()> {
+        let mapped = Elements::from_file(file, self.metric, self.storage())?;
+        if mapped.len() > self.len() {
+            let message = format!("the file has {} vectors, more than the {} of the elements", mapped.len(), self.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        if mapped.is_empty() {
+            return Ok(());
+        }
+
+        let mut appended = Vectors::new(self.storage());
+        for idx in mapped.len()..self.len() {
+            let (vectors, idx) = self.locate(idx);
+            appended.push_from(vectors, idx);
+        }
+        self.vectors = mapped.vectors;
+        self.appended = Some(appended);
+        Ok(())
+    }
+}
+
+/// Builds the vector of `values` for an index with `metric`. Angular vectors are normalized, the
+/// rest are kept as they are since their norm is significant.
+pub fn vector_for(metric: Metric, values: Vec<f32>) -> Vector<'static> {
+    match metric {
+        Metric::Angular => Vector::from(values),
+        Metric::DotProduct | Metric::Euclidean => Vector(values.into()),
+    }
+}
+
+impl ElementContainer for Elements<'_> {
+    type Element = Vector<'static>;
+
+    fn get(&self, idx: usize) -> Self::Element {
+        self.get_element(idx).into_owned()
+    }
+
+    fn len(&self) -> usize {
+        Elements::len(self)
+    }
+
+    // Distances are only NaN for vectors with non-finite components, which `InvalidPolicy::Keep`
+    // lets through: they go after any other instead of aborting the build or the search.
+    fn dist_to_element(&self, idx: usize, element: &Self::Element) -> NotNan<f32> {
+        let distance = self.distance(idx, &element.0);
+        NotNan::new(distance).unwrap_or_else(|_| NotNan::new(f32::MAX).unwrap())
+    }
+
+    fn dist(&self, i: usize, j: usize) -> NotNan<f32> {
+        let ((vectors, i), (other, j)) = (self.locate(i), self.locate(j));
+        let distance = vectors.distance_to(self.metric, i, other, j);
+        NotNan::new(distance).unwrap_or_else(|_| NotNan::new(f32::MAX).unwrap())
+    }
+
+    fn dists(&self, idx: usize, others: &[usize]) -> Vec<NotNan<f32>> {
+        others.iter().map(|j| self.dist(idx, *j)).collect()
+    }
+}
+
+impl Writeable for Elements<'_> {
+    fn write<B: Write>(&self, buffer: &mut B) -> io::Result<usize> {
+        match &self.appended {
+            Some(_) => self.write_from(0, buffer),
+            None => self.vectors.write(buffer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, sync::Arc};
+
+    use granne::{angular::Vector, BuildConfig, Builder, ElementContainer, GranneBuilder, Index, Writeable};
+    use tempfile::NamedTempFile;
+
+    use super::Elements;
+    use crate::vectors::{InvalidVector, Metric, Storage, VectorError};
+
+    fn elements(metric: Metric) -> Elements<'static> {
+        let mut elements = Elements::new(metric, Storage::F32);
+        for v in [[1.0, 0.0], [0.0, 2.0], [3.0, 3.0]] {
+            elements.push(&Vector(v.to_vec().into())).unwrap();
+        }
+        elements
+    }
+
+    #[test]
+    fn distances() {
+        let query = Vector(vec![1.0, 1.0].into());
+
+        let angular = elements(Metric::Angular);
+        assert_eq!(angular.dist_to_element(0, &query).into_inner(), 0.0);
+
+        let dot = elements(Metric::DotProduct);
+        assert_eq!(dot.dist_to_element(0, &query).into_inner(), -1.0);
+        assert_eq!(dot.dist_to_element(2, &query).into_inner(), -6.0);
+
+        let euclidean = elements(Metric::Euclidean);
+        assert_eq!(euclidean.dist_to_element(0, &query).into_inner(), 1.0);
+        assert_eq!(euclidean.dist_to_element(2, &query).into_inner(), 8.0f32.sqrt());
+    }
+
+    #[test]
+    fn distances_between_elements() {
+        for (metric, storage) in [
+            (Metric::Angular, Storage::F32),
+            (Metric::Euclidean, Storage::F32),
+            (Metric::Angular, Storage::Int8),
+            (Metric::DotProduct, Storage::F16),
+        ] {
+            let mut elements = Elements::new(metric, storage);
+            for v in [[1.0, 0.0], [0.6, 0.8], [0.0, 1.0]] {
+                elements.push(&Vector(v.to_vec().into())).unwrap();
+            }
+
+            let dists: Vec<_> = elements.dists(0, &[0, 1, 2]).into_iter().map(|d| d.into_inner()).collect();
+            for (j, dist) in dists.into_iter().enumerate() {
+                let expected = elements.dist_to_element(0, &elements.get(j)).into_inner();
+                assert!((elements.dist(0, j).into_inner() - expected).abs() < 1e-2, "{:?} {:?}", metric, storage);
+                assert_eq!(dist, elements.dist(0, j).into_inner());
+            }
+        }
+    }
+
+    #[test]
+    fn build_and_search() {
+        let mut builder = GranneBuilder::new(BuildConfig::default(), elements(Metric::Euclidean));
+        builder.build();
+        assert_eq!(builder.len(), 3);
+
+        let index = builder.get_index();
+        let res = index.search(&Vector(vec![2.5, 2.5].into()), 10, 3);
+        let idxs: Vec<_> = res.iter().map(|(idx, _)| *idx).collect();
+        assert_eq!(idxs, [2, 1, 0]);
+    }
+
+    #[test]
+    fn int8() {
+        let mut elements = Elements::new(Metric::Angular, Storage::Int8);
+        elements.push(&Vector::from(vec![1.0, 0.0, 0.0])).unwrap();
+        elements.push(&Vector::from(vec![1.0, 1.0, 0.0])).unwrap();
+        assert_eq!(elements.dim(), 3);
+        assert_eq!(elements.get_element(0).0[..], [127.0, 0.0, 0.0]);
+
+        let query = Vector::from(vec![1.0, 1.0, 0.0]);
+        assert!(elements.dist_to_element(1, &query).into_inner() < 1e-6);
+        assert!((elements.dist_to_element(0, &query).into_inner() - (1.0 - 0.5f32.sqrt())).abs() < 1e-6);
+
+        // Long enough to fill the lanes, with a remainder.
+        let x: Vec<i8> = (0..21).map(|i| (i * 7 % 31 - 15) as i8).collect();
+        let y: Vec<f32> = (0..21).map(|i| (i as f32 * 0.37).sin()).collect();
+        let dot: f32 = x.iter().zip(&y).map(|(x, y)| *x as f32 * y).sum();
+        let norm_x = x.iter().map(|x| (*x as f32).powi(2)).sum::<f32>().sqrt();
+        let norm_y = y.iter().map(|y| y * y).sum::<f32>().sqrt();
+        assert!((super::int8_angular_distance(&x, &y) - (1.0 - dot / (norm_x * norm_y))).abs() < 1e-5);
+        assert_eq!(super::int8_angular_distance(&[0; 9], &y[..9]), 1.0);
+    }
+
+    #[test]
+    fn half_precision() {
+        for storage in [Storage::F16, Storage::Bf16] {
+            let mut elements = Elements::new(Metric::Euclidean, storage);
+            elements.push(&Vector(vec![1.0, 0.0, 2.0].into())).unwrap();
+            elements.push(&Vector(vec![0.1, -0.3, 0.7].into())).unwrap();
+            assert_eq!(elements.storage(), storage);
+            assert_eq!(elements.dim(), 3);
+            assert_eq!(elements.get_element(0).0[..], [1.0, 0.0, 2.0]);
+
+            let query = Vector(vec![0.0, 0.0, 0.0].into());
+            assert_eq!(elements.dist_to_element(0, &query).into_inner(), 5.0f32.sqrt());
+            assert!((elements.dist_to_element(1, &query).into_inner() - 0.59f32.sqrt()).abs() < 1e-2);
+        }
+    }
+
+    #[test]
+    fn sum_embeddings() {
+        let mut elements = Elements::new(Metric::Angular, Storage::SumEmbeddings);
+        assert_eq!(elements.push_embedding(&[1.0, 0.0]), 0);
+        assert_eq!(elements.push_embedding(&[0.0, 2.0]), 1);
+        elements.push_terms(&[0, 1]);
+        elements.push_terms(&[1]);
+
+        assert_eq!(elements.len(), 2);
+        assert_eq!(elements.dim(), 2);
+        assert_eq!(elements.sum_embeddings().unwrap().get_terms(0), [0, 1]);
+        assert_eq!(elements.get_element(0).0[..], Vector::from(vec![1.0, 2.0]).0[..]);
+
+        let query = elements.embed(&[1, 1]).unwrap();
+        assert!(elements.dist_to_element(1, &query).into_inner() < 1e-6);
+        assert!(elements.embed(&[]).is_err());
+        assert!(elements.embed(&[2]).is_err());
+        assert!(Elements::new(Metric::Angular, Storage::F32).embed(&[0]).is_err());
+
+        // Terms that cancel each other out have no direction.
+        assert_eq!(elements.push_embedding(&[-1.0, 0.0]), 2);
+        assert!(matches!(elements.embed(&[0, 2]), Err(VectorError::InvalidVector(InvalidVector::Zero))));
+        assert!(elements.push(&Vector::from(vec![1.0, 0.0])).is_err());
+        assert_eq!(elements.len(), 2);
+    }
+
+    #[test]
+    fn append_and_remap() {
+        for storage in [Storage::F32, Storage::Int8, Storage::F16] {
+            let vector = |i: usize| Vector::from(vec![1.0, i as f32]);
+            let mut expected = Elements::new(Metric::Angular, storage);
+            for i in 0..9 {
+                expected.push(&vector(i)).unwrap();
+            }
+
+            let mut elements = Elements::new(Metric::Angular, storage);
+            for i in 0..5 {
+                elements.push(&vector(i)).unwrap();
+            }
+            let mut file = NamedTempFile::new().unwrap();
+            elements.write(&mut file).unwrap();
+
+            // Vectors pushed to mapped elements stay in memory until they are appended.
+            let mut mapped = unsafe { Elements::from_file(file.as_file(), Metric::Angular, storage).unwrap() };
+            for i in 5..8 {
+                mapped.push(&vector(i)).unwrap();
+            }
+            assert_eq!(mapped.len(), 8);
+            let len = file.as_file().metadata().unwrap().len();
+            assert_eq!(mapped.stored_in(len), 5);
+            assert_eq!(mapped.offset(5), len);
+            mapped.write_from(5, &mut file).unwrap();
+
+            mapped.push(&vector(8)).unwrap();
+            unsafe { mapped.remap(file.as_file()).unwrap() };
+            assert_eq!(mapped.len(), 9);
+            assert_eq!(mapped.appended.as_ref().unwrap().len(), 1);
+
+            // The file written in parts is the one written at once.
+            let mut whole = Vec::new();
+            expected.write(&mut whole).unwrap();
+            assert_eq!(whole[..expected.offset(8) as usize], std::fs::read(file.path()).unwrap()[..]);
+            for idx in 0..9 {
+                assert_eq!(mapped.get_element(idx).0, expected.get_element(idx).0);
+            }
+        }
+    }
+
+    #[test]
+    fn clones_share_mapped_vectors() {
+        let mut file = NamedTempFile::new().unwrap();
+        elements(Metric::Euclidean).write(&mut file).unwrap();
+        let mut mapped = unsafe { Elements::from_file(file.as_file(), Metric::Euclidean, Storage::F32).unwrap() };
+        mapped.push(&Vector(vec![4.0, 4.0].into())).unwrap();
+
+        // A commit builds over a clone while the writer takes more vectors.
+        let mut clone = mapped.clone();
+        assert!(Arc::ptr_eq(&clone.vectors, &mapped.vectors));
+        mapped.push(&Vector(vec![5.0, 5.0].into())).unwrap();
+        assert_eq!((clone.len(), mapped.len()), (4, 5));
+        assert_eq!(clone.get_element(3).0[..], [4.0, 4.0]);
+
+        // Elements held in memory are copied by the first push to a shared clone.
+        let mut elements = elements(Metric::Euclidean);
+        let shared = elements.clone();
+        elements.push(&Vector(vec![6.0, 6.0].into())).unwrap();
+        assert!(!Arc::ptr_eq(&elements.vectors, &shared.vectors));
+        assert_eq!((shared.len(), elements.len()), (3, 4));
+        clone.push(&Vector(vec![7.0, 7.0].into())).unwrap();
+        assert_eq!(clone.get_element(4).0[..], [7.0, 7.0]);
+    }
+
+    #[test]
+    fn repair() {
+        let mut file = NamedTempFile::new().unwrap();
+        elements(Metric::Euclidean).write(&mut file).unwrap();
+        Elements::repair(file.as_file(), Storage::F32).unwrap();
+        assert_eq!(file.as_file().metadata().unwrap().len(), 8 + 3 * 8);
+
+        file.write_all(&[0; 5]).unwrap();
+        Elements::repair(file.as_file(), Storage::F32).unwrap();
+        assert_eq!(file.as_file().metadata().unwrap().len(), 8 + 3 * 8);
+        let mapped = unsafe { Elements::from_file(file.as_file(), Metric::Euclidean, Storage::F32).unwrap() };
+        assert_eq!(mapped.get_element(2).0[..], [3.0, 3.0]);
+    }
+}
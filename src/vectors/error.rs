@@ -0,0 +1,84 @@
+use std::{fmt, io};
+
+extern crate lmdb_zero as lmdb;
+
+use super::InvalidVector;
+
+/// Errors returned by the `Writer` and the `Reader`.
+#[derive(Debug)]
+pub enum VectorError {
+    /// A vector doesn't have the dimension recorded in the schema of the index.
+    DimensionMismatch { expected: usize, found: usize },
+    /// A batch doesn't have a document id for each vector.
+    BatchMismatch { doc_ids: usize, vectors: usize },
+    /// A vector was rejected by the ingestion pipeline.
+    InvalidVector(InvalidVector),
+    /// Another `Writer` holds the lock of the index, or a commit of the writer is running.
+    Locked(String),
+    /// The schema file of the index can't be read or doesn't match the index.
+    Schema(String),
+    /// The index has no vector space with this name.
+    UnknownSpace(String),
+    /// The index has no namespace with this name.
+    UnknownNamespace(String),
+    /// The `IndexManager` has no index with this name.
+    UnknownIndex(String),
+    /// The `IndexManager` already has an index with this name.
+    IndexExists(String),
+    /// The background task of an `AsyncReader` or an `AsyncWriter` stopped.
+    Stopped(String),
+    /// The commit was cancelled before publishing its generation.
+    Cancelled,
+    Storage(lmdb::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for VectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VectorError::DimensionMismatch { expected, found } => {
+                write!(f, "Vector of dimension {} in an index of dimension {}", found, expected)
+            }
+            VectorError::BatchMismatch { doc_ids, vectors } => {
+                write!(f, "Batch of {} vectors with {} document ids", vectors, doc_ids)
+            }
+            VectorError::InvalidVector(invalid) => write!(f, "Invalid vector: {}", invalid),
+            VectorError::Locked(message) => write!(f, "Adquiring lock for Writer: {}", message),
+            VectorError::Schema(message) => write!(f, "Invalid schema: {}", message),
+            VectorError::UnknownSpace(name) => write!(f, "Unknown vector space: {}", name),
+            VectorError::UnknownNamespace(name) => write!(f, "Unknown namespace: {}", name),
+            VectorError::UnknownIndex(name) => write!(f, "Unknown index: {}", name),
+            VectorError::IndexExists(name) => write!(f, "Index already exists: {}", name),
+            VectorError::Stopped(message) => write!(f, "Background task stopped: {}", message),
+            VectorError::Cancelled => write!(f, "Commit cancelled"),
+            VectorError::Storage(e) => write!(f, "Storage error: {}", e),
+            VectorError::Io(e) => write!(f, "IO error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for VectorError {}
+
+impl From<lmdb::Error> for VectorError {
+    fn from(e: lmdb::Error) -> Self {
+        VectorError::Storage(e)
+    }
+}
+
+impl From<InvalidVector> for VectorError {
+    fn from(invalid: InvalidVector) -> Self {
+        VectorError::InvalidVector(invalid)
+    }
+}
+
+impl From<io::Error> for VectorError {
+    fn from(e: io::Error) -> Self {
+        VectorError::Io(e)
+    }
+}
+
+impl From<VectorError> for String {
+    fn from(e: VectorError) -> Self {
+        e.to_string()
+    }
+}
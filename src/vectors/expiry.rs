@@ -0,0 +1,183 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lmdb::Database;
+extern crate lmdb_zero as lmdb;
+
+use super::storage;
+
+pub(crate) const EXPIRY_DB: &str = "expiry";
+pub(crate) const EXPIRY_BY_TIME_DB: &str = "expiry_by_time";
+
+/// Converts an expiry time to the seconds since the epoch stored in the database.
+pub fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs()
+}
+
+pub fn now() -> u64 {
+    timestamp(SystemTime::now())
+}
+
+/// Expiry time of the documents, as seconds since the epoch keyed by doc id, and the same entries
+/// keyed by time so that the ones due can be read without scanning the rest.
+#[derive(Debug)]
+pub struct ExpiryDB<'a> {
+    db: Database<'a>,
+    by_time: Database<'a>,
+}
+
+impl<'a> ExpiryDB<'a> {
+    pub fn open(env: &Arc<lmdb::Environment>) -> Result<Self, lmdb::Error> {
+        let db = storage::open_db(env, EXPIRY_DB, lmdb::db::Flags::empty())?;
+        let by_time = storage::open_db(env, EXPIRY_BY_TIME_DB, lmdb::db::DUPSORT | lmdb::db::DUPFIXED)?;
+
+        Ok(ExpiryDB { db, by_time })
+    }
+
+    /// Sets the expiry time of `doc_id` as part of `txn`, replacing the previous one.
+    pub fn set_in(&self, txn: &lmdb::WriteTransaction, doc_id: usize, expires_at: u64) -> Result<(), lmdb::Error> {
+        trace!("\tExpire {} at {}", doc_id, expires_at);
+        self.clear_in(txn, &[doc_id])?;
+
+        let mut access = txn.access();
+        let key = storage::encode_id(doc_id);
+        access.put::<[u8], [u8]>(&self.db, &key, &expires_at.to_be_bytes(), lmdb::put::Flags::empty())?;
+        access.put::<[u8], [u8]>(&self.by_time, &expires_at.to_be_bytes(), &key, lmdb::put::Flags::empty())
+    }
+
+    /// Removes the expiry time of `doc_ids` as part of `txn`.
+    pub fn clear_in(&self, txn: &lmdb::WriteTransaction, doc_ids: &[usize]) -> Result<(), lmdb::Error> {
+        let mut access = txn.access();
+        for doc_id in doc_ids {
+            let key = storage::encode_id(*doc_id);
+            let expires_at = match access.get::<[u8], [u8]>(&self.db, &key) {
+                Ok(expires_at) => expires_at.to_vec(),
+                Err(lmdb::Error::Code(lmdb::error::NOTFOUND)) => continue,
+                Err(e) => return Err(e),
+            };
+            access.del_key::<[u8]>(&self.db, &key)?;
+            access.del_item::<[u8], [u8]>(&self.by_time, &expires_at, &key)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the expiry time of every document that has one.
+    pub fn all(&self) -> Result<HashMap<usize, u64>, lmdb::Error> {
+        let txn = storage::read_txn(self.db.env())?;
+        let mut results = HashMap::new();
+        storage::scan_range(&txn, &self.db, 0..usize::MAX, |doc_id, expires_at| {
+            results.insert(doc_id, u64::from_be_bytes(expires_at.try_into().unwrap()));
+        })?;
+        Ok(results)
+    }
+
+    /// Returns the doc ids that expire in `times`, in seconds since the epoch, reading only their
+    /// entries.
+    pub fn expiring(&self, times: Range<u64>) -> Result<Vec<usize>, lmdb::Error> {
+        let txn = storage::read_txn(self.db.env())?;
+        self.expiring_in(&txn, times)
+    }
+
+    pub fn expiring_in(&self, txn: &lmdb::ConstTransaction, times: Range<u64>) -> Result<Vec<usize>, lmdb::Error> {
+        let mut doc_ids = Vec::new();
+        let times = times.start as usize..times.end as usize;
+        storage::scan_range(txn, &self.by_time, times, |_expires_at, doc_id| {
+            doc_ids.push(storage::decode_id(doc_id));
+        })?;
+        doc_ids.sort_unstable();
+        Ok(doc_ids)
+    }
+
+    /// Returns the doc ids that expired at `now` or before.
+    pub fn expired_in(&self, txn: &lmdb::ConstTransaction, now: u64) -> Result<Vec<usize>, lmdb::Error> {
+        self.expiring_in(txn, 0..now + 1)
+    }
+}
+
+/// Documents that expired and are not tombstoned yet, read from the entries of the `ExpiryDB`
+/// that are due. Commits clear the entries of the documents they tombstone, so there are only
+/// the ones expired since the last commit to read.
+#[derive(Debug, Default)]
+pub struct Expired {
+    doc_ids: HashSet<usize>,
+}
+
+impl Expired {
+    pub fn load(db: &ExpiryDB) -> Result<Self, lmdb::Error> {
+        let mut expired = Expired::default();
+        expired.update(db)?;
+        Ok(expired)
+    }
+
+    /// Reads the documents that expired again, so expiry times set in the past and cleared
+    /// since the last read are seen too.
+    pub fn update(&mut self, db: &ExpiryDB) -> Result<(), lmdb::Error> {
+        self.doc_ids = db.expiring(0..now() + 1)?.into_iter().collect();
+        Ok(())
+    }
+
+    pub fn contains(&self, doc_id: usize) -> bool {
+        self.doc_ids.contains(&doc_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.doc_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.doc_ids.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use log::LevelFilter;
+    use tempfile::tempdir;
+
+    use super::ExpiryDB;
+    use crate::vectors::storage;
+
+    fn init() {
+        let _ = env_logger::builder()
+            .filter_level(LevelFilter::Trace)
+            .is_test(true)
+            .try_init();
+    }
+
+    #[test]
+    fn set_and_clear() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let db = ExpiryDB::open(&env).unwrap();
+
+        storage::write(&env, |txn| {
+            db.set_in(txn, 1, 100)?;
+            db.set_in(txn, 2, 100)?;
+            db.set_in(txn, 3, 200)?;
+            db.set_in(txn, 2, 300)
+        })
+        .unwrap();
+
+        let all = db.all().unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[&2], 300);
+        assert_eq!(db.expiring(100..300).unwrap(), [1, 3]);
+
+        storage::write(&env, |txn| {
+            assert_eq!(db.expired_in(txn, 99)?, Vec::<usize>::new());
+            assert_eq!(db.expired_in(txn, 200)?, [1, 3]);
+            db.clear_in(txn, &[1, 4])
+        })
+        .unwrap();
+
+        assert!(!db.all().unwrap().contains_key(&1));
+        assert_eq!(db.expiring(0..u64::MAX).unwrap(), [2, 3]);
+    }
+}
@@ -0,0 +1,213 @@
+use std::{fs::File, io, sync::Arc};
+
+use half::{bf16, f16};
+use memmap::Mmap;
+
+use super::InvalidVector;
+
+/// 16-bit float format of the components of `HalfVectors`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HalfFormat {
+    /// IEEE 754 half precision: 11 bits of precision, values up to 65504.
+    F16,
+    /// bfloat16: the range of `f32` with 8 bits of precision.
+    Bf16,
+}
+
+impl HalfFormat {
+    /// Checks that the finite components of `vector` don't round to infinity in this format.
+    pub fn check(&self, vector: &[f32]) -> Result<(), InvalidVector> {
+        let overflows = |value: &f32| value.is_finite() && self.decode(self.encode(*value)).is_infinite();
+        match vector.iter().position(overflows) {
+            Some(position) => Err(InvalidVector::OutOfRange(position)),
+            None => Ok(()),
+        }
+    }
+
+    fn encode(&self, value: f32) -> u16 {
+        match self {
+            HalfFormat::F16 => f16::from_f32(value).to_bits(),
+            HalfFormat::Bf16 => bf16::from_f32(value).to_bits(),
+        }
+    }
+
+    fn decode(&self, bits: u16) -> f32 {
+        match self {
+            HalfFormat::F16 => f16::from_bits(bits).to_f32(),
+            HalfFormat::Bf16 => bf16::from_bits(bits).to_f32(),
+        }
+    }
+}
+
+/// Size of the header of the file, the dimension as a little-endian `u64`.
+const HEADER_LEN: usize = 8;
+
+#[derive(Clone)]
+enum Data {
+    Owned(Vec<u8>),
+    Mapped(Arc<Mmap>),
+}
+
+/// Vectors stored with 16-bit components, half the size of `f32` vectors.
+///
+/// Components are little-endian `u16`s after the header, both on disk and in memory, and they are
+/// converted to `f32` when read.
+#[derive(Clone)]
+pub struct HalfVectors {
+    format: HalfFormat,
+    dim: usize,
+    data: Data,
+}
+
+impl HalfVectors {
+    pub fn new(format: HalfFormat) -> Self {
+        HalfVectors {
+            format,
+            dim: 0,
+            data: Data::Owned(Vec::new()),
+        }
+    }
+
+    /// Memory-maps the vectors stored in `file`.
+    ///
+    /// ## Safety
+    ///
+    /// The file must not be modified while it is mapped.
+    pub unsafe fn from_file(file: &File, format: HalfFormat) -> io::Result<Self> {
+        if file.metadata()?.len() < HEADER_LEN as u64 {
+            return Ok(HalfVectors::new(format));
+        }
+
+        let mmap = Mmap::map(file)?;
+        let mut header = [0; HEADER_LEN];
+        header.copy_from_slice(&mmap[..HEADER_LEN]);
+        Ok(HalfVectors {
+            format,
+            dim: u64::from_le_bytes(header) as usize,
+            data: Data::Mapped(Arc::new(mmap)),
+        })
+    }
+
+    pub fn format(&self) -> HalfFormat {
+        self.format
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.data {
+            Data::Owned(bytes) => bytes,
+            Data::Mapped(mmap) => &mmap[HEADER_LEN..],
+        }
+    }
+
+    /// Pushes `vector`, rounded to the format, which should be checked with `HalfFormat::check`
+    /// first. A mapped collection is copied to memory first.
+    pub fn push(&mut self, vector: &[f32]) {
+        if let Data::Mapped(mmap) = &self.data {
+            self.data = Data::Owned(mmap[HEADER_LEN..].to_vec());
+        }
+        if self.dim == 0 {
+            self.dim = vector.len();
+        }
+        assert_eq!(self.dim, vector.len(), "All the vectors must have the same dimension");
+
+        let format = self.format;
+        if let Data::Owned(bytes) = &mut self.data {
+            bytes.extend(vector.iter().flat_map(|value| format.encode(*value).to_le_bytes()));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self.dim {
+            0 => 0,
+            dim => self.bytes().len() / (2 * dim),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// Components of the vector at `idx`, converted to `f32` as they are read.
+    pub fn components(&self, idx: usize) -> impl Iterator<Item = f32> + '_ {
+        let row = 2 * self.dim;
+        self.bytes()[idx * row..(idx + 1) * row]
+            .chunks_exact(2)
+            .map(|bits| self.format.decode(u16::from_le_bytes([bits[0], bits[1]])))
+    }
+
+    pub fn get_element(&self, idx: usize) -> Vec<f32> {
+        self.components(idx).collect()
+    }
+
+    /// Components of the vector at `idx` as they are stored.
+    pub fn raw(&self, idx: usize) -> &[u8] {
+        let row = 2 * self.dim;
+        &self.bytes()[idx * row..(idx + 1) * row]
+    }
+
+    pub fn write<B: io::Write>(&self, buffer: &mut B) -> io::Result<usize> {
+        buffer.write_all(&(self.dim as u64).to_le_bytes())?;
+        buffer.write_all(self.bytes())?;
+        Ok(HEADER_LEN + self.bytes().len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::NamedTempFile;
+
+    use super::{HalfFormat, HalfVectors};
+    use crate::vectors::InvalidVector;
+
+    #[test]
+    fn push_and_get() {
+        let mut vectors = HalfVectors::new(HalfFormat::F16);
+        assert_eq!(vectors.dim(), 0);
+        vectors.push(&[1.0, -0.5, 0.1]);
+        vectors.push(&[65504.0, 0.0, 1e-3]);
+        assert_eq!(vectors.len(), 2);
+        assert_eq!(vectors.dim(), 3);
+
+        let vector = vectors.get_element(0);
+        assert_eq!(vector[..2], [1.0, -0.5]);
+        assert!((vector[2] - 0.1).abs() < 1e-4);
+        assert_eq!(vectors.get_element(1)[0], 65504.0);
+
+        // Out of the range of f16, but not of bf16.
+        assert_eq!(HalfFormat::F16.check(&[1.0, -70000.0]), Err(InvalidVector::OutOfRange(1)));
+        assert_eq!(HalfFormat::F16.check(&[65504.0, f32::NAN]), Ok(()));
+        assert_eq!(HalfFormat::Bf16.check(&[1.0, -70000.0]), Ok(()));
+
+        let mut vectors = HalfVectors::new(HalfFormat::Bf16);
+        vectors.push(&[70000.0, 0.1]);
+        let vector = vectors.get_element(0);
+        assert!((vector[0] - 70000.0).abs() < 300.0);
+        assert!((vector[1] - 0.1).abs() < 1e-3);
+    }
+
+    #[test]
+    fn write_and_map() {
+        let mut vectors = HalfVectors::new(HalfFormat::Bf16);
+        for i in 0..10 {
+            vectors.push(&[i as f32, -(i as f32)]);
+        }
+
+        let mut file = NamedTempFile::new().unwrap();
+        assert_eq!(vectors.write(&mut file).unwrap(), 8 + 10 * 2 * 2);
+
+        let mut mapped = unsafe { HalfVectors::from_file(file.as_file(), HalfFormat::Bf16).unwrap() };
+        assert_eq!(mapped.len(), 10);
+        assert_eq!(mapped.get_element(7), [7.0, -7.0]);
+
+        mapped.push(&[10.0, -10.0]);
+        assert_eq!(mapped.len(), 11);
+        assert_eq!(mapped.get_element(10), [10.0, -10.0]);
+
+        let empty = NamedTempFile::new().unwrap();
+        assert!(unsafe { HalfVectors::from_file(empty.as_file(), HalfFormat::F16).unwrap() }.is_empty());
+    }
+}
@@ -1,6 +1,13 @@
+use std::{ops::Range, sync::Arc};
+
 use lmdb::Database;
 extern crate lmdb_zero as lmdb;
 
+use super::storage;
+
+pub(crate) const INDEX_MAP_DB: &str = "index_map";
+pub(crate) const INDEX_MAP_INVERTED_DB: &str = "index_map_inverted";
+
 #[derive(Debug)]
 pub struct IndexMap<'a> {
     db: Database<'a>,
@@ -8,125 +15,89 @@ pub struct IndexMap<'a> {
 }
 
 impl<'a> IndexMap<'a> {
-    pub fn open(path: &str) -> Result<Self, lmdb::Error> {
-        let inverted_path = path.to_string() + "inverted";
-        std::fs::create_dir_all(path).unwrap();
-        std::fs::create_dir_all(&inverted_path).unwrap();
-
-        let flags = lmdb::open::Flags::empty();
-        let database_options = lmdb::DatabaseOptions::new(lmdb::db::DUPSORT);
-        let database_options_inverted = lmdb::DatabaseOptions::defaults();
-
-        let env = unsafe {
-            lmdb::EnvBuilder::new()
-                .unwrap()
-                .open(path, flags, 0o666)
-                .unwrap()
-        };
-
-        let env_inverted = unsafe {
-            lmdb::EnvBuilder::new()
-                .unwrap()
-                .open(&inverted_path, flags, 0o666)
-                .unwrap()
-        };
-
-        let db = lmdb::Database::open(env, None, &database_options)?;
-        let db_inverted = lmdb::Database::open(env_inverted, None, &database_options_inverted)?;
+    pub fn open(env: &Arc<lmdb::Environment>) -> Result<Self, lmdb::Error> {
+        let db = storage::open_db(env, INDEX_MAP_DB, lmdb::db::DUPSORT | lmdb::db::DUPFIXED)?;
+        let db_inverted = storage::open_db(env, INDEX_MAP_INVERTED_DB, lmdb::db::Flags::empty())?;
 
         Ok(IndexMap { db, db_inverted })
     }
 
     /// Returns all the internal vectors ids for a document.
     pub fn get_vec_ids(&self, doc_id: usize) -> Result<Vec<usize>, lmdb::Error> {
+        let txn = storage::read_txn(self.db.env())?;
+        self.get_vec_ids_in(&txn, doc_id)
+    }
+
+    /// Returns all the internal vectors ids for a document, as seen by `txn`.
+    pub fn get_vec_ids_in(&self, txn: &lmdb::ConstTransaction, doc_id: usize) -> Result<Vec<usize>, lmdb::Error> {
         trace!("Obtaining all vector idxs for document: {}", doc_id);
-        let key = bincode::serialize(&doc_id).unwrap();
+        let key = storage::encode_id(doc_id);
 
-        let env = self.db.env();
-        let txn = lmdb::ReadTransaction::new(env).unwrap();
         let access = txn.access();
-
-        let mut cursor = txn.cursor(&self.db).unwrap();
+        let mut cursor = txn.cursor(&self.db)?;
 
         let mut results = Vec::new();
         match cursor.seek_k::<[u8], [u8]>(&access, &key) {
             Ok(v) => {
-                let v: usize = bincode::deserialize(v).unwrap();
-                results.push(v);
+                results.push(storage::decode_id(v));
                 while let Ok((_, v)) = cursor.next_dup::<[u8], [u8]>(&access) {
-                    let v: usize = bincode::deserialize(v).unwrap();
-                    results.push(v);
+                    results.push(storage::decode_id(v));
                 }
             }
             Err(e) => {
-                error!("Error looking for key {}: {}", doc_id, e.to_string());
+                error!("Error looking for key {}: {}", doc_id, e);
             }
         }
         Ok(results)
     }
 
-    /// Returns all the internal vectors ids for a document.
+    /// Returns the document a vector belongs to.
     pub fn get_doc_id(&self, vec_id: usize) -> Result<usize, lmdb::Error> {
-        let key = bincode::serialize(&vec_id).unwrap();
+        let key = storage::encode_id(vec_id);
 
         let env = self.db_inverted.env();
-        let txn = lmdb::ReadTransaction::new(env).unwrap();
+        let txn = storage::read_txn(env)?;
         let access = txn.access();
 
         match access.get::<[u8], [u8]>(&self.db_inverted, &key) {
-            Ok(v) => Ok(bincode::deserialize(v).unwrap()),
+            Ok(v) => Ok(storage::decode_id(v)),
             Err(e) => Err(e),
         }
     }
 
-    fn insert_at(db: &Database, key: &[u8], val: &[u8]) -> Result<(), lmdb::Error> {
-        let env = db.env();
-        let txn = lmdb::WriteTransaction::new(env)?;
-        let flags = lmdb::put::Flags::empty();
-        {
-            let mut access = txn.access();
-            access.put::<[u8], [u8]>(db, key, val, flags)?;
-        }
-        txn.commit()?;
-        Ok(())
-    }
-
     /// Adds a new internal vec_id to the list of associated vectors of a document.
     pub fn insert(&self, doc_id: usize, vec_id: usize) -> Result<(), lmdb::Error> {
-        trace!("Add doc_id {} <-> vec_id {}", doc_id, vec_id);
-
-        let key = bincode::serialize(&doc_id).unwrap();
-        let val = bincode::serialize(&vec_id).unwrap();
-
-        IndexMap::insert_at(&self.db, &key, &val)?;
-        IndexMap::insert_at(&self.db_inverted, &val, &key)?;
-
-        Ok(())
+        storage::write(self.db.env(), |txn| self.insert_in(txn, doc_id, vec_id))
     }
 
-    fn insert_at_batch(db: &Database, key: &[usize], val: &[usize]) -> Result<(), lmdb::Error> {
-        let env = db.env();
-        let txn = lmdb::WriteTransaction::new(env)?;
-        let flags = lmdb::put::Flags::empty();
-        {
-            let mut access = txn.access();
-
-            for i in 0..key.len() {
-                let key = bincode::serialize(&key[i]).unwrap();
-                let val = bincode::serialize(&val[i]).unwrap();
-                access.put::<[u8], [u8]>(db, &key, &val, flags)?;
-            }
-        }
-        txn.commit()?;
-        Ok(())
+    /// Adds a new internal vec_id to the list of associated vectors of a document as part of `txn`.
+    pub fn insert_in(&self, txn: &lmdb::WriteTransaction, doc_id: usize, vec_id: usize) -> Result<(), lmdb::Error> {
+        self.insert_batch_in(txn, &[doc_id], &[vec_id])
     }
 
     /// Adds a new internal vec_id to the list of associated vectors of a document.
     pub fn insert_batch(&self, doc_ids: &[usize], vec_ids: &[usize]) -> Result<(), lmdb::Error> {
-        assert_eq!(doc_ids.len(), vec_ids.len());
-        IndexMap::insert_at_batch(&self.db, doc_ids, vec_ids)?;
-        IndexMap::insert_at_batch(&self.db_inverted, vec_ids, doc_ids)?;
+        storage::write(self.db.env(), |txn| self.insert_batch_in(txn, doc_ids, vec_ids))
+    }
 
+    /// Adds a batch of mappings as part of `txn`, both maps are updated in the same transaction.
+    pub fn insert_batch_in(
+        &self,
+        txn: &lmdb::WriteTransaction,
+        doc_ids: &[usize],
+        vec_ids: &[usize],
+    ) -> Result<(), lmdb::Error> {
+        assert_eq!(doc_ids.len(), vec_ids.len());
+        let flags = lmdb::put::Flags::empty();
+        let mut access = txn.access();
+
+        for (doc_id, vec_id) in doc_ids.iter().zip(vec_ids) {
+            trace!("Add doc_id {} <-> vec_id {}", doc_id, vec_id);
+            let key = storage::encode_id(*doc_id);
+            let val = storage::encode_id(*vec_id);
+            access.put::<[u8], [u8]>(&self.db, &key, &val, flags)?;
+            access.put::<[u8], [u8]>(&self.db_inverted, &val, &key, flags)?;
+        }
         Ok(())
     }
 
@@ -134,16 +105,47 @@ impl<'a> IndexMap<'a> {
     ///
     /// The inverted index is not modified since these elements still exists in granne vectors
     pub fn delete(&self, doc_id: usize) -> Result<(), lmdb::Error> {
-        let env = self.db.env();
-        let txn = lmdb::WriteTransaction::new(env)?;
-        {
-            let mut access = txn.access();
-            let key = bincode::serialize(&doc_id).unwrap();
-            access.del_key(&self.db, &key)?;
+        storage::write(self.db.env(), |txn| self.delete_in(txn, doc_id))
+    }
+
+    /// Deletes all the entries of a doc_id as part of `txn`.
+    pub fn delete_in(&self, txn: &lmdb::WriteTransaction, doc_id: usize) -> Result<(), lmdb::Error> {
+        let mut access = txn.access();
+        let key = storage::encode_id(doc_id);
+        access.del_key(&self.db, &key)
+    }
+
+    /// Deletes the inverse entries of vectors that no longer exist, as part of `txn`.
+    pub fn delete_vec_ids_in(&self, txn: &lmdb::WriteTransaction, vec_ids: &[usize]) -> Result<(), lmdb::Error> {
+        let mut access = txn.access();
+        for vec_id in vec_ids {
+            match access.del_key::<[u8]>(&self.db_inverted, &storage::encode_id(*vec_id)) {
+                Ok(()) | Err(lmdb::Error::Code(lmdb::error::NOTFOUND)) => (),
+                Err(e) => return Err(e),
+            }
         }
-        txn.commit()?;
         Ok(())
     }
+
+    /// Returns the `(vec_id, doc_id)` pairs of the vectors with a vec id in `range`.
+    pub fn vec_ids_in_range(&self, range: Range<usize>) -> Result<Vec<(usize, usize)>, lmdb::Error> {
+        let txn = storage::read_txn(self.db_inverted.env())?;
+        let mut results = Vec::new();
+        storage::scan_range(&txn, &self.db_inverted, range, |vec_id, doc_id| {
+            results.push((vec_id, storage::decode_id(doc_id)))
+        })?;
+        Ok(results)
+    }
+
+    /// Returns the `(doc_id, vec_id)` pairs of the documents with a doc id in `range`.
+    pub fn doc_ids_in_range(&self, range: Range<usize>) -> Result<Vec<(usize, usize)>, lmdb::Error> {
+        let txn = storage::read_txn(self.db.env())?;
+        let mut results = Vec::new();
+        storage::scan_range(&txn, &self.db, range, |doc_id, vec_id| {
+            results.push((doc_id, storage::decode_id(vec_id)))
+        })?;
+        Ok(results)
+    }
 }
 
 #[cfg(test)]
@@ -152,6 +154,7 @@ mod test {
     use tempfile::tempdir;
 
     use super::IndexMap;
+    use crate::vectors::storage;
 
     fn init() {
         let _ = env_logger::builder()
@@ -165,8 +168,8 @@ mod test {
         init();
 
         let tempdir = tempdir().unwrap();
-        let path = tempdir.path().to_str().unwrap();
-        let map = IndexMap::open(path).unwrap();
+        let env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let map = IndexMap::open(&env).unwrap();
 
         map.insert(0, 0).unwrap();
         map.insert(1, 4).unwrap();
@@ -183,8 +186,8 @@ mod test {
         init();
 
         let tempdir = tempdir().unwrap();
-        let path = tempdir.path().to_str().unwrap();
-        let map = IndexMap::open(path).unwrap();
+        let env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let map = IndexMap::open(&env).unwrap();
 
         map.insert(0, 0).unwrap();
         map.insert(0, 1).unwrap();
@@ -208,8 +211,8 @@ mod test {
         init();
 
         let tempdir = tempdir().unwrap();
-        let path = tempdir.path().to_str().unwrap();
-        let map = IndexMap::open(path).unwrap();
+        let env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let map = IndexMap::open(&env).unwrap();
 
         map.insert(0, 0).unwrap();
         map.insert(0, 1).unwrap();
@@ -239,8 +242,8 @@ mod test {
         init();
 
         let tempdir = tempdir().unwrap();
-        let path = tempdir.path().to_str().unwrap();
-        let map = IndexMap::open(path).unwrap();
+        let env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let map = IndexMap::open(&env).unwrap();
 
         /*
         map.insert(0, 0).unwrap();
@@ -262,4 +265,29 @@ mod test {
         assert_eq!(map.get_doc_id(3).unwrap(), 1);
         assert_eq!(map.get_doc_id(4).unwrap(), 1);
     }
+
+    #[test]
+    fn ranges() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let map = IndexMap::open(&env).unwrap();
+
+        // Ids crossing byte boundaries, where little-endian keys would be out of order.
+        map.insert_batch(&[1, 1, 300, 2, 70_000], &[0, 255, 256, 257, 65_536])
+            .unwrap();
+
+        assert_eq!(
+            map.vec_ids_in_range(0..257).unwrap(),
+            vec![(0, 1), (255, 1), (256, 300)]
+        );
+        assert_eq!(map.vec_ids_in_range(256..1_000_000).unwrap(), vec![(256, 300), (257, 2), (65_536, 70_000)]);
+        assert!(map.vec_ids_in_range(10..10).unwrap().is_empty());
+
+        assert_eq!(
+            map.doc_ids_in_range(1..301).unwrap(),
+            vec![(1, 0), (1, 255), (2, 257), (300, 256)]
+        );
+    }
 }
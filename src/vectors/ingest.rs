@@ -0,0 +1,180 @@
+use std::fmt;
+
+use super::VectorError;
+
+/// What to do with a vector that has `NaN` or infinite components.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InvalidPolicy {
+    /// The vector is indexed as it is.
+    #[default]
+    Keep,
+    /// The vector is rejected.
+    Reject,
+    /// The non-finite components are replaced by `0.0`.
+    Repair,
+}
+
+/// Reason why a vector can't be indexed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidVector {
+    Empty,
+    /// The component at this position is `NaN` or infinite.
+    NotFinite(usize),
+    /// All the components are zero, the angle to any other vector is undefined.
+    Zero,
+    /// The term id is not in the embedding table of the index.
+    UnknownTerm(usize),
+    /// The component at this position is finite but too large for the storage of the index.
+    OutOfRange(usize),
+}
+
+impl fmt::Display for InvalidVector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidVector::Empty => write!(f, "empty vector"),
+            InvalidVector::NotFinite(position) => write!(f, "non-finite component at position {}", position),
+            InvalidVector::Zero => write!(f, "zero vector"),
+            InvalidVector::UnknownTerm(term) => write!(f, "unknown term {}", term),
+            InvalidVector::OutOfRange(position) => write!(f, "component at position {} out of range", position),
+        }
+    }
+}
+
+/// Checks and transformations applied by the `Writer` to every vector before indexing it.
+///
+/// Vectors are truncated first, then validated and finally normalized, so the prefix of a
+/// Matryoshka embedding is normalized on its own. The default config indexes the vectors as they
+/// are pushed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IngestConfig {
+    pub(crate) invalid: InvalidPolicy,
+    pub(crate) reject_zero: bool,
+    pub(crate) normalize: bool,
+    pub(crate) truncate: Option<usize>,
+}
+
+impl IngestConfig {
+    pub fn new() -> Self {
+        IngestConfig::default()
+    }
+
+    pub fn invalid(mut self, invalid: InvalidPolicy) -> Self {
+        self.invalid = invalid;
+        self
+    }
+
+    /// Rejects the vectors whose components are all zero, after repairing them. Normalizing
+    /// rejects them too, since they have no direction.
+    pub fn reject_zero(mut self, reject_zero: bool) -> Self {
+        self.reject_zero = reject_zero;
+        self
+    }
+
+    /// L2-normalizes the vectors.
+    pub fn normalize(mut self, normalize: bool) -> Self {
+        self.normalize = normalize;
+        self
+    }
+
+    /// Keeps only the first `dimension` components of longer vectors.
+    pub fn truncate(mut self, dimension: usize) -> Self {
+        self.truncate = Some(dimension);
+        self
+    }
+
+    /// Runs the pipeline on `vector`. Readers run their queries through it too, see
+    /// `ReaderConfig::ingest`.
+    pub fn apply(&self, vector: &[f32]) -> Result<Vec<f32>, VectorError> {
+        let len = match self.truncate {
+            Some(dimension) => vector.len().min(dimension),
+            None => vector.len(),
+        };
+        let mut vector = vector[..len].to_vec();
+
+        if vector.is_empty() {
+            return Err(InvalidVector::Empty.into());
+        }
+
+        for (position, value) in vector.iter_mut().enumerate() {
+            if !value.is_finite() {
+                match self.invalid {
+                    InvalidPolicy::Keep => (),
+                    InvalidPolicy::Reject => return Err(InvalidVector::NotFinite(position).into()),
+                    InvalidPolicy::Repair => *value = 0.0,
+                }
+            }
+        }
+
+        if self.reject_zero || self.normalize {
+            let norm = vector.iter().map(|value| value * value).sum::<f32>().sqrt();
+            if norm == 0.0 {
+                return Err(InvalidVector::Zero.into());
+            }
+            if self.normalize {
+                vector.iter_mut().for_each(|value| *value /= norm);
+            }
+        }
+
+        Ok(vector)
+    }
+}
+
+/// Outcome of pushing a batch: the valid vectors are pushed and the invalid ones reported.
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub pushed: usize,
+    /// Position in the batch and reason of each vector that was not pushed.
+    pub rejected: Vec<(usize, VectorError)>,
+}
+
+impl BatchReport {
+    pub fn is_complete(&self) -> bool {
+        self.rejected.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IngestConfig, InvalidPolicy, InvalidVector};
+    use crate::vectors::VectorError;
+
+    fn invalid(result: Result<Vec<f32>, VectorError>) -> InvalidVector {
+        match result {
+            Err(VectorError::InvalidVector(invalid)) => invalid,
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let config = IngestConfig::new();
+        assert_eq!(config.apply(&[0.0, 0.0]).unwrap(), [0.0, 0.0]);
+        assert!(config.apply(&[1.0, f32::NAN]).unwrap()[1].is_nan());
+        assert_eq!(invalid(config.apply(&[])), InvalidVector::Empty);
+
+        let config = config.invalid(InvalidPolicy::Reject).reject_zero(true);
+        assert_eq!(config.apply(&[1.0, 2.0]).unwrap(), [1.0, 2.0]);
+        assert_eq!(invalid(config.apply(&[])), InvalidVector::Empty);
+        assert_eq!(invalid(config.apply(&[0.0, 0.0])), InvalidVector::Zero);
+        assert_eq!(invalid(config.apply(&[1.0, f32::NAN])), InvalidVector::NotFinite(1));
+        assert_eq!(invalid(config.apply(&[f32::INFINITY, 1.0])), InvalidVector::NotFinite(0));
+
+        let config = config.invalid(InvalidPolicy::Repair);
+        assert_eq!(config.apply(&[1.0, f32::NAN, f32::NEG_INFINITY]).unwrap(), [1.0, 0.0, 0.0]);
+        assert_eq!(invalid(config.apply(&[f32::NAN, 0.0])), InvalidVector::Zero);
+    }
+
+    #[test]
+    fn normalize_and_truncate() {
+        let config = IngestConfig::new().normalize(true);
+        assert_eq!(config.apply(&[3.0, 4.0]).unwrap(), [0.6, 0.8]);
+
+        let config = config.truncate(2);
+        assert_eq!(config.apply(&[3.0, 4.0, 100.0]).unwrap(), [0.6, 0.8]);
+        assert_eq!(config.apply(&[2.0]).unwrap(), [1.0]);
+
+        // Only the prefix has to be valid.
+        assert_eq!(config.apply(&[0.0, 1.0, f32::NAN]).unwrap(), [0.0, 1.0]);
+        assert_eq!(invalid(config.apply(&[0.0, 0.0, 1.0])), InvalidVector::Zero);
+    }
+}
@@ -34,7 +34,7 @@ impl Lock {
                 Ok(())
             }
             Err(e) => {
-                let message = format!("Error setting dirty file: {}", e.to_string());
+                let message = format!("Error setting dirty file: {}", e);
                 error!("{}", message);
                 Err(message)
             }
@@ -138,7 +138,7 @@ mod tests {
     #[test]
     fn parent_dir_doesnt_exists() {
         let temp_file = "/tmp/this_dir_doesnt_exists/lock";
-        let lock = Lock::open(&temp_file);
+        let lock = Lock::open(temp_file);
         assert!(lock.is_err());
     }
 
@@ -0,0 +1,280 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use lru::LruCache;
+
+use super::{spaces, ManagerConfig, Reader, VectorError, Writer};
+
+/// Named indexes stored under a root directory, one directory per index.
+///
+/// The manager hands out the single `Writer` of each index and keeps the most recently used
+/// `Reader`s open, so applications don't open indexes by path.
+pub struct IndexManager {
+    root: PathBuf,
+    config: ManagerConfig,
+    readers: Mutex<LruCache<String, Arc<Reader<'static>>>>,
+    /// Held while a reader of the index is opened, see `reader`.
+    opening: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    writers: Mutex<HashMap<String, Arc<Mutex<Writer<'static>>>>>,
+}
+
+impl IndexManager {
+    pub fn open<T: Into<PathBuf>>(root: T) -> Result<Self, VectorError> {
+        IndexManager::open_with_config(root, ManagerConfig::default())
+    }
+
+    pub fn open_with_config<T: Into<PathBuf>>(root: T, config: ManagerConfig) -> Result<Self, VectorError> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        let capacity = NonZeroUsize::new(config.max_readers).unwrap_or(NonZeroUsize::MIN);
+
+        Ok(IndexManager {
+            root,
+            config,
+            readers: Mutex::new(LruCache::new(capacity)),
+            opening: Mutex::new(HashMap::new()),
+            writers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, VectorError> {
+        spaces::check_name(name)?;
+        Ok(self.root.join(name))
+    }
+
+    fn existing_path(&self, name: &str) -> Result<PathBuf, VectorError> {
+        let path = self.path(name)?;
+        match path.is_dir() {
+            true => Ok(path),
+            false => Err(VectorError::UnknownIndex(name.to_string())),
+        }
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.existing_path(name).is_ok()
+    }
+
+    /// Names of the indexes under the root, in alphabetical order.
+    pub fn list(&self) -> Result<Vec<String>, VectorError> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.extend(entry.file_name().to_str().map(str::to_string));
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Creates the index `name` with the writer config of the manager and commits it empty, so it
+    /// can be read right away. Its writer stays open.
+    pub fn create(&self, name: &str) -> Result<Arc<Mutex<Writer<'static>>>, VectorError> {
+        let path = self.path(name)?;
+        if path.exists() {
+            return Err(VectorError::IndexExists(name.to_string()));
+        }
+
+        debug!("Creating index {}", name);
+        let mut writer = Writer::open_with_config(path, self.config.writer)?;
+        writer.commit()?;
+
+        let writer = Arc::new(Mutex::new(writer));
+        self.writers.lock().unwrap().insert(name.to_string(), writer.clone());
+        Ok(writer)
+    }
+
+    /// Returns the writer of the index `name`, opening it if needed. There is a single writer per
+    /// index, shared by all the callers.
+    pub fn writer(&self, name: &str) -> Result<Arc<Mutex<Writer<'static>>>, VectorError> {
+        let mut writers = self.writers.lock().unwrap();
+        if let Some(writer) = writers.get(name) {
+            return Ok(writer.clone());
+        }
+
+        let path = self.existing_path(name)?;
+        let writer = Arc::new(Mutex::new(Writer::open_with_config(path, self.config.writer)?));
+        writers.insert(name.to_string(), writer.clone());
+        Ok(writer)
+    }
+
+    /// Returns a reader of the index `name`, opening it if it isn't among the recently used ones.
+    ///
+    /// Evicted readers are closed once their last user drops them. Readers are opened outside of
+    /// the cache lock, so opening one doesn't hold up the users of the others.
+    pub fn reader(&self, name: &str) -> Result<Arc<Reader<'static>>, VectorError> {
+        if let Some(reader) = self.readers.lock().unwrap().get(name) {
+            return Ok(reader.clone());
+        }
+
+        // LMDB breaks when a process opens an environment twice at once, so the callers of an
+        // index wait for the one opening it and get its reader.
+        let opening = self.opening.lock().unwrap().entry(name.to_string()).or_default().clone();
+        let _opening = opening.lock().unwrap();
+        if let Some(reader) = self.readers.lock().unwrap().get(name) {
+            return Ok(reader.clone());
+        }
+
+        let path = self.existing_path(name)?;
+        let reader = Arc::new(Reader::open_with_config(path, self.config.reader)?);
+        if let Some((evicted, _)) = self.readers.lock().unwrap().push(name.to_string(), reader.clone()) {
+            trace!("Evicted reader of index {}", evicted);
+        }
+        Ok(reader)
+    }
+
+    /// Number of readers kept open by the manager.
+    pub fn open_readers(&self) -> usize {
+        self.readers.lock().unwrap().len()
+    }
+
+    /// Closes the reader and the writer of the index `name`. Fails if the writer is in use.
+    fn close(&self, name: &str) -> Result<(), VectorError> {
+        let mut writers = self.writers.lock().unwrap();
+        if let Some(writer) = writers.get(name) {
+            if Arc::strong_count(writer) > 1 {
+                return Err(VectorError::Locked(format!("the writer of index {} is in use", name)));
+            }
+        }
+        writers.remove(name);
+        self.readers.lock().unwrap().pop(name);
+        self.opening.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    /// Renames the index `from` to `to`. Readers handed out before keep serving the last
+    /// generation they loaded.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), VectorError> {
+        let from_path = self.existing_path(from)?;
+        let to_path = self.path(to)?;
+        if to_path.exists() {
+            return Err(VectorError::IndexExists(to.to_string()));
+        }
+
+        debug!("Renaming index {} to {}", from, to);
+        self.close(from)?;
+        std::fs::rename(from_path, to_path)?;
+        Ok(())
+    }
+
+    /// Deletes the index `name` and its files. Readers handed out before keep serving the last
+    /// generation they loaded.
+    pub fn drop_index(&self, name: &str) -> Result<(), VectorError> {
+        let path = self.existing_path(name)?;
+
+        debug!("Dropping index {}", name);
+        self.close(name)?;
+        std::fs::remove_dir_all(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use granne::angular::Vector;
+    use tempfile::TempDir;
+
+    use super::IndexManager;
+    use crate::vectors::{ManagerConfig, VectorError};
+
+    #[test]
+    fn create_list_and_drop() {
+        let root = TempDir::new().unwrap();
+        let manager = IndexManager::open(root.path()).unwrap();
+        assert!(manager.list().unwrap().is_empty());
+
+        manager.create("kb2").unwrap();
+        manager.create("kb1").unwrap();
+        assert!(matches!(manager.create("kb1"), Err(VectorError::IndexExists(_))));
+        assert!(manager.create("../kb").is_err());
+        assert_eq!(manager.list().unwrap(), ["kb1", "kb2"]);
+
+        // A new index can be read before anything is pushed.
+        assert!(manager.reader("kb1").unwrap().search(&Vector::from(vec![1.0, 0.0])).unwrap().is_empty());
+        assert!(matches!(manager.reader("kb3"), Err(VectorError::UnknownIndex(_))));
+
+        let writer = manager.writer("kb1").unwrap();
+        assert!(Arc::ptr_eq(&writer, &manager.writer("kb1").unwrap()));
+        assert!(matches!(manager.drop_index("kb1"), Err(VectorError::Locked(_))));
+        drop(writer);
+
+        manager.drop_index("kb1").unwrap();
+        assert_eq!(manager.list().unwrap(), ["kb2"]);
+        assert!(!manager.exists("kb1"));
+        assert!(matches!(manager.drop_index("kb1"), Err(VectorError::UnknownIndex(_))));
+    }
+
+    #[test]
+    fn rename() {
+        let root = TempDir::new().unwrap();
+        let manager = IndexManager::open(root.path()).unwrap();
+        {
+            let writer = manager.create("old").unwrap();
+            let mut writer = writer.lock().unwrap();
+            writer.push_vec(3, vec![1.0, 2.0]).unwrap();
+            writer.commit().unwrap();
+        }
+        manager.create("other").unwrap();
+        assert!(matches!(manager.rename("old", "other"), Err(VectorError::IndexExists(_))));
+
+        manager.rename("old", "new").unwrap();
+        assert_eq!(manager.list().unwrap(), ["new", "other"]);
+        assert!(manager.reader("old").is_err());
+        assert_eq!(manager.reader("new").unwrap().search_vec(vec![1.0, 2.0]).unwrap()[0].0, 3);
+
+        // The writer lock moved with the index.
+        let writer = manager.writer("new").unwrap();
+        writer.lock().unwrap().push_vec(4, vec![2.0, 1.0]).unwrap();
+    }
+
+    #[test]
+    fn reader_cache() {
+        let root = TempDir::new().unwrap();
+        let manager = IndexManager::open_with_config(root.path(), ManagerConfig::new().max_readers(2)).unwrap();
+        for name in ["a", "b", "c"] {
+            manager.create(name).unwrap();
+        }
+
+        let a = manager.reader("a").unwrap();
+        assert!(Arc::ptr_eq(&a, &manager.reader("a").unwrap()));
+        manager.reader("b").unwrap();
+        manager.reader("c").unwrap();
+        assert_eq!(manager.open_readers(), 2);
+
+        // "a" was evicted, but its users keep it.
+        assert!(!Arc::ptr_eq(&a, &manager.reader("a").unwrap()));
+        assert_eq!(manager.open_readers(), 2);
+    }
+
+    #[test]
+    fn concurrent_readers() {
+        let root = TempDir::new().unwrap();
+        let manager = IndexManager::open(root.path()).unwrap();
+        {
+            let writer = manager.create("kb").unwrap();
+            let mut writer = writer.lock().unwrap();
+            writer.push_vec(3, vec![1.0, 2.0]).unwrap();
+            writer.commit().unwrap();
+        }
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let reader = manager.reader("kb").unwrap();
+                    assert_eq!(reader.search_vec(vec![1.0, 2.0]).unwrap()[0].0, 3);
+                });
+            }
+        });
+        assert_eq!(manager.open_readers(), 1);
+    }
+}
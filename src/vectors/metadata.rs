@@ -0,0 +1,391 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use lmdb::Database;
+extern crate lmdb_zero as lmdb;
+
+use super::storage;
+
+pub(crate) const METADATA_TAGS_DB: &str = "metadata_tags";
+pub(crate) const METADATA_NUMBERS_DB: &str = "metadata_numbers";
+
+/// Metadata attached to a single vector: keyword tags and numeric fields.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub tags: Vec<(String, String)>,
+    pub numbers: Vec<(String, f64)>,
+}
+
+impl Metadata {
+    pub fn new() -> Self {
+        Metadata::default()
+    }
+
+    pub fn tag(mut self, field: &str, value: &str) -> Self {
+        self.tags.push((field.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn number(mut self, field: &str, value: f64) -> Self {
+        self.numbers.push((field.to_string(), value));
+        self
+    }
+}
+
+/// Filter expression evaluated against the metadata of the vectors.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// Vectors tagged with `field = value`.
+    Tag(String, String),
+    /// Vectors whose numeric `field` is in `[min, max]`.
+    Range(String, f64, f64),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+/// Vectors matching a filter. Negations keep the vectors they exclude instead of listing every
+/// other vector of the index.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Matches {
+    /// Only these vectors match.
+    Only(HashSet<usize>),
+    /// Every vector but these matches.
+    AllBut(HashSet<usize>),
+}
+
+impl Matches {
+    pub fn contains(&self, idx: usize) -> bool {
+        match self {
+            Matches::Only(idxs) => idxs.contains(&idx),
+            Matches::AllBut(idxs) => !idxs.contains(&idx),
+        }
+    }
+
+    /// Number of vectors matching among the first `universe`.
+    pub fn len(&self, universe: usize) -> usize {
+        match self {
+            Matches::Only(idxs) => idxs.iter().filter(|idx| **idx < universe).count(),
+            Matches::AllBut(idxs) => universe - idxs.iter().filter(|idx| **idx < universe).count(),
+        }
+    }
+
+    fn not(self) -> Matches {
+        match self {
+            Matches::Only(idxs) => Matches::AllBut(idxs),
+            Matches::AllBut(idxs) => Matches::Only(idxs),
+        }
+    }
+
+    fn and(self, other: Matches) -> Matches {
+        match (self, other) {
+            (Matches::Only(a), Matches::Only(b)) => Matches::Only(a.intersection(&b).copied().collect()),
+            (Matches::Only(a), Matches::AllBut(b)) | (Matches::AllBut(b), Matches::Only(a)) => {
+                Matches::Only(a.difference(&b).copied().collect())
+            }
+            (Matches::AllBut(a), Matches::AllBut(b)) => Matches::AllBut(a.union(&b).copied().collect()),
+        }
+    }
+
+    fn or(self, other: Matches) -> Matches {
+        match (self, other) {
+            (Matches::Only(a), Matches::Only(b)) => Matches::Only(a.union(&b).copied().collect()),
+            (Matches::Only(a), Matches::AllBut(b)) | (Matches::AllBut(b), Matches::Only(a)) => {
+                Matches::AllBut(b.difference(&a).copied().collect())
+            }
+            (Matches::AllBut(a), Matches::AllBut(b)) => Matches::AllBut(a.intersection(&b).copied().collect()),
+        }
+    }
+
+    /// Whether no vector can match, whatever the size of the index.
+    fn is_empty(&self) -> bool {
+        matches!(self, Matches::Only(idxs) if idxs.is_empty())
+    }
+}
+
+impl Filter {
+    pub fn tag(field: &str, value: &str) -> Self {
+        Filter::Tag(field.to_string(), value.to_string())
+    }
+
+    pub fn range(field: &str, min: f64, max: f64) -> Self {
+        Filter::Range(field.to_string(), min, max)
+    }
+}
+
+#[derive(Debug)]
+pub struct MetadataDB<'a> {
+    db_tags: Database<'a>,
+    db_numbers: Database<'a>,
+}
+
+impl<'a> MetadataDB<'a> {
+    pub fn open(env: &Arc<lmdb::Environment>) -> Result<Self, lmdb::Error> {
+        let flags = lmdb::db::DUPSORT | lmdb::db::DUPFIXED;
+        let db_tags = storage::open_db(env, METADATA_TAGS_DB, flags)?;
+        let db_numbers = storage::open_db(env, METADATA_NUMBERS_DB, flags)?;
+
+        Ok(MetadataDB { db_tags, db_numbers })
+    }
+
+    fn tag_key(field: &str, value: &str) -> Vec<u8> {
+        let mut key = Vec::with_capacity(field.len() + value.len() + 1);
+        key.extend_from_slice(field.as_bytes());
+        key.push(0);
+        key.extend_from_slice(value.as_bytes());
+        key
+    }
+
+    /// Encodes a number so the byte ordering of the keys matches the numeric ordering.
+    fn number_key(field: &str, value: f64) -> Vec<u8> {
+        // `-0.0` is stored as `0.0`, which it is equal to.
+        let value = if value == 0.0 { 0.0 } else { value };
+        let bits = value.to_bits();
+        let bits = if bits >> 63 == 1 { !bits } else { bits ^ (1 << 63) };
+
+        let mut key = Vec::with_capacity(field.len() + 9);
+        key.extend_from_slice(field.as_bytes());
+        key.push(0);
+        key.extend_from_slice(&bits.to_be_bytes());
+        key
+    }
+
+    fn split_key(key: &[u8]) -> (String, &[u8]) {
+        let end = key.iter().position(|byte| *byte == 0).unwrap_or(key.len());
+        let field = String::from_utf8_lossy(&key[..end]).into_owned();
+        (field, key.get(end + 1..).unwrap_or_default())
+    }
+
+    /// Inverse of `number_key`.
+    fn decode_number(bytes: &[u8]) -> f64 {
+        let bits = u64::from_be_bytes(bytes.try_into().unwrap());
+        let bits = if bits >> 63 == 1 { bits ^ (1 << 63) } else { !bits };
+        f64::from_bits(bits)
+    }
+
+    /// Returns the metadata of every vector that has some, rebuilt from the keys of the indexes.
+    pub fn all(&self) -> Result<HashMap<usize, Metadata>, lmdb::Error> {
+        let txn = storage::read_txn(self.db_tags.env())?;
+        let access = txn.access();
+        let mut all: HashMap<usize, Metadata> = HashMap::new();
+
+        let mut cursor = txn.cursor(&self.db_tags)?;
+        let mut current = cursor.first::<[u8], [u8]>(&access);
+        while let Ok((key, v)) = current {
+            let (field, value) = MetadataDB::split_key(key);
+            let value = String::from_utf8_lossy(value).into_owned();
+            all.entry(storage::decode_id(v)).or_default().tags.push((field, value));
+            current = cursor.next::<[u8], [u8]>(&access);
+        }
+
+        let mut cursor = txn.cursor(&self.db_numbers)?;
+        let mut current = cursor.first::<[u8], [u8]>(&access);
+        while let Ok((key, v)) = current {
+            let (field, value) = MetadataDB::split_key(key);
+            let value = MetadataDB::decode_number(value);
+            all.entry(storage::decode_id(v)).or_default().numbers.push((field, value));
+            current = cursor.next::<[u8], [u8]>(&access);
+        }
+        Ok(all)
+    }
+
+    /// Indexes the metadata of the vector `vec_id`.
+    pub fn insert(&self, vec_id: usize, metadata: &Metadata) -> Result<(), lmdb::Error> {
+        storage::write(self.db_tags.env(), |txn| self.insert_in(txn, vec_id, metadata))
+    }
+
+    /// Indexes the metadata of the vector `vec_id` as part of `txn`.
+    pub fn insert_in(&self, txn: &lmdb::WriteTransaction, vec_id: usize, metadata: &Metadata) -> Result<(), lmdb::Error> {
+        trace!("Add metadata for vec_id {}: {:?}", vec_id, metadata);
+        let flags = lmdb::put::Flags::empty();
+        let val = storage::encode_id(vec_id);
+        let mut access = txn.access();
+
+        for (field, value) in &metadata.tags {
+            let key = MetadataDB::tag_key(field, value);
+            access.put::<[u8], [u8]>(&self.db_tags, &key, &val, flags)?;
+        }
+        for (field, value) in &metadata.numbers {
+            let key = MetadataDB::number_key(field, *value);
+            access.put::<[u8], [u8]>(&self.db_numbers, &key, &val, flags)?;
+        }
+        Ok(())
+    }
+
+    fn get_tag(&self, field: &str, value: &str) -> Result<HashSet<usize>, lmdb::Error> {
+        let key = MetadataDB::tag_key(field, value);
+
+        let env = self.db_tags.env();
+        let txn = storage::read_txn(env)?;
+        let access = txn.access();
+        let mut cursor = txn.cursor(&self.db_tags)?;
+
+        let mut results = HashSet::new();
+        if let Ok(v) = cursor.seek_k::<[u8], [u8]>(&access, &key) {
+            results.insert(storage::decode_id(v));
+            while let Ok((_, v)) = cursor.next_dup::<[u8], [u8]>(&access) {
+                results.insert(storage::decode_id(v));
+            }
+        }
+        Ok(results)
+    }
+
+    fn get_range(&self, field: &str, min: f64, max: f64) -> Result<HashSet<usize>, lmdb::Error> {
+        let start = MetadataDB::number_key(field, min);
+        let end = MetadataDB::number_key(field, max);
+
+        let env = self.db_numbers.env();
+        let txn = storage::read_txn(env)?;
+        let access = txn.access();
+        let mut cursor = txn.cursor(&self.db_numbers)?;
+
+        let mut results = HashSet::new();
+        let mut current = cursor.seek_range_k::<[u8], [u8]>(&access, &start);
+        while let Ok((key, v)) = current {
+            if key > &end[..] {
+                break;
+            }
+            results.insert(storage::decode_id(v));
+            current = cursor.next::<[u8], [u8]>(&access);
+        }
+        Ok(results)
+    }
+
+    /// Returns the vectors matching `filter`. Callers only consider the vectors of their index,
+    /// so vectors beyond it, pushed after the last commit, are meaningless.
+    pub fn matching(&self, filter: &Filter) -> Result<Matches, lmdb::Error> {
+        let matches = match filter {
+            Filter::Tag(field, value) => Matches::Only(self.get_tag(field, value)?),
+            Filter::Range(field, min, max) => Matches::Only(self.get_range(field, *min, *max)?),
+            Filter::And(filters) => {
+                let mut matches = Matches::AllBut(HashSet::new());
+                for filter in filters {
+                    if matches.is_empty() {
+                        break;
+                    }
+                    matches = matches.and(self.matching(filter)?);
+                }
+                matches
+            }
+            Filter::Or(filters) => {
+                let mut matches = Matches::Only(HashSet::new());
+                for filter in filters {
+                    matches = matches.or(self.matching(filter)?);
+                }
+                matches
+            }
+            Filter::Not(filter) => self.matching(filter)?.not(),
+        };
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use log::LevelFilter;
+    use tempfile::tempdir;
+
+    use super::{Filter, Matches, Metadata, MetadataDB};
+    use crate::vectors::storage;
+
+    fn init() {
+        let _ = env_logger::builder()
+            .filter_level(LevelFilter::Trace)
+            .is_test(true)
+            .try_init();
+    }
+
+    fn set(idxs: &[usize]) -> HashSet<usize> {
+        idxs.iter().copied().collect()
+    }
+
+    fn only(idxs: &[usize]) -> Matches {
+        Matches::Only(set(idxs))
+    }
+
+    #[test]
+    fn tags() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let db = MetadataDB::open(&env).unwrap();
+
+        db.insert(0, &Metadata::new().tag("lang", "en").tag("user", "a")).unwrap();
+        db.insert(1, &Metadata::new().tag("lang", "es").tag("user", "a")).unwrap();
+        db.insert(2, &Metadata::new().tag("lang", "en").tag("user", "b")).unwrap();
+
+        assert_eq!(db.matching(&Filter::tag("lang", "en")).unwrap(), only(&[0, 2]));
+        assert_eq!(db.matching(&Filter::tag("lang", "fr")).unwrap(), only(&[]));
+
+        let filter = Filter::And(vec![Filter::tag("lang", "en"), Filter::tag("user", "a")]);
+        assert_eq!(db.matching(&filter).unwrap(), only(&[0]));
+
+        let filter = Filter::Or(vec![Filter::tag("lang", "es"), Filter::tag("user", "b")]);
+        assert_eq!(db.matching(&filter).unwrap(), only(&[1, 2]));
+
+        // Negations keep the vectors they exclude, whatever the size of the index.
+        let filter = Filter::Not(Box::new(Filter::tag("user", "a")));
+        let matches = db.matching(&filter).unwrap();
+        assert_eq!(matches, Matches::AllBut(set(&[0, 1])));
+        assert_eq!(matches.len(3), 1);
+        assert!(matches.contains(2) && !matches.contains(1));
+        assert_eq!(db.matching(&Filter::And(vec![])).unwrap(), Matches::AllBut(set(&[])));
+
+        let filter = Filter::And(vec![Filter::tag("lang", "en"), Filter::Not(Box::new(Filter::tag("user", "a")))]);
+        assert_eq!(db.matching(&filter).unwrap(), only(&[2]));
+        let filter = Filter::Or(vec![Filter::tag("lang", "es"), Filter::Not(Box::new(Filter::tag("lang", "en")))]);
+        assert_eq!(db.matching(&filter).unwrap(), Matches::AllBut(set(&[0, 2])));
+
+        // Vectors outside of the universe are not counted.
+        assert_eq!(db.matching(&Filter::tag("lang", "en")).unwrap().len(1), 1);
+    }
+
+    #[test]
+    fn ranges() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let db = MetadataDB::open(&env).unwrap();
+
+        db.insert(0, &Metadata::new().number("date", -10.5)).unwrap();
+        db.insert(1, &Metadata::new().number("date", 0.0)).unwrap();
+        db.insert(2, &Metadata::new().number("date", 3.0)).unwrap();
+        db.insert(3, &Metadata::new().number("date", 1000.0)).unwrap();
+        db.insert(4, &Metadata::new().number("size", 2.0)).unwrap();
+
+        assert_eq!(db.matching(&Filter::range("date", -20.0, 3.0)).unwrap(), only(&[0, 1, 2]));
+        assert_eq!(db.matching(&Filter::range("date", 0.0, 0.0)).unwrap(), only(&[1]));
+        assert_eq!(db.matching(&Filter::range("date", 1.0, 2000.0)).unwrap(), only(&[2, 3]));
+        assert_eq!(db.matching(&Filter::range("size", 0.0, 10.0)).unwrap(), only(&[4]));
+
+        // Zero has a single key, whatever its sign.
+        db.insert(5, &Metadata::new().number("date", -0.0)).unwrap();
+        assert_eq!(db.matching(&Filter::range("date", 0.0, 0.0)).unwrap(), only(&[1, 5]));
+        assert_eq!(db.matching(&Filter::range("date", -0.0, -0.0)).unwrap(), only(&[1, 5]));
+    }
+
+    #[test]
+    fn all() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let db = MetadataDB::open(&env).unwrap();
+
+        let first = Metadata::new().tag("lang", "en").number("date", -10.5);
+        let second = Metadata::new().tag("lang", "es").tag("user", "a").number("date", 3.0);
+        db.insert(0, &first).unwrap();
+        db.insert(7, &second).unwrap();
+
+        let all = db.all().unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[&0], first);
+        assert_eq!(all[&7], second);
+    }
+}
@@ -0,0 +1,342 @@
+//! Upgrades the LMDB databases of an index to the current on-disk format.
+//!
+//! Format history:
+//! - Legacy: one LMDB environment per database (`index_map`, `index_mapinverted`, `deleted.dat`,
+//!   `metadata` and `metadatanumbers` directories), ids encoded with bincode.
+//! - `0`: a single environment with named databases, ids still encoded with bincode.
+//! - `1`: ids encoded as fixed-width big-endian integers, see `storage::encode_id`.
+
+use std::{path::PathBuf, sync::Arc};
+
+use lmdb::Database;
+extern crate lmdb_zero as lmdb;
+
+use super::{
+    deleted_db::DELETED_DB,
+    directory::Location,
+    index_map::{INDEX_MAP_DB, INDEX_MAP_INVERTED_DB},
+    metadata::{METADATA_NUMBERS_DB, METADATA_TAGS_DB},
+    storage, VectorError,
+};
+
+const META_DB: &str = "meta";
+const FORMAT_VERSION_KEY: &str = "format_version";
+
+const BINCODE_FORMAT: u32 = 0;
+pub const CURRENT_FORMAT: u32 = 1;
+
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// A database of the index and how its entries are encoded.
+struct Table {
+    name: &'static str,
+    legacy_dir: &'static str,
+    flags: lmdb::db::Flags,
+    id_keys: bool,
+    id_values: bool,
+}
+
+fn tables() -> [Table; 5] {
+    let dup = lmdb::db::DUPSORT | lmdb::db::DUPFIXED;
+    let unique = lmdb::db::Flags::empty();
+    [
+        Table { name: INDEX_MAP_DB, legacy_dir: "index_map", flags: dup, id_keys: true, id_values: true },
+        Table { name: INDEX_MAP_INVERTED_DB, legacy_dir: "index_mapinverted", flags: unique, id_keys: true, id_values: true },
+        Table { name: DELETED_DB, legacy_dir: "deleted.dat", flags: unique, id_keys: true, id_values: false },
+        Table { name: METADATA_TAGS_DB, legacy_dir: "metadata", flags: dup, id_keys: false, id_values: true },
+        Table { name: METADATA_NUMBERS_DB, legacy_dir: "metadatanumbers", flags: dup, id_keys: false, id_values: true },
+    ]
+}
+
+/// Brings the databases in `env` to `CURRENT_FORMAT`.
+///
+/// Has to run before the databases are opened by the `IndexMap`, `DeletedDB*` or `MetadataDB`,
+/// and only from the `Writer`, see `check` for the `Reader`. Each step is applied in a single write transaction.
+/// Legacy directories that can't be removed once imported fail the migration, which removes them
+/// when it runs again: readers can't open the index while they are there.
+pub fn migrate(env: &Arc<lmdb::Environment>, location: &Location) -> Result<(), VectorError> {
+    let meta = storage::open_db(env, META_DB, lmdb::db::Flags::empty())?;
+
+    let legacy_dirs: Vec<PathBuf> = tables()
+        .iter()
+        .map(|table| location.path().join(table.legacy_dir))
+        .filter(|path| path.exists())
+        .collect();
+
+    match format_version(&meta)? {
+        Some(CURRENT_FORMAT) => (),
+        Some(BINCODE_FORMAT) => reencode(env, &meta)?,
+        Some(version) => {
+            error!("Unknown format version {} of the index databases", version);
+            return Err(lmdb::Error::Code(lmdb::error::VERSION_MISMATCH).into());
+        }
+        None if !legacy_dirs.is_empty() => import_legacy(env, &meta, location)?,
+        None if is_empty(env)? => set_format_version(env, &meta)?,
+        None => reencode(env, &meta)?,
+    }
+
+    for dir in legacy_dirs {
+        info!("Removing migrated legacy database {:?}", dir);
+        if let Err(e) = std::fs::remove_dir_all(&dir) {
+            error!("Error removing migrated legacy database {:?}: {}", dir, e);
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the databases in `env` are in `CURRENT_FORMAT`, for the `Reader`, which can't
+/// migrate them while a `Writer` may be doing it: an index written in an older format has to be
+/// opened by a `Writer` first.
+pub fn check(env: &Arc<lmdb::Environment>, location: &Location) -> Result<(), lmdb::Error> {
+    let meta = storage::open_db(env, META_DB, lmdb::db::Flags::empty())?;
+    let legacy = tables().iter().any(|table| location.path().join(table.legacy_dir).exists());
+
+    match format_version(&meta)? {
+        Some(CURRENT_FORMAT) if !legacy => Ok(()),
+        None if !legacy && is_empty(env)? => Ok(()),
+        version => {
+            error!(
+                "Index databases in format {:?} have to be migrated to format {} by a Writer",
+                version, CURRENT_FORMAT
+            );
+            Err(lmdb::Error::Code(lmdb::error::VERSION_MISMATCH))
+        }
+    }
+}
+
+fn format_version(meta: &Database) -> Result<Option<u32>, lmdb::Error> {
+    let txn = storage::read_txn(meta.env())?;
+    let access = txn.access();
+    match access.get::<[u8], [u8]>(meta, FORMAT_VERSION_KEY.as_bytes()) {
+        Ok(v) => Ok(Some(bincode::deserialize(v).unwrap())),
+        Err(lmdb::Error::Code(lmdb::error::NOTFOUND)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn put_format_version(txn: &lmdb::WriteTransaction, meta: &Database) -> Result<(), lmdb::Error> {
+    let version = bincode::serialize(&CURRENT_FORMAT).unwrap();
+    let mut access = txn.access();
+    access.put::<[u8], [u8]>(meta, FORMAT_VERSION_KEY.as_bytes(), &version, lmdb::put::Flags::empty())
+}
+
+fn set_format_version(env: &lmdb::Environment, meta: &Database) -> Result<(), lmdb::Error> {
+    storage::write(env, |txn| put_format_version(txn, meta))
+}
+
+fn is_empty(env: &Arc<lmdb::Environment>) -> Result<bool, lmdb::Error> {
+    for table in tables().iter() {
+        let db = storage::open_db(env, table.name, table.flags)?;
+        let txn = storage::read_txn(env)?;
+        let access = txn.access();
+        let mut cursor = txn.cursor(&db)?;
+        if cursor.first::<[u8], [u8]>(&access).is_ok() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn read_all(txn: &lmdb::ConstTransaction, db: &Database) -> Result<Entries, lmdb::Error> {
+    let access = txn.access();
+    let mut cursor = txn.cursor(db)?;
+
+    let mut entries = Vec::new();
+    let mut current = cursor.first::<[u8], [u8]>(&access);
+    while let Ok((key, val)) = current {
+        entries.push((key.to_vec(), val.to_vec()));
+        current = cursor.next::<[u8], [u8]>(&access);
+    }
+    Ok(entries)
+}
+
+fn encode(bincode_id: &[u8]) -> Vec<u8> {
+    let id: usize = bincode::deserialize(bincode_id).unwrap();
+    storage::encode_id(id).to_vec()
+}
+
+fn put_reencoded(
+    txn: &lmdb::WriteTransaction,
+    table: &Table,
+    db: &Database,
+    entries: &Entries,
+) -> Result<(), lmdb::Error> {
+    let mut access = txn.access();
+    for (key, val) in entries {
+        let key = if table.id_keys { encode(key) } else { key.clone() };
+        let val = if table.id_values { encode(val) } else { Vec::new() };
+        access.put::<[u8], [u8]>(db, &key, &val, lmdb::put::Flags::empty())?;
+    }
+    Ok(())
+}
+
+/// Rewrites the bincode entries of the named databases with the current encoding.
+fn reencode(env: &Arc<lmdb::Environment>, meta: &Database) -> Result<(), lmdb::Error> {
+    info!("Migrating index databases to format {}", CURRENT_FORMAT);
+    let tables = tables();
+    let dbs = tables
+        .iter()
+        .map(|table| storage::open_db(env, table.name, table.flags))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    storage::write(env, |txn| {
+        for (table, db) in tables.iter().zip(&dbs) {
+            let entries = read_all(txn, db)?;
+            txn.access().clear_db(db)?;
+            put_reencoded(txn, table, db, &entries)?;
+        }
+        put_format_version(txn, meta)
+    })
+}
+
+/// Imports the databases of an index from the time each of them had its own environment.
+fn import_legacy(env: &Arc<lmdb::Environment>, meta: &Database, location: &Location) -> Result<(), lmdb::Error> {
+    info!("Migrating legacy index databases to format {}", CURRENT_FORMAT);
+    let tables = tables();
+
+    let mut legacy_entries = Vec::new();
+    for table in tables.iter() {
+        let path = location.path().join(table.legacy_dir);
+        if !path.exists() {
+            legacy_entries.push(Vec::new());
+            continue;
+        }
+
+        let legacy_env = unsafe {
+            lmdb::EnvBuilder::new()?.open(path.to_str().unwrap(), lmdb::open::Flags::empty(), 0o666)?
+        };
+        let legacy_flags = table.flags & lmdb::db::DUPSORT;
+        let legacy_db = lmdb::Database::open(&legacy_env, None, &lmdb::DatabaseOptions::new(legacy_flags))?;
+        let txn = lmdb::ReadTransaction::new(&legacy_env)?;
+        legacy_entries.push(read_all(&txn, &legacy_db)?);
+    }
+
+    let dbs = tables
+        .iter()
+        .map(|table| storage::open_db(env, table.name, table.flags))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    storage::write(env, |txn| {
+        for ((table, db), entries) in tables.iter().zip(&dbs).zip(&legacy_entries) {
+            put_reencoded(txn, table, db, entries)?;
+        }
+        put_format_version(txn, meta)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use log::LevelFilter;
+    use tempfile::tempdir;
+
+    use super::{check, lmdb, migrate};
+    use crate::vectors::{directory::Location, storage, DeletedDBReader, IndexMap};
+
+    fn init() {
+        let _ = env_logger::builder()
+            .filter_level(LevelFilter::Trace)
+            .is_test(true)
+            .try_init();
+    }
+
+    fn write_legacy(path: &Path, flags: lmdb::db::Flags, entries: &[(usize, usize)]) {
+        std::fs::create_dir_all(path).unwrap();
+        let env = unsafe {
+            lmdb::EnvBuilder::new()
+                .unwrap()
+                .open(path.to_str().unwrap(), lmdb::open::Flags::empty(), 0o666)
+                .unwrap()
+        };
+        let db = lmdb::Database::open(&env, None, &lmdb::DatabaseOptions::new(flags)).unwrap();
+        let txn = lmdb::WriteTransaction::new(&env).unwrap();
+        {
+            let mut access = txn.access();
+            for (key, val) in entries {
+                let key = bincode::serialize(key).unwrap();
+                let val = bincode::serialize(val).unwrap();
+                access.put::<[u8], [u8]>(&db, &key, &val, lmdb::put::Flags::empty()).unwrap();
+            }
+        }
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn legacy_environments() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let location = Location(tempdir.path().to_path_buf());
+
+        write_legacy(&location.path().join("index_map"), lmdb::db::DUPSORT, &[(1, 0), (1, 1), (300, 256)]);
+        write_legacy(&location.path().join("index_mapinverted"), lmdb::db::Flags::empty(), &[(0, 1), (1, 1), (256, 300)]);
+        write_legacy(&location.path().join("deleted.dat"), lmdb::db::Flags::empty(), &[(256, 1)]);
+
+        let env = storage::open_env(&location.lmdb_path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        assert!(check(&env, &location).is_err());
+        migrate(&env, &location).unwrap();
+        check(&env, &location).unwrap();
+        assert!(!location.path().join("index_map").exists());
+        assert!(!location.path().join("deleted.dat").exists());
+
+        let map = IndexMap::open(&env).unwrap();
+        assert_eq!(map.get_vec_ids(1).unwrap(), vec![0, 1]);
+        assert_eq!(map.get_doc_id(256).unwrap(), 300);
+        assert_eq!(map.vec_ids_in_range(0..300).unwrap(), vec![(0, 1), (1, 1), (256, 300)]);
+
+        let reader_env = storage::open_env(&location.lmdb_path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let deleted = DeletedDBReader::open(&reader_env).unwrap();
+        assert_eq!(deleted.deleted_in_range(0..1000).unwrap(), vec![256]);
+    }
+
+    #[test]
+    fn new_index_is_current() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let location = Location(tempdir.path().to_path_buf());
+
+        let env = storage::open_env(&location.lmdb_path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        check(&env, &location).unwrap();
+        migrate(&env, &location).unwrap();
+        // Migrating twice is a no-op.
+        migrate(&env, &location).unwrap();
+
+        let map = IndexMap::open(&env).unwrap();
+        map.insert(1, 2).unwrap();
+        assert_eq!(map.get_vec_ids(1).unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn bincode_named_databases() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let location = Location(tempdir.path().to_path_buf());
+        let env = storage::open_env(&location.lmdb_path(), storage::DEFAULT_MAP_SIZE).unwrap();
+
+        {
+            let db = storage::open_db(&env, "index_map_inverted", lmdb::db::Flags::empty()).unwrap();
+            storage::write(&env, |txn| {
+                let mut access = txn.access();
+                for (key, val) in [(255usize, 7usize), (256, 8)] {
+                    let key = bincode::serialize(&key).unwrap();
+                    let val = bincode::serialize(&val).unwrap();
+                    access.put::<[u8], [u8]>(&db, &key, &val, lmdb::put::Flags::empty())?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        }
+
+        assert!(check(&env, &location).is_err());
+        migrate(&env, &location).unwrap();
+        check(&env, &location).unwrap();
+
+        let map = IndexMap::open(&env).unwrap();
+        assert_eq!(map.get_doc_id(255).unwrap(), 7);
+        assert_eq!(map.vec_ids_in_range(0..1000).unwrap(), vec![(255, 7), (256, 8)]);
+    }
+}
@@ -1,35 +1,91 @@
+pub mod asynchronous;
+pub mod autocommit;
+pub mod binary;
+pub mod bulk;
+pub mod commit;
+pub mod config;
 pub mod deleted_db;
 pub mod directory;
+pub mod elements;
+pub mod error;
+pub mod expiry;
+pub mod half_precision;
 pub mod index_map;
+pub mod ingest;
 pub mod lock;
+pub mod manager;
+pub mod metadata;
+mod migration;
+pub mod namespaces;
+pub mod pq;
 pub mod reader;
+pub mod schema;
+pub mod sharded;
+pub mod spaces;
+pub mod storage;
+pub mod tombstones;
 pub mod writer;
 
+pub use asynchronous::{AsyncReader, AsyncWriter};
+pub use autocommit::{AutoCommit, PendingWrites};
+pub use binary::*;
+pub use bulk::BulkLoader;
+pub use commit::CommitHandle;
+pub use config::*;
 pub use deleted_db::*;
+pub use elements::*;
+pub use error::*;
+pub use expiry::ExpiryDB;
+pub use half_precision::*;
 pub use index_map::*;
+pub use ingest::*;
 pub use lock::*;
+pub use manager::IndexManager;
+pub use metadata::*;
+pub use namespaces::{NamespaceRegistry, NamespaceStats};
+pub use pq::*;
 pub use reader::*;
+pub use schema::*;
+pub use sharded::{ShardedReader, ShardedWriter};
+pub use spaces::{SpaceQuery, SpaceRegistry};
+pub use tombstones::*;
 pub use writer::*;
 
 const COMMIT_LOCK_PATH: &str = "COMMIT_LOCK";
 const WRITER_LOCK_PATH: &str = "WRITER_LOCK";
 const ELEMENTS_PATH: &str = "elements.dat";
+const FULL_PRECISION_PATH: &str = "elements_full.dat";
 const INDEX_PATH: &str = "index.dat";
-const DIRTY_PATH: &str = "DIRTY_BIT";
-const DELETED_PATH: &str = "deleted.dat";
-const INDEX_MAP_PATH: &str = "index_map";
+const LMDB_PATH: &str = "lmdb";
+const TOMBSTONES_PATH: &str = "tombstones.bitmap";
+const SCHEMA_PATH: &str = "schema.json";
+const PQ_PATH: &str = "pq.bin";
+const BINARY_PATH: &str = "binary.dat";
+const BINARY_INDEX_PATH: &str = "binary_index.dat";
+const EMBEDDINGS_PATH: &str = "embeddings.dat";
+const SPACES_PATH: &str = "spaces";
+const SPACE_REGISTRY_PATH: &str = "spaces.json";
+const NAMESPACES_PATH: &str = "namespaces";
+const NAMESPACE_REGISTRY_PATH: &str = "namespaces.json";
+const GENERATION_PATH: &str = "generation";
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{
+        io::Write,
+        time::{Duration, SystemTime},
+    };
 
     use granne::angular::Vector;
     use log::LevelFilter;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
     use tempfile::TempDir;
 
     use crate::vectors::Writer;
 
-    use super::Reader;
+    use super::{
+        namespaces, BinaryPrefilter, Filter, IngestConfig, InvalidPolicy, InvalidVector, Metadata, Metric, NamespaceStats, PqConfig, Reader, ReaderConfig, Schema, SpaceQuery, Storage, VectorError, WriterConfig,
+    };
 
     fn init() {
         let _ = env_logger::builder()
@@ -65,17 +121,17 @@ mod tests {
         writer.push(1, &create_vector(3, 2.0)).unwrap();
         writer.push(1, &create_vector(3, 3.0)).unwrap();
 
-        writer.commit();
+        writer.commit().unwrap();
 
         writer.push(2, &create_vector(3, 4.0)).unwrap();
         writer.push(2, &create_vector(3, 5.0)).unwrap();
 
-        writer.commit();
+        writer.commit().unwrap();
 
         writer.push(3, &create_vector(3, 6.0)).unwrap();
         writer.push(3, &create_vector(3, 7.0)).unwrap();
 
-        writer.commit();
+        writer.commit().unwrap();
     }
 
     #[test]
@@ -89,10 +145,10 @@ mod tests {
         writer.push(1, &create_vector(3, 2.0)).unwrap();
         writer.push(1, &create_vector(3, 3.0)).unwrap();
 
-        writer.commit();
+        writer.commit().unwrap();
 
         let reader = Reader::open(tmpdir.path()).unwrap();
-        let res = reader.search(&create_vector(3, 1.0));
+        let res = reader.search(&create_vector(3, 1.0)).unwrap();
 
         let doc_ids: Vec<_> = res.iter().map(|(doc_id, _score)| *doc_id).collect();
         assert_eq!(doc_ids, vec![1, 1, 1]);
@@ -103,9 +159,9 @@ mod tests {
         writer.push(2, &create_vector(3, 5.0)).unwrap();
         writer.push(2, &create_vector(3, 6.0)).unwrap();
 
-        writer.commit();
+        writer.commit().unwrap();
 
-        let res = reader.search(&create_vector(3, 3.0));
+        let res = reader.search(&create_vector(3, 3.0)).unwrap();
 
         let doc_ids: Vec<_> = res.iter().map(|(doc_id, _score)| *doc_id).collect();
         assert_eq!(doc_ids, vec![1, 1, 1, 2, 2, 2]);
@@ -124,7 +180,7 @@ mod tests {
             let mut writer = Writer::open(tmp1).unwrap();
             for i in 0..500 {
                 writer.push(1, &create_vector(3, i as f32)).unwrap();
-                writer.commit();
+                writer.commit().unwrap();
             }
         });
 
@@ -132,7 +188,7 @@ mod tests {
             std::thread::sleep(Duration::from_millis(100));
             let reader = Reader::open(tmp2).unwrap();
             for _ in 0..500 {
-                reader.search(&create_vector(3, 3.0));
+                reader.search(&create_vector(3, 3.0)).unwrap();
             }
         });
 
@@ -150,19 +206,911 @@ mod tests {
         for i in 1..100 {
             writer.push(i, &create_vector(700, i as f32)).unwrap();
         }
-        writer.commit();
+        writer.commit().unwrap();
 
-        let idxs: Vec<_> = (100..10_000).into_iter().collect();
+        let idxs: Vec<_> = (100..10_000).collect();
         let vectors: Vec<_> = (100..10_000)
-            .into_iter()
             .map(|i| create_vector(700, i as f32))
             .collect();
 
         writer.push_batch(&idxs, &vectors).unwrap();
-        writer.commit();
+        writer.commit().unwrap();
 
         let reader = Reader::open(tmpdir.path()).unwrap();
-        let res = reader.search(&create_vector(3, 700.0));
+        let res = reader.search(&create_vector(700, 700.0)).unwrap();
         println!("Res: {:?}", res);
     }
+
+    #[test]
+    fn filtered_search() {
+        init();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+
+        for i in 0..200 {
+            let lang = if i % 2 == 0 { "en" } else { "es" };
+            let metadata = Metadata::new().tag("lang", lang).number("date", i as f64);
+            writer
+                .push_with_metadata(i, &create_vector(3, i as f32), &metadata)
+                .unwrap();
+        }
+        writer.commit().unwrap();
+
+        let reader = Reader::open(tmpdir.path()).unwrap();
+
+        // Loose filter, over-fetching from the graph.
+        let res = reader.search_filtered(&create_vector(3, 1.0), &Filter::tag("lang", "en")).unwrap();
+        assert!(!res.is_empty());
+        assert!(res.iter().all(|(doc_id, _score)| doc_id % 2 == 0));
+
+        // Selective filter, brute force over the matching vectors.
+        let filter = Filter::And(vec![Filter::tag("lang", "es"), Filter::range("date", 10.0, 15.0)]);
+        let res = reader.search_filtered(&create_vector(3, 1.0), &filter).unwrap();
+        let mut doc_ids: Vec<_> = res.iter().map(|(doc_id, _score)| *doc_id).collect();
+        doc_ids.sort_unstable();
+        assert_eq!(doc_ids, vec![11, 13, 15]);
+
+        writer.delete(13).unwrap();
+        writer.commit().unwrap();
+        let res = reader.search_filtered(&create_vector(3, 1.0), &filter).unwrap();
+        let mut doc_ids: Vec<_> = res.iter().map(|(doc_id, _score)| *doc_id).collect();
+        doc_ids.sort_unstable();
+        assert_eq!(doc_ids, vec![11, 15]);
+    }
+
+    #[test]
+    fn tombstones_per_generation() {
+        init();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+
+        for i in 0..20 {
+            writer.push(i, &create_vector(3, i as f32)).unwrap();
+        }
+        writer.commit().unwrap();
+
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        let query = create_vector(3, 1.0);
+        let doc_ids = |res: Vec<(usize, f32)>| res.into_iter().map(|(doc_id, _score)| doc_id).collect::<Vec<_>>();
+
+        // The deletion is not visible until it is committed.
+        writer.delete(5).unwrap();
+        assert!(doc_ids(reader.search(&query).unwrap()).contains(&5));
+
+        writer.commit().unwrap();
+        assert!(!doc_ids(reader.search(&query).unwrap()).contains(&5));
+
+        // A new writer picks up the tombstones from the previous one.
+        drop(writer);
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        writer.delete(6).unwrap();
+        writer.commit().unwrap();
+
+        let res = doc_ids(reader.search(&query).unwrap());
+        assert!(!res.contains(&5));
+        assert!(!res.contains(&6));
+
+        // Indexes committed before the tombstones had a file keep them in the deleted database.
+        drop(writer);
+        std::fs::remove_file(tmpdir.path().join("tombstones.bitmap")).unwrap();
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        let res = doc_ids(reader.search(&query).unwrap());
+        assert!(!res.contains(&5));
+        assert!(!res.contains(&6));
+    }
+
+    #[test]
+    fn undelete_and_expiry() {
+        init();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+
+        for i in 0..20 {
+            writer.push(i, &create_vector(3, i as f32)).unwrap();
+        }
+        writer.delete(3).unwrap();
+        writer.set_expiry(4, SystemTime::now() - Duration::from_secs(1)).unwrap();
+        // The expiry is the document's, so it covers the vectors pushed after it was set.
+        writer.push(4, &create_vector(3, 1.0)).unwrap();
+        writer.set_expiry(5, SystemTime::now() + Duration::from_secs(3600)).unwrap();
+        writer.commit().unwrap();
+
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        let query = create_vector(3, 1.0);
+        let doc_ids = |res: Vec<(usize, f32)>| res.into_iter().map(|(doc_id, _score)| doc_id).collect::<Vec<_>>();
+
+        let res = doc_ids(reader.search(&query).unwrap());
+        assert!(!res.contains(&3));
+        assert!(!res.contains(&4));
+        assert!(res.contains(&5));
+
+        // Expired vectors were tombstoned by the commit, so both can be restored.
+        writer.undelete(3).unwrap();
+        writer.undelete(4).unwrap();
+        writer.set_expiry(5, SystemTime::now() - Duration::from_secs(1)).unwrap();
+        writer.commit().unwrap();
+
+        let res = doc_ids(reader.search(&query).unwrap());
+        assert!(res.contains(&3));
+        assert!(res.contains(&4));
+        assert!(!res.contains(&5));
+
+        // Readers see expiry times set in the past and cleared before a commit.
+        writer.set_expiry(6, SystemTime::now() - Duration::from_secs(10)).unwrap();
+        assert!(!doc_ids(reader.search(&query).unwrap()).contains(&6));
+        writer.clear_expiry(6).unwrap();
+        assert!(doc_ids(reader.search(&query).unwrap()).contains(&6));
+
+        // Undeleting a document that expired after the last commit lifts its expiry.
+        writer.set_expiry(7, SystemTime::now() - Duration::from_secs(1)).unwrap();
+        assert!(!doc_ids(reader.search(&query).unwrap()).contains(&7));
+        writer.undelete(7).unwrap();
+        writer.commit().unwrap();
+        assert!(doc_ids(reader.search(&query).unwrap()).contains(&7));
+    }
+
+    #[test]
+    fn dimension_is_enforced() {
+        init();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        assert_eq!(writer.schema(), None);
+
+        writer.push(1, &create_vector(3, 1.0)).unwrap();
+        assert_eq!(writer.schema(), Some(Schema::new(3)));
+
+        let err = writer.push(2, &create_vector(4, 1.0)).unwrap_err();
+        assert!(matches!(err, VectorError::DimensionMismatch { expected: 3, found: 4 }));
+
+        // Only the mismatched vectors of a batch are rejected.
+        let vectors = vec![create_vector(3, 2.0), create_vector(2, 3.0)];
+        let report = writer.push_batch(&[2, 3], &vectors).unwrap();
+        assert_eq!(report.pushed, 1);
+        assert!(matches!(report.rejected[..], [(1, VectorError::DimensionMismatch { expected: 3, found: 2 })]));
+        writer.commit().unwrap();
+
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        assert_eq!(reader.search(&create_vector(3, 1.0)).unwrap().len(), 2);
+        let err = reader.search(&create_vector(700, 1.0)).unwrap_err();
+        assert!(matches!(err, VectorError::DimensionMismatch { expected: 3, found: 700 }));
+
+        // The schema is recorded with the index.
+        drop(writer);
+        assert!(Writer::open_with_config(tmpdir.path(), WriterConfig::new().dimension(5)).is_err());
+        let mut writer = Writer::open_with_config(tmpdir.path(), WriterConfig::new().dimension(3)).unwrap();
+        assert!(writer.push(4, &create_vector(5, 1.0)).is_err());
+    }
+
+    #[test]
+    fn ingestion_pipeline() {
+        init();
+
+        let tmpdir = TempDir::new().unwrap();
+        let ingest = IngestConfig::new().invalid(InvalidPolicy::Repair).normalize(true).truncate(2);
+        let mut writer = Writer::open_with_config(tmpdir.path(), WriterConfig::new().ingest(ingest)).unwrap();
+
+        writer.push(1, &Vector(vec![3.0, 4.0, 12.0].into())).unwrap();
+        writer.push(2, &Vector(vec![f32::NAN, 2.0, 1.0].into())).unwrap();
+        assert_eq!(writer.schema(), Some(Schema::new(2)));
+
+        let err = writer.push(3, &Vector(vec![0.0, 0.0, 1.0].into())).unwrap_err();
+        assert!(matches!(err, VectorError::InvalidVector(InvalidVector::Zero)));
+
+        let vectors = vec![
+            Vector(vec![1.0, 0.0, 0.0].into()),
+            Vector(vec![0.0, f32::INFINITY].into()),
+            Vector(vec![].into()),
+        ];
+        let err = writer.push_batch(&[4, 5], &vectors).unwrap_err();
+        assert!(matches!(err, VectorError::BatchMismatch { doc_ids: 2, vectors: 3 }));
+        let report = writer.push_batch(&[4, 5, 6], &vectors).unwrap();
+        assert_eq!(report.pushed, 1);
+        assert_eq!(report.rejected.len(), 2);
+        assert!(matches!(report.rejected[0], (1, VectorError::InvalidVector(InvalidVector::Zero))));
+        assert!(matches!(report.rejected[1], (2, VectorError::InvalidVector(InvalidVector::Empty))));
+        writer.commit().unwrap();
+
+        // Queries are truncated and normalized like the vectors.
+        let reader = Reader::open_with_config(tmpdir.path(), ReaderConfig::new().ingest(ingest)).unwrap();
+        let res = reader.search(&Vector(vec![0.0, 3.0, 5.0].into())).unwrap();
+        assert_eq!(res[0].0, 2);
+        assert!(res[0].1.abs() < 1e-6);
+        assert!(reader.search(&Vector(vec![0.0, 0.0, 5.0].into())).is_err());
+    }
+
+    #[test]
+    fn metrics() {
+        init();
+
+        let vectors = [vec![1.0, 0.0], vec![0.5, 2.0], vec![3.0, 3.0]];
+        let query = vec![2.5, 2.5];
+
+        for (metric, expected) in [
+            (Metric::Angular, [(2, 0.0), (1, 0.142507)]),
+            (Metric::DotProduct, [(2, -15.0), (1, -6.25)]),
+            (Metric::Euclidean, [(2, 0.70710677), (1, 2.0615528)]),
+        ] {
+            let tmpdir = TempDir::new().unwrap();
+            let config = WriterConfig::new().metric(metric);
+            let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+            for (doc_id, vector) in vectors.iter().enumerate() {
+                writer.push_vec(doc_id, vector.clone()).unwrap();
+            }
+            writer.commit().unwrap();
+
+            let reader = Reader::open(tmpdir.path()).unwrap();
+            assert_eq!(reader.schema().unwrap().metric, metric);
+            let res = reader.search_vec(query.clone()).unwrap();
+            for ((doc_id, score), (expected_doc_id, expected_score)) in res.iter().zip(expected) {
+                assert_eq!(*doc_id, expected_doc_id);
+                assert!((score - expected_score).abs() < 1e-5, "{:?}: {} != {}", metric, score, expected_score);
+            }
+
+            // The metric is fixed when the index is created.
+            drop(writer);
+            let other = if metric == Metric::Euclidean { Metric::Angular } else { Metric::Euclidean };
+            assert!(Writer::open_with_config(tmpdir.path(), WriterConfig::new().metric(other)).is_err());
+        }
+    }
+
+    #[test]
+    fn int8_storage() {
+        init();
+
+        let dim = 64;
+        let vectors: Vec<Vec<f32>> = (0..500)
+            .map(|i| (0..dim).map(|j| ((i * 31 + j * 17) % 97) as f32 - 48.0).collect())
+            .collect();
+
+        let build = |config: WriterConfig| {
+            let tmpdir = TempDir::new().unwrap();
+            let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+            for (doc_id, vector) in vectors.iter().enumerate() {
+                writer.push_vec(doc_id, vector.clone()).unwrap();
+            }
+            writer.commit().unwrap();
+            tmpdir
+        };
+        let f32_dir = build(WriterConfig::new());
+        let int8_dir = build(WriterConfig::new().storage(Storage::Int8).full_precision(true));
+
+        let size = |dir: &TempDir, file: &str| std::fs::metadata(dir.path().join(file)).unwrap().len();
+        assert!(size(&int8_dir, "elements.dat") * 3 < size(&f32_dir, "elements.dat"));
+        assert_eq!(size(&int8_dir, "elements_full.dat"), size(&f32_dir, "elements.dat"));
+
+        let f32_reader = Reader::open(f32_dir.path()).unwrap();
+        let int8_reader = Reader::open(int8_dir.path()).unwrap();
+        assert_eq!(int8_reader.schema().unwrap().storage, Storage::Int8);
+
+        let mut recall = 0;
+        for query in vectors.iter().step_by(50) {
+            let expected = f32_reader.search_vec(query.clone()).unwrap();
+            let res = int8_reader.search_vec(query.clone()).unwrap();
+            // Reranked with the full-precision vectors, the scores are exact.
+            assert!((res[0].1 - expected[0].1).abs() < 1e-5);
+            recall += res.iter().filter(|r| expected[..10].iter().any(|e| e.0 == r.0)).count();
+        }
+        assert!(recall >= 90, "recall@10 {} of 100", recall);
+
+        let config = WriterConfig::new().metric(Metric::Euclidean).storage(Storage::Int8);
+        assert!(Writer::open_with_config(TempDir::new().unwrap().path(), config).is_err());
+    }
+
+    #[test]
+    fn half_precision_storage() {
+        init();
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let dim = 64;
+        let vectors: Vec<Vec<f32>> = (0..500)
+            .map(|_| (0..dim).map(|_| rng.gen_range(-1.0..1.0)).collect())
+            .collect();
+
+        let build = |storage: Storage| {
+            let tmpdir = TempDir::new().unwrap();
+            let config = WriterConfig::new().metric(Metric::Euclidean).storage(storage);
+            let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+            for (doc_id, vector) in vectors[..400].iter().enumerate() {
+                writer.push_vec(doc_id, vector.clone()).unwrap();
+            }
+            writer.commit().unwrap();
+            drop(writer);
+
+            // Vectors pushed after reopening are appended to the mapped ones.
+            let mut writer = Writer::open(tmpdir.path()).unwrap();
+            for (doc_id, vector) in vectors.iter().enumerate().skip(400) {
+                writer.push_vec(doc_id, vector.clone()).unwrap();
+            }
+            writer.commit().unwrap();
+            tmpdir
+        };
+        let f32_dir = build(Storage::F32);
+        let f32_reader = Reader::open(f32_dir.path()).unwrap();
+        let f32_size = std::fs::metadata(f32_dir.path().join("elements.dat")).unwrap().len();
+
+        for storage in [Storage::F16, Storage::Bf16] {
+            let dir = build(storage);
+            let size = std::fs::metadata(dir.path().join("elements.dat")).unwrap().len();
+            assert!(size * 2 <= f32_size + 8, "{:?} elements take {} bytes", storage, size);
+
+            let reader = Reader::open(dir.path()).unwrap();
+            assert_eq!(reader.schema().unwrap().storage, storage);
+            for (doc_id, query) in vectors.iter().enumerate().step_by(50) {
+                let expected = f32_reader.search_vec(query.clone()).unwrap();
+                let res = reader.search_vec(query.clone()).unwrap();
+                assert_eq!(res[0].0, doc_id);
+                assert!((res[1].1 - expected[1].1).abs() < 1e-2);
+            }
+        }
+    }
+
+    #[test]
+    fn sum_embeddings() {
+        init();
+
+        let words = ["red", "green", "blue", "car", "boat", "house"];
+        let embeddings: Vec<Vec<f32>> = (0..words.len())
+            .map(|i| (0..words.len()).map(|j| if i == j { 1.0 } else { 0.1 }).collect())
+            .collect();
+
+        let tmpdir = TempDir::new().unwrap();
+        let config = WriterConfig::new().storage(Storage::SumEmbeddings);
+        let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+        let terms: Vec<usize> = embeddings.iter().map(|e| writer.push_embedding(e).unwrap()).collect();
+        assert!(writer.push_vec(0, vec![1.0; 6]).is_err());
+        assert!(matches!(
+            writer.push_terms(0, &[9]),
+            Err(VectorError::InvalidVector(InvalidVector::UnknownTerm(9)))
+        ));
+
+        // Every color with every thing.
+        for (doc_id, (color, thing)) in (0..3).flat_map(|c| (3..6).map(move |t| (c, t))).enumerate() {
+            writer.push_terms(doc_id, &[terms[color], terms[thing]]).unwrap();
+        }
+        writer.commit().unwrap();
+        assert!(tmpdir.path().join("embeddings.dat").exists());
+        drop(writer);
+
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        assert_eq!(reader.schema().unwrap().storage, Storage::SumEmbeddings);
+        assert_eq!(reader.schema().unwrap().dimension, 6);
+        // "green boat"
+        let res = reader.search_terms(&[1, 4]).unwrap();
+        assert_eq!(res[0].0, 4);
+        assert!(res[0].1 < 1e-6);
+        assert!(reader.search_terms(&[6]).is_err());
+
+        // The mapped embeddings and terms are extended after reopening.
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        let vehicle = writer.push_embedding(&[0.0, 0.0, 0.0, 0.5, 0.5, 0.0]).unwrap();
+        assert_eq!(vehicle, 6);
+        assert!(writer.push_embedding(&[1.0; 7]).is_err());
+        // An embedding is a write of its own, but no vector.
+        assert_eq!(writer.pending().vectors, 0);
+        assert_eq!(writer.pending().bytes, 24);
+        writer.push_terms(9, &[terms[0], vehicle, vehicle]).unwrap();
+        writer.commit().unwrap();
+
+        let res = reader.search_terms(&[0, 6, 6]).unwrap();
+        assert_eq!(res[0].0, 9);
+        assert_eq!(reader.search_terms(&[2, 3]).unwrap()[0].0, 6);
+        drop(writer);
+
+        // Terms without their embeddings can't be read.
+        std::fs::remove_file(tmpdir.path().join("embeddings.dat")).unwrap();
+        assert!(matches!(Writer::open(tmpdir.path()), Err(VectorError::Schema(_))));
+    }
+
+    #[test]
+    fn named_spaces() {
+        init();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        writer.add_space("title", Schema::with_metric(4, Metric::Angular)).unwrap();
+        writer.add_space("image", Schema::with_metric(3, Metric::Euclidean)).unwrap();
+        writer.add_space("image", Schema::with_metric(3, Metric::Euclidean)).unwrap();
+        assert!(writer.add_space("image", Schema::with_metric(3, Metric::Angular)).is_err());
+        assert!(writer.add_space("../title", Schema::new(4)).is_err());
+        // Written by the commit, with the rest of the files of the space.
+        assert!(!tmpdir.path().join("spaces/title/schema.json").exists());
+
+        for doc_id in 0..4 {
+            let mut title = vec![0.1; 4];
+            title[doc_id] = 1.0;
+            writer.push_to_space("title", doc_id, title).unwrap();
+            writer.push_to_space("image", doc_id, vec![doc_id as f32 + 1.0; 3]).unwrap();
+        }
+        writer.push_vec(10, vec![1.0, 2.0]).unwrap();
+        assert!(matches!(
+            writer.push_to_space("body", 0, vec![1.0]),
+            Err(VectorError::UnknownSpace(_))
+        ));
+        assert!(writer.push_to_space("image", 0, vec![1.0; 4]).is_err());
+        writer.commit().unwrap();
+        assert!(tmpdir.path().join("spaces.json").exists());
+        assert!(tmpdir.path().join("spaces/title/index.dat").exists());
+
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        assert_eq!(reader.space_schema("image").unwrap().metric, Metric::Euclidean);
+        assert_eq!(reader.search_space("title", &[0.0, 0.0, 1.0, 0.0]).unwrap()[0].0, 2);
+        assert_eq!(reader.search_space("image", &[4.1, 4.1, 4.1]).unwrap()[0].0, 3);
+        assert_eq!(reader.search_vec(vec![1.0, 2.0]).unwrap()[0].0, 10);
+        assert!(matches!(
+            reader.search_space("body", &[1.0]),
+            Err(VectorError::UnknownSpace(_))
+        ));
+        assert!(matches!(
+            reader.search_space("image", &[1.0]),
+            Err(VectorError::DimensionMismatch { .. })
+        ));
+
+        // The title points to 1 and the image to 2, the image weighs more.
+        let queries = [
+            SpaceQuery::new("title", &[0.0, 1.0, 0.0, 0.0]),
+            SpaceQuery::new("image", &[3.0, 3.0, 3.0]).weight(10.0),
+        ];
+        let res = reader.search_fused(&queries).unwrap();
+        assert_eq!(res[0].0, 2);
+        assert!(res.windows(2).all(|w| w[0].1 <= w[1].1));
+        let res = reader.search_fused(&[queries[0]]).unwrap();
+        assert_eq!(res[0].0, 1);
+
+        // Deletions apply to every space, without building their graphs again.
+        let graph = tmpdir.path().join("spaces/image/index.dat");
+        let built = std::fs::metadata(&graph).unwrap().modified().unwrap();
+        writer.delete(2).unwrap();
+        writer.commit().unwrap();
+        assert!(reader.search_space("image", &[3.0, 3.0, 3.0]).unwrap().iter().all(|(doc_id, _)| *doc_id != 2));
+        assert_eq!(std::fs::metadata(&graph).unwrap().modified().unwrap(), built);
+        drop(writer);
+
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        assert_eq!(writer.space_schema("title").unwrap().dimension, 4);
+        writer.push_to_space("image", 7, vec![7.0; 3]).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(reader.search_space("image", &[7.0, 7.0, 7.0]).unwrap()[0], (7, 0.0));
+        assert_eq!(reader.search_space("title", &[0.0, 0.0, 0.0, 1.0]).unwrap()[0].0, 3);
+    }
+
+    #[test]
+    fn readers_reload_on_their_own() {
+        init();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        writer.push_vec(0, vec![1.0, 0.0]).unwrap();
+        writer.commit().unwrap();
+        let readers = [Reader::open(tmpdir.path()).unwrap(), Reader::open(tmpdir.path()).unwrap()];
+
+        writer.push_vec(1, vec![0.0, 1.0]).unwrap();
+        writer.commit().unwrap();
+        for reader in &readers {
+            assert!(reader.is_dirty());
+            assert_eq!(reader.search_vec(vec![0.0, 1.0]).unwrap()[0].0, 1);
+            assert_eq!(reader.generation(), 2);
+        }
+
+        // A generation that can't be loaded fails the search, and the reload is retried.
+        writer.push_vec(2, vec![1.0, 1.0]).unwrap();
+        writer.commit().unwrap();
+        let tombstones_path = tmpdir.path().join("tombstones.bitmap");
+        let tombstones = std::fs::read(&tombstones_path).unwrap();
+        std::fs::remove_file(&tombstones_path).unwrap();
+        std::fs::create_dir_all(tombstones_path.join("blocked")).unwrap();
+        assert!(matches!(readers[0].search_vec(vec![1.0, 1.0]), Err(VectorError::Io(_))));
+        assert_eq!(readers[0].generation(), 2);
+
+        std::fs::remove_dir_all(&tombstones_path).unwrap();
+        std::fs::write(&tombstones_path, tombstones).unwrap();
+        assert_eq!(readers[0].search_vec(vec![1.0, 1.0]).unwrap()[0].0, 2);
+        assert_eq!(readers[0].generation(), 3);
+    }
+
+    #[test]
+    fn failed_commit_rolls_back() {
+        init();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        writer.add_space("title", Schema::with_metric(2, Metric::Euclidean)).unwrap();
+        writer.push_vec(0, vec![1.0, 0.0]).unwrap();
+        writer.push_to_space("title", 0, vec![1.0, 0.0]).unwrap();
+        writer.commit().unwrap();
+
+        let elements_path = tmpdir.path().join("elements.dat");
+        let space_path = tmpdir.path().join("spaces/title/elements.dat");
+        let elements_len = std::fs::metadata(&elements_path).unwrap().len();
+        let space_elements = std::fs::read(&space_path).unwrap();
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        assert_eq!(reader.search_vec(vec![0.0, 1.0]).unwrap().len(), 1);
+
+        // The vectors of the space can't be appended, after the ones of the index were.
+        writer.push_vec(1, vec![0.0, 1.0]).unwrap();
+        writer.push_to_space("title", 1, vec![0.0, 1.0]).unwrap();
+        std::fs::remove_file(&space_path).unwrap();
+        std::fs::create_dir_all(space_path.join("blocked")).unwrap();
+        assert!(writer.commit().is_err());
+        assert_eq!(std::fs::metadata(&elements_path).unwrap().len(), elements_len);
+        assert!(!tmpdir.path().join("COMMIT_LOCK").exists());
+        assert_eq!(reader.search_vec(vec![0.0, 1.0]).unwrap().len(), 1);
+        assert_eq!(reader.generation(), 1);
+
+        std::fs::remove_dir_all(&space_path).unwrap();
+        std::fs::write(&space_path, space_elements).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(reader.search_vec(vec![0.0, 1.0]).unwrap()[0].0, 1);
+        assert_eq!(reader.generation(), 2);
+        assert_eq!(reader.search_space("title", &[0.0, 1.0]).unwrap()[0].0, 1);
+    }
+
+    #[test]
+    fn failed_commit_keeps_writes() {
+        init();
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        writer.add_space("title", Schema::with_metric(2, Metric::Euclidean)).unwrap();
+        writer.push_to_space("title", 0, vec![1.0, 0.0]).unwrap();
+        writer.push_to_namespace("tenant", 0, &Vector::from(vec![1.0, 0.0])).unwrap();
+        writer.commit().unwrap();
+
+        // The graph of the space can't be extended, so the commit fails before writing anything.
+        let index_path = tmpdir.path().join("spaces/title/index.dat");
+        let index = std::fs::read(&index_path).unwrap();
+        std::fs::remove_file(&index_path).unwrap();
+        std::fs::create_dir_all(index_path.join("blocked")).unwrap();
+        writer.push_to_space("title", 1, vec![0.0, 1.0]).unwrap();
+        writer.delete_namespace("tenant").unwrap();
+        writer.push_to_namespace("tenant", 5, &Vector::from(vec![0.0, 1.0])).unwrap();
+        assert!(writer.commit().is_err());
+        assert_eq!(writer.pending().vectors, 2);
+
+        std::fs::remove_dir_all(&index_path).unwrap();
+        std::fs::write(&index_path, index).unwrap();
+        assert_eq!(writer.commit().unwrap(), 2);
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        assert_eq!(reader.search_space("title", &[0.0, 1.0]).unwrap()[0].0, 1);
+        // The namespace deleted before the failed commit only has the vectors pushed since.
+        assert_eq!(reader.namespace_stats("tenant").unwrap().vectors, 1);
+        let res = reader.search_namespace("tenant", &Vector::from(vec![0.0, 1.0])).unwrap();
+        assert_eq!(res[0].0, 5);
+        assert!(res[0].1 < 1e-3);
+    }
+
+    #[test]
+    fn namespaces() {
+        init();
+
+        let dim = 8;
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut random_vector = || Vector::from((0..dim).map(|_| rng.gen_range(-1.0..1.0)).collect::<Vec<f32>>());
+
+        let tmpdir = TempDir::new().unwrap();
+        let config = WriterConfig::new().namespace_graph_threshold(50);
+        let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+        writer.push(0, &random_vector()).unwrap();
+
+        // Both tenants use the same document ids.
+        let kb1: Vec<_> = (0..3).map(|_| random_vector()).collect();
+        let kb2: Vec<_> = (0..3).map(|_| random_vector()).collect();
+        for doc_id in 0..3 {
+            writer.push_to_namespace("kb1", doc_id, &kb1[doc_id]).unwrap();
+            writer.push_to_namespace("kb2", doc_id, &kb2[doc_id]).unwrap();
+        }
+        for doc_id in 0..100 {
+            writer.push_to_namespace("kb3", doc_id, &random_vector()).unwrap();
+        }
+        assert!(writer.push_to_namespace("kb/1", 0, &kb1[0]).is_err());
+        assert!(writer.push_to_namespace("kb1", 0, &create_vector(3, 1.0)).is_err());
+        // The ids of the main space can't reach the keys of the documents of namespaces.
+        assert!(writer.push(namespaces::doc_key(1, 0), &kb1[0]).is_err());
+        assert!(writer.delete(namespaces::doc_key(1, 0)).is_err());
+        writer.commit().unwrap();
+        assert!(tmpdir.path().join("namespaces.json").exists());
+        assert!(!tmpdir.path().join("namespaces/kb1/index.dat").exists());
+        assert!(tmpdir.path().join("namespaces/kb3/index.dat").exists());
+
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        assert_eq!(reader.namespaces(), ["kb1", "kb2", "kb3"]);
+
+        // Searches don't leave the namespace, even with the vector of another tenant.
+        let res = reader.search_namespace("kb1", &kb2[1]).unwrap();
+        assert_eq!(res.len(), 3);
+        for (doc_id, score) in &res {
+            assert!((score - Metric::Angular.distance(&kb1[*doc_id].0, &kb2[1].0)).abs() < 1e-5);
+        }
+        assert_eq!(reader.search_namespace("kb2", &kb2[1]).unwrap()[0].0, 1);
+        assert_eq!(reader.search(&kb1[0]).unwrap().len(), 1);
+        assert!(matches!(
+            reader.search_namespace("kb4", &kb1[0]),
+            Err(VectorError::UnknownNamespace(_))
+        ));
+
+        let stats = NamespaceStats {
+            documents: 3,
+            vectors: 3,
+            deleted: 0,
+            graph: false,
+        };
+        assert_eq!(reader.namespace_stats("kb1").unwrap(), stats);
+        assert!(reader.namespace_stats("kb3").unwrap().graph);
+        assert_eq!(reader.search_namespace("kb3", &kb1[0]).unwrap().len(), 30);
+
+        // Namespaces unmapped to make room are mapped again when they are searched.
+        let small = Reader::open_with_config(tmpdir.path(), ReaderConfig::new().max_namespaces(1)).unwrap();
+        for _ in 0..2 {
+            assert_eq!(small.search_namespace("kb1", &kb1[2]).unwrap()[0].0, 2);
+            assert_eq!(small.search_namespace("kb2", &kb2[0]).unwrap()[0].0, 0);
+        }
+
+        // Deleting a document of a namespace leaves the other namespaces alone.
+        writer.delete_from_namespace("kb1", 1).unwrap();
+        assert!(writer.delete_from_namespace("kb4", 1).is_err());
+        writer.commit().unwrap();
+        assert!(reader.search_namespace("kb1", &kb1[1]).unwrap().iter().all(|(doc_id, _)| *doc_id != 1));
+        assert_eq!(reader.search_namespace("kb2", &kb2[1]).unwrap()[0].0, 1);
+        let stats = reader.namespace_stats("kb1").unwrap();
+        assert_eq!((stats.documents, stats.deleted), (2, 1));
+
+        writer.delete_namespace("kb2").unwrap();
+        writer.commit().unwrap();
+        assert!(!tmpdir.path().join("namespaces/kb2").exists());
+        assert_eq!(reader.namespaces(), ["kb1", "kb3"]);
+        assert!(reader.search_namespace("kb2", &kb2[1]).is_err());
+        drop(writer);
+
+        // A namespace created again starts empty.
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        assert_eq!(writer.namespaces(), ["kb1", "kb3"]);
+        writer.push_to_namespace("kb2", 7, &kb2[0]).unwrap();
+        writer.push_to_namespace("kb1", 5, &kb2[0]).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(reader.search_namespace("kb2", &kb2[1]).unwrap().len(), 1);
+        assert_eq!(reader.search_namespace("kb1", &kb2[0]).unwrap()[0].0, 5);
+        assert_eq!(reader.namespace_stats("kb2").unwrap().documents, 1);
+    }
+
+    #[test]
+    fn rerank_depth() {
+        init();
+
+        let dim = 32;
+        let vectors: Vec<Vec<f32>> = (0..1000)
+            .map(|i| (0..dim).map(|j| ((i * 37 + j * 11) % 101) as f32 - 50.0).collect())
+            .collect();
+
+        let tmpdir = TempDir::new().unwrap();
+        let config = WriterConfig::new().storage(Storage::Int8).full_precision(true);
+        let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+        for (doc_id, vector) in vectors.iter().enumerate() {
+            writer.push_vec(doc_id, vector.clone()).unwrap();
+        }
+        writer.commit().unwrap();
+
+        let config = ReaderConfig::new().num_neighbors(5).max_search(10).rerank_depth(100);
+        let reader = Reader::open_with_config(tmpdir.path(), config).unwrap();
+
+        let normalized: Vec<_> = vectors.iter().map(|v| Vector::from(v.clone())).collect();
+        for query in normalized.iter().step_by(100) {
+            let mut exact: Vec<_> = normalized
+                .iter()
+                .enumerate()
+                .map(|(doc_id, v)| (doc_id, Metric::Angular.distance(&v.0, &query.0)))
+                .collect();
+            exact.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+            let res = reader.search(query).unwrap();
+            assert_eq!(res.len(), 5);
+            assert!(res.windows(2).all(|w| w[0].1 <= w[1].1));
+            for ((_, score), (_, exact_score)) in res.iter().zip(&exact) {
+                assert!((score - exact_score).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn product_quantization() {
+        init();
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let dim = 32;
+        let vectors: Vec<Vec<f32>> = (0..1000)
+            .map(|_| (0..dim).map(|_| rng.gen_range(-1.0..1.0)).collect())
+            .collect();
+
+        let tmpdir = TempDir::new().unwrap();
+        let config = WriterConfig::new().product_quantization(PqConfig::new(8).sample_size(500));
+        let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+        for (doc_id, vector) in vectors.iter().enumerate() {
+            writer.push_vec(doc_id, vector.clone()).unwrap();
+        }
+        writer.commit().unwrap();
+
+        let size = |file: &str| std::fs::metadata(tmpdir.path().join(file)).unwrap().len();
+        assert!(size("pq.bin") * 2 < size("elements.dat"));
+
+        let config = ReaderConfig::new().num_neighbors(10).rerank_depth(100).product_quantization(true);
+        let reader = Reader::open_with_config(tmpdir.path(), config).unwrap();
+        assert_eq!(reader.schema().unwrap().product_quantization.unwrap().subspaces, 8);
+
+        let normalized: Vec<_> = vectors.iter().map(|v| Vector::from(v.clone())).collect();
+        let mut recall = 0;
+        for (doc_id, query) in normalized.iter().enumerate().step_by(100) {
+            let mut exact: Vec<_> = normalized
+                .iter()
+                .enumerate()
+                .map(|(doc_id, v)| (doc_id, Metric::Angular.distance(&v.0, &query.0)))
+                .collect();
+            exact.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+            let res = reader.search(query).unwrap();
+            assert_eq!(res[0].0, doc_id);
+            recall += res.iter().filter(|r| exact[..10].iter().any(|e| e.0 == r.0)).count();
+        }
+        assert!(recall >= 80, "recall@10 {} of 100", recall);
+
+        // The codes of the next generation are picked up on reload.
+        writer.delete(0).unwrap();
+        writer.commit().unwrap();
+        let res = reader.search(&normalized[0]).unwrap();
+        assert!(res.iter().all(|(doc_id, _)| *doc_id != 0));
+    }
+
+    #[test]
+    fn binary_prefilter() {
+        init();
+
+        let mut rng = StdRng::seed_from_u64(11);
+        let dim = 128;
+        let vectors: Vec<Vec<f32>> = (0..1000)
+            .map(|_| (0..dim).map(|_| rng.gen_range(-1.0..1.0)).collect())
+            .collect();
+
+        let tmpdir = TempDir::new().unwrap();
+        let config = WriterConfig::new().binary_quantization(true);
+        let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+        // The second commit extends the codes and the graph of the first.
+        for (start, chunk) in vectors.chunks(500).enumerate() {
+            for (i, vector) in chunk.iter().enumerate() {
+                writer.push_vec(start * 500 + i, vector.clone()).unwrap();
+            }
+            writer.commit().unwrap();
+        }
+        assert!(tmpdir.path().join("binary.dat").exists());
+
+        let normalized: Vec<_> = vectors.iter().map(|v| Vector::from(v.clone())).collect();
+        for prefilter in [BinaryPrefilter::Scan, BinaryPrefilter::Graph] {
+            let config = ReaderConfig::new().num_neighbors(10).rerank_depth(200).binary_prefilter(prefilter);
+            let reader = Reader::open_with_config(tmpdir.path(), config).unwrap();
+            assert!(reader.schema().unwrap().binary_quantization);
+
+            let mut recall = 0;
+            for (doc_id, query) in normalized.iter().enumerate().step_by(100) {
+                let mut exact: Vec<_> = normalized
+                    .iter()
+                    .enumerate()
+                    .map(|(doc_id, v)| (doc_id, Metric::Angular.distance(&v.0, &query.0)))
+                    .collect();
+                exact.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+                // Rescored with the f32 vectors.
+                let res = reader.search(query).unwrap();
+                assert_eq!(res[0], (doc_id, exact[0].1));
+                recall += res.iter().filter(|r| exact[..10].iter().any(|e| e.0 == r.0)).count();
+            }
+            assert!(recall >= 70, "{:?} recall@10 {} of 100", prefilter, recall);
+        }
+    }
+
+    #[test]
+    fn background_commit() {
+        init();
+
+        let dim = 8;
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut random_vector = || Vector::from((0..dim).map(|_| rng.gen_range(-1.0..1.0)).collect::<Vec<f32>>());
+
+        let tmpdir = TempDir::new().unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        assert_eq!(writer.generation(), 0);
+        let first = random_vector();
+        writer.push(0, &first).unwrap();
+        for doc_id in 1..1500 {
+            writer.push(doc_id, &random_vector()).unwrap();
+        }
+
+        let handle = writer.commit_async().unwrap();
+        assert_eq!(handle.generation(), 1);
+        assert!(matches!(writer.commit_async(), Err(VectorError::Locked(_))) || handle.is_finished());
+        // The writer keeps taking changes, which go to the next commit.
+        let second = random_vector();
+        writer.push(5000, &second).unwrap();
+        assert_eq!(handle.wait().unwrap(), 1);
+        assert_eq!(writer.generation(), 1);
+
+        let reader = Reader::open(tmpdir.path()).unwrap();
+        assert_eq!(reader.generation(), 1);
+        assert_eq!(reader.search(&first).unwrap()[0].0, 0);
+        assert_ne!(reader.search(&second).unwrap()[0].0, 5000);
+
+        // A cancelled commit leaves the previous generation untouched.
+        let kb1 = random_vector();
+        writer.push_to_namespace("kb1", 7, &kb1).unwrap();
+        let handle = writer.commit_async().unwrap();
+        handle.cancel();
+        assert!(matches!(handle.wait(), Err(VectorError::Cancelled)));
+        assert_eq!(writer.generation(), 1);
+        assert_eq!(reader.generation(), 1);
+        assert_ne!(reader.search(&second).unwrap()[0].0, 5000);
+        assert!(!tmpdir.path().join("namespaces/kb1/elements.dat").exists());
+
+        // The next commit publishes what the cancelled one didn't.
+        let handle = writer.commit_async().unwrap();
+        while !handle.is_finished() {
+            assert!(handle.progress() <= 1.0);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(handle.progress(), 1.0);
+        assert_eq!(handle.eta(), Some(Duration::ZERO));
+        assert_eq!(handle.wait().unwrap(), 2);
+        assert_eq!(reader.generation(), 2);
+        assert_eq!(reader.search(&second).unwrap()[0].0, 5000);
+        assert_eq!(reader.search_namespace("kb1", &kb1).unwrap()[0].0, 7);
+
+        writer.commit().unwrap();
+        assert_eq!(writer.generation(), 3);
+        drop(writer);
+        assert_eq!(Writer::open(tmpdir.path()).unwrap().generation(), 3);
+    }
+
+    #[test]
+    fn append_only_elements() {
+        init();
+
+        let tmpdir = TempDir::new().unwrap();
+        let config = WriterConfig::new().metric(Metric::Euclidean).full_precision(true).storage(Storage::F16);
+        let mut writer = Writer::open_with_config(tmpdir.path(), config).unwrap();
+        for doc_id in 0..10 {
+            writer.push_vec(doc_id, vec![doc_id as f32, 1.0]).unwrap();
+        }
+        writer.push_to_namespace("kb1", 0, &Vector(vec![1.0, 1.0].into())).unwrap();
+        // Rejected rather than stored as an infinite component.
+        let err = writer.push_vec(10, vec![1e5, 1.0]).unwrap_err();
+        assert!(matches!(err, VectorError::InvalidVector(InvalidVector::OutOfRange(0))));
+        writer.commit().unwrap();
+        let reader = Reader::open(tmpdir.path()).unwrap();
+
+        // Commits append the new vectors after the ones the readers have mapped.
+        let paths = ["elements.dat", "elements_full.dat", "namespaces/kb1/elements.dat"];
+        let committed: Vec<_> = paths.iter().map(|path| std::fs::read(tmpdir.path().join(path)).unwrap()).collect();
+        for doc_id in 10..20 {
+            writer.push_vec(doc_id, vec![doc_id as f32, 1.0]).unwrap();
+        }
+        writer.push_to_namespace("kb1", 1, &Vector(vec![5.0, 1.0].into())).unwrap();
+        writer.commit().unwrap();
+        for (path, committed) in paths.iter().zip(committed) {
+            let appended = std::fs::read(tmpdir.path().join(path)).unwrap();
+            assert!(appended.len() > committed.len());
+            assert_eq!(appended[..committed.len()], committed[..]);
+        }
+        assert_eq!(reader.search_vec(vec![15.0, 1.0]).unwrap()[0].0, 15);
+        assert_eq!(reader.search_namespace("kb1", &Vector(vec![5.0, 1.0].into())).unwrap()[0].0, 1);
+
+        // A vector half-written by a commit that didn't finish is cut when the writer opens.
+        drop(writer);
+        let elements_path = tmpdir.path().join("elements.dat");
+        let len = std::fs::metadata(&elements_path).unwrap().len();
+        std::fs::OpenOptions::new().append(true).open(&elements_path).unwrap().write_all(&[1, 2, 3]).unwrap();
+        let mut writer = Writer::open(tmpdir.path()).unwrap();
+        assert_eq!(std::fs::metadata(&elements_path).unwrap().len(), len);
+        writer.push_vec(20, vec![20.0, 1.0]).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(reader.search_vec(vec![20.0, 1.0]).unwrap()[0].0, 20);
+        assert_eq!(reader.search_vec(vec![3.0, 1.0]).unwrap()[0].0, 3);
+    }
 }
@@ -0,0 +1,177 @@
+//! Tenant namespaces hosted by an index next to its main space.
+//!
+//! A namespace is a segment of its own under `namespaces/<name>/`, with the vectors of one tenant
+//! and a graph once it is large enough to need one. Namespaces share the schema, the LMDB
+//! environment and the tombstones of the index, and their documents and vectors get ids in ranges
+//! of their own: a document of a namespace only exists in it, so searches never leave it.
+
+use std::{collections::BTreeMap, fs::File, io, ops::Range, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{directory::Location, spaces, VectorError};
+
+/// Marks the vector ids of namespaces, which are otherwise laid out like the ids of spaces.
+const NAMESPACE_BIT: usize = 1 << 63;
+
+/// Documents need ids below this bound, the main space's as well as the namespaces': the keys
+/// of the documents of namespaces start there in the id map the index shares.
+pub const MAX_DOC_ID: usize = spaces::vec_id(1, 0);
+
+/// Namespaces with fewer vectors than this are searched by brute force and don't get a graph.
+pub const DEFAULT_GRAPH_THRESHOLD: usize = 1000;
+
+/// Checks that `doc_id` is below `MAX_DOC_ID`.
+pub fn check_doc_id(doc_id: usize) -> Result<(), VectorError> {
+    match doc_id < MAX_DOC_ID {
+        true => Ok(()),
+        false => Err(VectorError::Schema(format!("documents need ids below {}", MAX_DOC_ID))),
+    }
+}
+
+/// Key of the document `doc_id` of the namespace `namespace_id` in the id map.
+pub fn doc_key(namespace_id: u64, doc_id: usize) -> usize {
+    spaces::vec_id(namespace_id, doc_id)
+}
+
+/// Id of the vector at `position` in the namespace `namespace_id`.
+pub fn vec_id(namespace_id: u64, position: usize) -> usize {
+    NAMESPACE_BIT | spaces::vec_id(namespace_id, position)
+}
+
+/// Document id of a key returned by `doc_key`.
+pub fn doc_id(doc_key: usize) -> usize {
+    spaces::position(doc_key)
+}
+
+/// Keys of all the documents of a namespace.
+pub fn doc_keys(namespace_id: u64) -> Range<usize> {
+    doc_key(namespace_id, 0)..doc_key(namespace_id + 1, 0)
+}
+
+/// Ids of all the vectors of a namespace.
+pub fn vec_ids(namespace_id: u64) -> Range<usize> {
+    vec_id(namespace_id, 0)..vec_id(namespace_id + 1, 0)
+}
+
+/// Names of the namespaces of an index and their ids, stored in `namespaces.json`.
+///
+/// Ids are never reused, so the ids of a deleted namespace can't be mistaken for the ones of a
+/// namespace created later.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceRegistry {
+    next_id: u64,
+    namespaces: BTreeMap<String, u64>,
+}
+
+impl Default for NamespaceRegistry {
+    fn default() -> Self {
+        NamespaceRegistry {
+            next_id: 1,
+            namespaces: BTreeMap::new(),
+        }
+    }
+}
+
+impl NamespaceRegistry {
+    /// Loads the registry stored at `path`, empty if there is none.
+    pub fn load(path: &Path) -> Result<Self, VectorError> {
+        match File::open(path) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file))
+                .map_err(|e| VectorError::Schema(format!("{:?}: {}", path, e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(NamespaceRegistry::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save<W: io::Write>(&self, writer: W) -> Result<(), VectorError> {
+        serde_json::to_writer_pretty(writer, self).map_err(|e| VectorError::Schema(e.to_string()))
+    }
+
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.namespaces.get(name).copied()
+    }
+
+    /// Registers `name` with a new id, unless it exists, and returns its id.
+    pub fn add(&mut self, name: &str) -> u64 {
+        if let Some(id) = self.get(name) {
+            return id;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.namespaces.insert(name.to_string(), id);
+        id
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<u64> {
+        self.namespaces.remove(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.namespaces.iter().map(|(name, id)| (name.as_str(), *id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.namespaces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.namespaces.is_empty()
+    }
+}
+
+/// Location of the files of the namespace `name` of the index at `location`.
+pub fn namespace_location(location: &Location, name: &str) -> Location {
+    Location(location.namespaces_path().join(name))
+}
+
+/// Size of a namespace, as seen by a `Reader`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NamespaceStats {
+    /// Documents with vectors in the namespace.
+    pub documents: usize,
+    /// Vectors stored in the namespace, deleted ones included.
+    pub vectors: usize,
+    /// Vectors of the namespace that are deleted.
+    pub deleted: usize,
+    /// Whether the namespace has a graph or is searched by brute force.
+    pub graph: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::NamedTempFile;
+
+    use super::{doc_id, doc_key, doc_keys, vec_id, vec_ids, NamespaceRegistry, MAX_DOC_ID};
+    use crate::vectors::spaces;
+
+    #[test]
+    fn ids() {
+        assert_eq!(doc_id(doc_key(3, 42)), 42);
+        assert_ne!(doc_key(1, 42), doc_key(2, 42));
+        assert!(doc_keys(1).contains(&doc_key(1, MAX_DOC_ID - 1)));
+        assert!(!doc_keys(1).contains(&doc_key(2, 0)));
+
+        // Vector ids don't collide with the ones of spaces or of the main space.
+        assert_ne!(vec_id(1, 7), spaces::vec_id(1, 7));
+        assert!(vec_ids(1).contains(&vec_id(1, 7)));
+        assert!(!vec_ids(1).contains(&7));
+    }
+
+    #[test]
+    fn registry() {
+        let mut registry = NamespaceRegistry::default();
+        assert_eq!(registry.add("kb1"), 1);
+        assert_eq!(registry.add("kb2"), 2);
+        assert_eq!(registry.add("kb1"), 1);
+        assert_eq!(registry.remove("kb2"), Some(2));
+        assert_eq!(registry.add("kb3"), 3);
+        assert_eq!(registry.get("kb2"), None);
+        assert_eq!(registry.len(), 2);
+
+        let file = NamedTempFile::new().unwrap();
+        registry.save(file.as_file()).unwrap();
+        assert_eq!(NamespaceRegistry::load(file.path()).unwrap(), registry);
+        assert!(NamespaceRegistry::load(&file.path().with_extension("missing")).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+use std::{fs::File, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Elements, Metric, VectorError};
+
+/// Centroids per subspace, so every code component fits in a byte.
+const NUM_CENTROIDS: usize = 256;
+
+/// Parameters of the product quantizer trained when an index is committed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PqConfig {
+    /// Number of subspaces the vectors are split into, which is the size in bytes of each code.
+    pub subspaces: usize,
+    /// Number of vectors sampled from the index to train the codebook.
+    pub sample_size: usize,
+    /// Iterations of k-means run on each subspace.
+    pub iterations: usize,
+}
+
+impl Default for PqConfig {
+    fn default() -> Self {
+        PqConfig {
+            subspaces: 8,
+            sample_size: 10_000,
+            iterations: 10,
+        }
+    }
+}
+
+impl PqConfig {
+    pub fn new(subspaces: usize) -> Self {
+        PqConfig {
+            subspaces,
+            ..PqConfig::default()
+        }
+    }
+
+    pub fn sample_size(mut self, sample_size: usize) -> Self {
+        self.sample_size = sample_size;
+        self
+    }
+
+    pub fn iterations(mut self, iterations: usize) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), VectorError> {
+        if self.subspaces == 0 || self.sample_size == 0 {
+            let message = format!("product quantization needs subspaces and a sample, got {:?}", self);
+            return Err(VectorError::Schema(message));
+        }
+        Ok(())
+    }
+}
+
+/// Centroids of every subspace. Subspace `i` covers the components `bounds[i]..bounds[i + 1]`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Codebook {
+    metric: Metric,
+    bounds: Vec<usize>,
+    /// Centroids of each subspace, one after the other.
+    centroids: Vec<Vec<f32>>,
+}
+
+impl Codebook {
+    /// Trains the centroids of each subspace with k-means over `sample`, vectors of the same
+    /// dimension. Vectors shorter than `config.subspaces` get one subspace per component.
+    pub fn train(config: &PqConfig, metric: Metric, sample: &[Vec<f32>]) -> Self {
+        let dimension = sample.first().map_or(0, |vector| vector.len());
+        let subspaces = config.subspaces.min(dimension);
+        let bounds: Vec<_> = (0..=subspaces).map(|i| i * dimension / subspaces.max(1)).collect();
+
+        let centroids = bounds
+            .windows(2)
+            .map(|bounds| {
+                let points: Vec<_> = sample.iter().map(|vector| &vector[bounds[0]..bounds[1]]).collect();
+                kmeans(&points, NUM_CENTROIDS.min(points.len()), config.iterations)
+            })
+            .collect();
+
+        Codebook {
+            metric,
+            bounds,
+            centroids,
+        }
+    }
+
+    pub fn subspaces(&self) -> usize {
+        self.centroids.len()
+    }
+
+    fn subspace<'v>(&self, i: usize, vector: &'v [f32]) -> &'v [f32] {
+        &vector[self.bounds[i]..self.bounds[i + 1]]
+    }
+
+    /// Index of the closest centroid of each subspace.
+    pub fn encode(&self, vector: &[f32]) -> Vec<u8> {
+        self.centroids
+            .iter()
+            .enumerate()
+            .map(|(i, centroids)| nearest(centroids, self.subspace(i, vector)) as u8)
+            .collect()
+    }
+
+    /// Precomputes the contribution of every centroid to the distance to `query`, so codes are
+    /// compared with one lookup per subspace.
+    pub fn distance_table(&self, query: &[f32]) -> DistanceTable {
+        let table = self
+            .centroids
+            .iter()
+            .enumerate()
+            .map(|(i, centroids)| {
+                let query = self.subspace(i, query);
+                centroids
+                    .chunks(query.len().max(1))
+                    .map(|centroid| match self.metric {
+                        Metric::Angular | Metric::DotProduct => dot(centroid, query),
+                        Metric::Euclidean => squared_l2(centroid, query),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        DistanceTable {
+            metric: self.metric,
+            table,
+        }
+    }
+}
+
+/// Asymmetric distances from a full-precision query to quantized vectors.
+#[derive(Debug)]
+pub struct DistanceTable {
+    metric: Metric,
+    table: Vec<Vec<f32>>,
+}
+
+impl DistanceTable {
+    /// Approximate distance from the query to the vector encoded as `code`, with the same scale
+    /// as `Metric::distance`.
+    pub fn distance(&self, code: &[u8]) -> f32 {
+        let sum: f32 = self.table.iter().zip(code).map(|(table, c)| table[*c as usize]).sum();
+        match self.metric {
+            Metric::Angular => (1.0 - sum).max(0.0),
+            Metric::DotProduct => -sum,
+            Metric::Euclidean => sum.sqrt(),
+        }
+    }
+}
+
+/// Codebook and codes of all the vectors of an index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProductQuantization {
+    codebook: Codebook,
+    /// Number of vectors the codebook was trained on.
+    trained_on: usize,
+    codes: Vec<u8>,
+}
+
+impl ProductQuantization {
+    /// Trains a codebook on a sample of `elements`, taken at regular intervals, and encodes all
+    /// of them.
+    pub fn build(config: &PqConfig, elements: &Elements) -> Self {
+        let step = (elements.len() / config.sample_size).max(1);
+        let sample: Vec<_> = (0..elements.len())
+            .step_by(step)
+            .take(config.sample_size)
+            .map(|idx| values(elements, idx))
+            .collect();
+        let codebook = Codebook::train(config, elements.metric(), &sample);
+
+        let mut pq = ProductQuantization {
+            codebook,
+            trained_on: sample.len(),
+            codes: Vec::new(),
+        };
+        pq.encode_from(elements);
+        pq
+    }
+
+    /// Brings `previous`, the quantization of a previous generation of `elements`, up to date by
+    /// encoding the vectors pushed since with its codebook. The codebook is only trained again
+    /// while its sample is smaller than `config.sample_size` and the index doubled since, so the
+    /// training work stays proportional to the size of the index.
+    pub fn update(config: &PqConfig, previous: Option<Self>, elements: &Elements) -> Self {
+        match previous {
+            Some(mut pq) if !pq.is_stale(config, elements) => {
+                pq.encode_from(elements);
+                pq
+            }
+            _ => ProductQuantization::build(config, elements),
+        }
+    }
+
+    fn is_stale(&self, config: &PqConfig, elements: &Elements) -> bool {
+        let undersampled = self.trained_on < config.sample_size && elements.len() >= 2 * self.trained_on;
+        undersampled || self.len() > elements.len() || self.codebook.metric != elements.metric()
+    }
+
+    /// Encodes the vectors of `elements` after the ones that have a code.
+    fn encode_from(&mut self, elements: &Elements) {
+        for idx in self.len()..elements.len() {
+            let code = self.codebook.encode(&values(elements, idx));
+            self.codes.extend(code);
+        }
+    }
+
+    pub fn codebook(&self) -> &Codebook {
+        &self.codebook
+    }
+
+    pub fn len(&self) -> usize {
+        self.codes.len() / self.codebook.subspaces().max(1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn code(&self, idx: usize) -> &[u8] {
+        let subspaces = self.codebook.subspaces();
+        &self.codes[idx * subspaces..(idx + 1) * subspaces]
+    }
+
+    /// Scans all the codes and returns the `k` closest vectors to `query` by their approximate
+    /// distance, in increasing order.
+    ///
+    /// The scan is linear in the number of vectors, but it only reads the codes, `subspaces`
+    /// bytes per vector held in memory, so it suits indexes whose codes fit in RAM while their
+    /// vectors don't. The vectors stay mapped, and only the candidates reranked are read.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(usize, f32)> {
+        let table = self.codebook.distance_table(query);
+        let mut results: Vec<_> = (0..self.len()).map(|idx| (idx, table.distance(self.code(idx)))).collect();
+
+        if k < results.len() {
+            results.select_nth_unstable_by(k, |a, b| a.1.total_cmp(&b.1));
+            results.truncate(k);
+        }
+        results.sort_by(|a, b| a.1.total_cmp(&b.1));
+        results
+    }
+
+    /// Loads the quantization stored at `path`, if there is one.
+    pub fn load(path: &Path) -> Result<Option<Self>, VectorError> {
+        match File::open(path) {
+            Ok(file) => bincode::deserialize_from(io::BufReader::new(file))
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save<W: io::Write>(&self, writer: W) -> Result<(), VectorError> {
+        let mut writer = io::BufWriter::new(writer);
+        bincode::serialize_into(&mut writer, self).map_err(io::Error::other)?;
+        io::Write::flush(&mut writer)?;
+        Ok(())
+    }
+}
+
+/// Components of the vector at `idx`. Angular vectors are normalized since quantized storage
+/// keeps only their direction.
+fn values(elements: &Elements, idx: usize) -> Vec<f32> {
+    let mut values = elements.get_element(idx).0.into_owned();
+    if elements.metric() == Metric::Angular {
+        let norm = values.iter().map(|x| x * x).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            values.iter_mut().for_each(|x| *x /= norm);
+        }
+    }
+    values
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// Position of the centroid closest to `point` among `centroids`, stored one after the other.
+fn nearest(centroids: &[f32], point: &[f32]) -> usize {
+    centroids
+        .chunks(point.len().max(1))
+        .map(|centroid| squared_l2(centroid, point))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(i, _)| i)
+}
+
+/// Lloyd's k-means, seeded with points spread over the input. Clusters left empty keep their
+/// previous centroid.
+fn kmeans(points: &[&[f32]], k: usize, iterations: usize) -> Vec<f32> {
+    let width = points.first().map_or(0, |point| point.len());
+    let mut centroids: Vec<f32> = (0..k).flat_map(|i| points[i * points.len() / k].iter().copied()).collect();
+
+    for _ in 0..iterations {
+        let mut sums = vec![0.0; k * width];
+        let mut counts = vec![0usize; k];
+        for point in points {
+            let cluster = nearest(&centroids, point);
+            counts[cluster] += 1;
+            for (sum, x) in sums[cluster * width..(cluster + 1) * width].iter_mut().zip(point.iter()) {
+                *sum += x;
+            }
+        }
+
+        for (cluster, count) in counts.into_iter().enumerate() {
+            if count > 0 {
+                let range = cluster * width..(cluster + 1) * width;
+                for (centroid, sum) in centroids[range.clone()].iter_mut().zip(&sums[range]) {
+                    *centroid = sum / count as f32;
+                }
+            }
+        }
+    }
+
+    centroids
+}
+
+#[cfg(test)]
+mod test {
+    use granne::angular::Vector;
+    use tempfile::NamedTempFile;
+
+    use super::{Codebook, PqConfig, ProductQuantization};
+    use crate::vectors::{Elements, Metric, Storage};
+
+    fn vectors(n: usize, dim: usize) -> Vec<Vec<f32>> {
+        (0..n)
+            .map(|i| (0..dim).map(|j| ((i * 31 + j * 17 + i * j) % 97) as f32 - 48.0).collect())
+            .collect()
+    }
+
+    #[test]
+    fn encode_and_distances() {
+        // Fewer distinct vectors than centroids, so the codes are exact.
+        let sample = vectors(17, 6);
+        let codebook = Codebook::train(&PqConfig::new(3), Metric::Euclidean, &sample);
+        assert_eq!(codebook.subspaces(), 3);
+
+        let query = [1.0, 0.0, -1.0, 2.0, 0.5, 3.0];
+        let table = codebook.distance_table(&query);
+        for vector in &sample {
+            let code = codebook.encode(vector);
+            assert_eq!(code.len(), 3);
+            let exact = Metric::Euclidean.distance(vector, &query);
+            assert!((table.distance(&code) - exact).abs() < 1e-4);
+        }
+
+        // Short vectors get a subspace per component.
+        let codebook = Codebook::train(&PqConfig::new(8), Metric::DotProduct, &vectors(10, 2));
+        assert_eq!(codebook.subspaces(), 2);
+    }
+
+    fn elements_of(vectors: &[Vec<f32>]) -> Elements<'static> {
+        let mut elements = Elements::new(Metric::Angular, Storage::F32);
+        for vector in vectors {
+            elements.push(&Vector::from(vector.clone())).unwrap();
+        }
+        elements
+    }
+
+    #[test]
+    fn build_search_and_save() {
+        let mut elements = elements_of(&vectors(300, 8));
+
+        let config = PqConfig::new(4).sample_size(100).iterations(5);
+        let pq = ProductQuantization::build(&config, &elements);
+        assert_eq!(pq.len(), 300);
+        assert_eq!(pq.code(299).len(), 4);
+
+        let query = elements.get_element(42).0.to_vec();
+        let res = pq.search(&query, 5);
+        assert_eq!(res.len(), 5);
+        assert!(res.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert!(res.iter().any(|(idx, _)| *idx == 42));
+
+        let file = NamedTempFile::new().unwrap();
+        pq.save(file.as_file()).unwrap();
+        assert_eq!(ProductQuantization::load(file.path()).unwrap().unwrap(), pq);
+
+        // New vectors are encoded with the codebook trained before.
+        for vector in vectors(20, 8) {
+            elements.push(&Vector::from(vector)).unwrap();
+        }
+        let updated = ProductQuantization::update(&config, Some(pq.clone()), &elements);
+        assert_eq!(updated.codebook(), pq.codebook());
+        assert_eq!(updated.len(), 320);
+        assert_eq!(updated.code(310), pq.codebook().encode(&super::values(&elements, 310)));
+
+        // Until the sample is full, a codebook is trained again once the index doubles.
+        let small = ProductQuantization::build(&config, &elements_of(&vectors(40, 8)));
+        assert_ne!(ProductQuantization::update(&config, Some(small.clone()), &elements).codebook(), small.codebook());
+        assert!(ProductQuantization::load(&file.path().with_extension("missing")).unwrap().is_none());
+    }
+}
@@ -1,133 +1,622 @@
-use granne::{
-    angular::{self, Vector, Vectors},
-    Granne,
+use granne::{angular::Vector, ElementContainer, Granne, Index};
+use lru::LruCache;
+use std::{collections::{BTreeMap, HashMap, HashSet}, num::NonZeroUsize, path::PathBuf, sync::{Arc, Mutex, RwLock}, fmt, io};
+extern crate lmdb_zero as lmdb;
+
+use super::{
+    commit, directory::Location, BinaryIndex, BinaryPrefilter, IngestConfig, ReaderConfig, elements, expiry::Expired, migration, DeletedDBReader, namespaces, spaces, storage, Elements, ExpiryDB, Filter, IndexMap, Matches, Lock, MetadataDB, NamespaceRegistry, NamespaceStats, ProductQuantization, Schema, SpaceQuery, SpaceRegistry, Storage, Tombstones, VectorError,
 };
-use std::{cell::RefCell, collections::HashMap, path::PathBuf, fmt, io};
 
-use super::{directory::Location, DeletedDBReader, IndexMap, Lock};
+/// Fraction of the index matching a filter under which the matching vectors are scanned by brute
+/// force instead of over-fetching from the graph.
+const BRUTE_FORCE_SELECTIVITY: f32 = 0.05;
+
+/// A named vector space, see `spaces`.
+struct Space<'a> {
+    id: u64,
+    index: Granne<'a, Elements<'a>>,
+    schema: Schema,
+}
+
+/// A tenant namespace, see `namespaces`. Small namespaces have no graph.
+struct Namespace<'a> {
+    id: u64,
+    elements: Elements<'a>,
+    index: Option<Granne<'a, Elements<'a>>>,
+}
+
+/// The files of the generation a reader serves. A reload loads the next generation aside and
+/// swaps it in whole, searches keep the one they started with.
+struct Served<'a> {
+    index: Granne<'a, Elements<'a>>,
+    full_precision: Option<Elements<'a>>,
+    product_quantization: Option<ProductQuantization>,
+    binary: Option<BinaryIndex>,
+    tombstones: Tombstones,
+    schema: Option<Schema>,
+    spaces: BTreeMap<String, Space<'a>>,
+    namespace_registry: NamespaceRegistry,
+    /// Namespaces loaded by a search of this generation, the most recently searched ones.
+    namespaces: Mutex<LruCache<String, Arc<Namespace<'a>>>>,
+    generation: u64,
+}
 
 pub struct Reader<'a> {
     location: Location,
     commit_lock: Lock,
-    index: RefCell<Granne<'a, Vectors<'a>>>,
+    served: RwLock<Arc<Served<'a>>>,
+    scan_codes: bool,
+    binary_prefilter: BinaryPrefilter,
+    ingest: IngestConfig,
     max_search: usize,
     num_neighbors: usize,
+    rerank_depth: usize,
+    max_namespaces: NonZeroUsize,
+    expired: Mutex<Expired>,
     deleted: DeletedDBReader<'a>,
+    expiry: ExpiryDB<'a>,
     index_map: IndexMap<'a>,
+    metadata: MetadataDB<'a>,
 }
 
 impl fmt::Debug  for Reader<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let served = self.served();
         f.debug_struct("Reader")
         .field("location", &self.location)
         .field("commit_lock", &self.commit_lock)
         .field("max_search", &self.max_search)
         .field("num_neighbors", &self.num_neighbors)
-        .field("deleted", &self.deleted)
+        .field("rerank_depth", &self.rerank_depth)
+        .field("scan_codes", &self.scan_codes)
+        .field("binary_prefilter", &self.binary_prefilter)
+        .field("ingest", &self.ingest)
+        .field("tombstones", &served.tombstones.len())
+        .field("expired", &self.expired.lock().unwrap().len())
         .field("index_map", &self.index_map)
+        .field("metadata", &self.metadata)
+        .field("schema", &served.schema)
+        .field("spaces", &served.spaces.keys().collect::<Vec<_>>())
+        .field("namespaces", &served.namespace_registry.len())
+        .field("generation", &served.generation)
         .finish()
     }
 }
 
 
 impl<'a> Reader<'a> {
-    pub fn open<T: Into<PathBuf>>(location: T) -> Result<Self, String> {
-        let location = Location(location.into());
-        let commit_lock = Lock::open(&location.commit_lock_path()).unwrap();
-
-        let index = match Reader::load_index(
-            location.index_path(),
-            location.elements_path(),
-        ) {
-            Ok(index) => index,
-            Err(e) => {
-                let message = format!("{}", e.to_string());
-                return Err(message)
-            },
-        };
+    pub fn open<T: Into<PathBuf>>(location: T) -> Result<Self, VectorError> {
+        Reader::open_with_config(location, ReaderConfig::default())
+    }
 
-        let index = RefCell::new(index);
-        let deleted_path = location.deleted_path();
-        let deleted = DeletedDBReader::open(deleted_path.to_str().unwrap()).unwrap();
+    pub fn open_with_config<T: Into<PathBuf>>(location: T, config: ReaderConfig) -> Result<Self, VectorError> {
+        let location = Location(location.into());
+        let commit_lock = Lock::open(location.commit_lock_path()).unwrap();
 
-        let index_map_path = location.index_map_path();
-        let index_map = IndexMap::open(index_map_path.to_str().unwrap()).unwrap();
+        let max_namespaces = NonZeroUsize::new(config.max_namespaces).unwrap_or(NonZeroUsize::MIN);
+        let env = storage::open_env(&location.lmdb_path(), storage::DEFAULT_MAP_SIZE)?;
+        migration::check(&env, &location)?;
+        let deleted = DeletedDBReader::open(&env)?;
+        let served = Reader::load(&location, &commit_lock, &deleted, max_namespaces)?;
+        let expiry = ExpiryDB::open(&env)?;
+        let expired = Mutex::new(Expired::load(&expiry)?);
+        let index_map = IndexMap::open(&env)?;
+        let metadata = MetadataDB::open(&env)?;
 
         Ok(Reader {
             location,
             commit_lock,
-            index,
-            max_search: 200,
-            num_neighbors: 30,
+            served: RwLock::new(Arc::new(served)),
+            scan_codes: config.product_quantization,
+            binary_prefilter: config.binary_prefilter,
+            ingest: config.ingest,
+            max_search: config.max_search,
+            num_neighbors: config.num_neighbors,
+            rerank_depth: config.rerank_depth,
+            max_namespaces,
+            expired,
             deleted,
+            expiry,
             index_map,
+            metadata,
         })
     }
 
-    pub fn search(&self, query_vector: &Vector<'static>) -> Vec<(usize, f32)> {
+    /// Loads the generation committed in `location`.
+    fn load(
+        location: &Location,
+        commit_lock: &Lock,
+        deleted: &DeletedDBReader,
+        max_namespaces: NonZeroUsize,
+    ) -> Result<Served<'a>, VectorError> {
+        // Commits append to the files of vectors, which are only mapped whole under the lock.
+        commit_lock.lock();
+        let loaded = (|| -> Result<_, VectorError> {
+            let stored = Schema::load(&location.schema_path())?;
+            let layout = stored.unwrap_or_else(|| Schema::new(0));
+            let index = Reader::load_index(location, layout)?;
+            Ok(Served {
+                full_precision: Reader::load_full_precision(location, layout)?,
+                product_quantization: Reader::load_product_quantization(location, layout)?,
+                binary: Reader::load_binary_index(location, layout)?,
+                tombstones: Reader::load_tombstones(location, deleted)?,
+                schema: Reader::load_schema(stored, &index),
+                index,
+                spaces: Reader::load_spaces(location)?,
+                namespace_registry: NamespaceRegistry::load(&location.namespace_registry_path())?,
+                namespaces: Mutex::new(LruCache::new(max_namespaces)),
+                generation: commit::load_generation(&location.generation_path())?,
+            })
+        })();
+        commit_lock.unlock();
+        loaded
+    }
+
+    /// The generation the reader serves. It stays valid for the caller while a reload swaps in the
+    /// next one.
+    fn served(&self) -> Arc<Served<'a>> {
+        self.served.read().unwrap().clone()
+    }
+
+    /// Indexes committed before the schema was recorded take the dimension of their vectors.
+    fn load_schema(stored: Option<Schema>, index: &Granne<Elements>) -> Option<Schema> {
+        let elements = index.get_elements();
+        stored.or_else(|| match elements.dim() {
+            0 => None,
+            dimension => Some(Schema::with_metric(dimension, elements.metric())),
+        })
+    }
+
+    fn check_dimension(served: &Served, query_vector: &Vector) -> Result<(), VectorError> {
+        match served.schema {
+            Some(schema) => schema.check(&query_vector.0),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the query through the ingestion pipeline of the reader, so it is truncated and
+    /// normalized like the indexed vectors, and checks its dimension.
+    fn prepare_query(&self, served: &Served, query_vector: &Vector) -> Result<Vector<'static>, VectorError> {
+        let query_vector = Vector(self.ingest.apply(&query_vector.0)?.into());
+        Reader::check_dimension(served, &query_vector)?;
+        Ok(query_vector)
+    }
+
+    pub fn schema(&self) -> Option<Schema> {
+        self.served().schema
+    }
+
+    /// Generation of the index the reader serves, 0 before the first commit.
+    pub fn generation(&self) -> u64 {
+        self.refresh_or_served().generation
+    }
+
+    /// Schema of the vector space `name`, if the index has it.
+    pub fn space_schema(&self, name: &str) -> Option<Schema> {
+        self.served().spaces.get(name).map(|space| space.schema)
+    }
+
+    /// Returns the closest documents to the query with their score, the distance given by the
+    /// metric of the index (see `Metric`), in increasing order.
+    ///
+    /// `rerank_depth` candidates are fetched from the graph, or from the quantization codes the
+    /// reader is configured to use, and ordered by their exact distance, computed with the
+    /// full-precision vectors if the index keeps them.
+    pub fn search(&self, query_vector: &Vector<'static>) -> Result<Vec<(usize, f32)>, VectorError> {
         debug!("Search for vector");
+        let served = self.refresh()?;
+        let query_vector = self.prepare_query(&served, query_vector)?;
+        self.search_prepared(&served, &query_vector)
+    }
+
+    fn search_prepared(&self, served: &Served, query_vector: &Vector<'static>) -> Result<Vec<(usize, f32)>, VectorError> {
+        let raw_results = self.candidates(served, query_vector, self.num_candidates());
+        let raw_results = self.rerank(served, raw_results, query_vector);
+
+        self.resolve(served, raw_results, self.num_neighbors)
+    }
+
+    /// Fetches the `num_candidates` closest vectors to the query from the structure the reader is
+    /// configured to use: the sign codes, the product-quantization codes or the graph.
+    fn candidates(&self, served: &Served, query_vector: &Vector<'static>, num_candidates: usize) -> Vec<(usize, f32)> {
+        let max_search = self.max_search.max(num_candidates);
+        if let Some(binary) = &served.binary {
+            match self.binary_prefilter {
+                BinaryPrefilter::Scan => return binary.scan(&query_vector.0, num_candidates),
+                BinaryPrefilter::Graph => return binary.search(&query_vector.0, max_search, num_candidates),
+                BinaryPrefilter::Off => (),
+            }
+        }
 
-        if self.is_dirty() {
-            self.reload();
-            self.clean_dirty();
+        match &served.product_quantization {
+            Some(pq) if self.scan_codes => pq.search(&query_vector.0, num_candidates),
+            _ => served.index.search(query_vector, max_search, num_candidates),
         }
+    }
+
+    /// Returns the closest documents to the query in the vector space `space`, scored with the
+    /// metric of the space.
+    pub fn search_space(&self, space: &str, query_vector: &[f32]) -> Result<Vec<(usize, f32)>, VectorError> {
+        debug!("Search in space {}", space);
+        let served = self.refresh()?;
+        let raw_results = self.space_candidates(&served, space, query_vector)?;
+        self.resolve(&served, raw_results, self.num_neighbors)
+    }
+
+    /// Searches several vector spaces and fuses their results.
+    ///
+    /// The score of a document is the weighted sum of its distances in each space. Documents that
+    /// a space didn't return count with the largest distance returned by that space. Spaces with
+    /// different metrics have different scales, which the weights can compensate.
+    pub fn search_fused(&self, queries: &[SpaceQuery]) -> Result<Vec<(usize, f32)>, VectorError> {
+        debug!("Fused search in {} spaces", queries.len());
+        let served = self.refresh()?;
+
+        let mut per_space = Vec::with_capacity(queries.len());
+        for query in queries {
+            let raw_results = self.space_candidates(&served, query.space, query.vector)?;
+            let mut distances: HashMap<usize, f32> = HashMap::new();
+            for (doc_id, distance) in self.resolve(&served, raw_results, usize::MAX)? {
+                let best = distances.entry(doc_id).or_insert(distance);
+                *best = best.min(distance);
+            }
+            let worst = distances.values().copied().reduce(f32::max).unwrap_or(0.0);
+            per_space.push((query.weight, distances, worst));
+        }
+
+        let doc_ids: HashSet<usize> = per_space.iter().flat_map(|(_, distances, _)| distances.keys().copied()).collect();
+        let mut results: Vec<_> = doc_ids
+            .into_iter()
+            .map(|doc_id| {
+                let score = per_space
+                    .iter()
+                    .map(|(weight, distances, worst)| weight * distances.get(&doc_id).unwrap_or(worst))
+                    .sum::<f32>();
+                (doc_id, score)
+            })
+            .collect();
+        results.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        results.truncate(self.num_neighbors);
+
+        Ok(results)
+    }
+
+    /// Candidates for the query in the vector space `space`, with their vector ids.
+    fn space_candidates(&self, served: &Served, space: &str, query_vector: &[f32]) -> Result<Vec<(usize, f32)>, VectorError> {
+        let space = served.spaces.get(space).ok_or_else(|| VectorError::UnknownSpace(space.to_string()))?;
+        // Truncation is meant for the dimension of the main space, as for the pushed vectors.
+        let ingest = IngestConfig {
+            truncate: None,
+            ..self.ingest
+        };
+        let query_vector = ingest.apply(query_vector)?;
+        space.schema.check(&query_vector)?;
+
+        let query_vector = elements::vector_for(space.schema.metric, query_vector);
+        let num_candidates = self.num_candidates();
+        let raw_results = space
+            .index
+            .search(&query_vector, self.max_search.max(num_candidates), num_candidates)
+            .into_iter()
+            .map(|(position, score)| (spaces::vec_id(space.id, position), score))
+            .collect();
+        Ok(raw_results)
+    }
+
+    /// Names of the namespaces of the index.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.refresh_or_served().namespace_registry.iter().map(|(name, _)| name.to_string()).collect()
+    }
+
+    /// Returns the closest documents to the query among the ones of the namespace `namespace`,
+    /// with their ids in the namespace.
+    ///
+    /// Namespaces without a graph are scanned by brute force.
+    pub fn search_namespace(
+        &self,
+        namespace: &str,
+        query_vector: &Vector<'static>,
+    ) -> Result<Vec<(usize, f32)>, VectorError> {
+        debug!("Search in namespace {}", namespace);
+        let served = self.refresh()?;
+        let query_vector = &self.prepare_query(&served, query_vector)?;
+        let namespace = self.load_namespace(&served, namespace)?;
+
+        let raw_results = match &namespace.index {
+            Some(index) => {
+                let num_candidates = self.num_candidates();
+                index.search(query_vector, self.max_search.max(num_candidates), num_candidates)
+            }
+            None => {
+                let elements = &namespace.elements;
+                let mut raw_results: Vec<_> = (0..elements.len())
+                    .map(|idx| (idx, elements.dist_to_element(idx, query_vector).into_inner()))
+                    .collect();
+                raw_results.sort_by(|a, b| a.1.total_cmp(&b.1));
+                raw_results
+            }
+        };
+        let raw_results = raw_results
+            .into_iter()
+            .map(|(position, score)| (namespaces::vec_id(namespace.id, position), score))
+            .collect();
+
+        let results = self.resolve(&served, raw_results, self.num_neighbors)?;
+        Ok(results.into_iter().map(|(doc_key, score)| (namespaces::doc_id(doc_key), score)).collect())
+    }
+
+    /// Counts the documents and vectors of the namespace `namespace` in the generation served by
+    /// the reader.
+    pub fn namespace_stats(&self, namespace: &str) -> Result<NamespaceStats, VectorError> {
+        let served = self.refresh()?;
+        let namespace = self.load_namespace(&served, namespace)?;
 
-        let raw_results =
-            self.index
-                .borrow()
-                .search(query_vector, self.max_search, self.num_neighbors);
+        let first = namespaces::vec_id(namespace.id, 0);
+        let committed = first..first + namespace.elements.len();
+        let tombstones = &served.tombstones;
+
+        let mut live_docs: Vec<usize> = self
+            .index_map
+            .doc_ids_in_range(namespaces::doc_keys(namespace.id))?
+            .into_iter()
+            .filter(|(_, vec_id)| committed.contains(vec_id) && !tombstones.contains(*vec_id))
+            .map(|(doc_key, _)| doc_key)
+            .collect();
+        live_docs.dedup();
+
+        Ok(NamespaceStats {
+            documents: live_docs.len(),
+            vectors: committed.len(),
+            deleted: tombstones.count_in(committed),
+            graph: namespace.index.is_some(),
+        })
+    }
+
+    /// Searches only among the vectors whose metadata matches `filter`.
+    ///
+    /// Selective filters scan the matching vectors by brute force, loose filters over-fetch from
+    /// the graph and discard the results that don't match.
+    pub fn search_filtered(
+        &self,
+        query_vector: &Vector<'static>,
+        filter: &Filter,
+    ) -> Result<Vec<(usize, f32)>, VectorError> {
+        debug!("Search for vector with filter {:?}", filter);
+        let served = self.refresh()?;
+        let query_vector = &self.prepare_query(&served, query_vector)?;
+
+        let index = &served.index;
+        let universe = index.len();
+        if universe == 0 {
+            return Ok(Vec::new());
+        }
+
+        let matches = self.metadata.matching(filter)?;
+        let num_matches = matches.len(universe);
+        let selectivity = num_matches as f32 / universe as f32;
+        trace!("Filter matches {} of {} vectors", num_matches, universe);
+
+        let raw_results = if selectivity < BRUTE_FORCE_SELECTIVITY {
+            let elements = index.get_elements();
+            let candidates: Vec<usize> = match &matches {
+                Matches::Only(idxs) => idxs.iter().copied().filter(|idx| *idx < universe).collect(),
+                // A negation excluding almost everything: the live vectors it doesn't exclude.
+                Matches::AllBut(excluded) => {
+                    let tombstones = &served.tombstones;
+                    (0..universe).filter(|idx| !excluded.contains(idx) && !tombstones.contains(*idx)).collect()
+                }
+            };
+            let mut raw_results: Vec<_> = candidates
+                .into_iter()
+                .map(|idx| (idx, elements.dist_to_element(idx, query_vector).into_inner()))
+                .collect();
+            raw_results.sort_by(|a, b| a.1.total_cmp(&b.1));
+            raw_results
+        } else {
+            let num_results = ((self.num_candidates() as f32 / selectivity).ceil() as usize).min(universe);
+            let max_search = self.max_search.max(num_results);
+            index
+                .search(query_vector, max_search, num_results)
+                .into_iter()
+                .filter(|(idx, _score)| matches.contains(*idx))
+                .collect()
+        };
+        let raw_results = self.rerank(&served, raw_results, query_vector);
+
+        self.resolve(&served, raw_results, self.num_neighbors)
+    }
+
+    fn num_candidates(&self) -> usize {
+        self.rerank_depth.max(self.num_neighbors)
+    }
+
+    /// Recomputes the scores of the candidates with the stored vectors and sorts them. The
+    /// full-precision vectors are used when the index keeps them, so quantized indexes are ordered
+    /// by their exact distance.
+    fn rerank(&self, served: &Served, mut raw_results: Vec<(usize, f32)>, query_vector: &Vector) -> Vec<(usize, f32)> {
+        let elements = served.full_precision.as_ref().unwrap_or_else(|| served.index.get_elements());
+
+        for (idx, score) in raw_results.iter_mut() {
+            *score = elements.distance(*idx, &query_vector.0);
+        }
+        raw_results.sort_by(|a, b| a.1.total_cmp(&b.1));
+        raw_results
+    }
+
+    /// Removes the deleted vectors and the ones of expired documents, and maps the best `limit`
+    /// remaining ones to their documents.
+    fn resolve(&self, served: &Served, raw_results: Vec<(usize, f32)>, limit: usize) -> Result<Vec<(usize, f32)>, VectorError> {
         let idxs: Vec<usize> = raw_results.iter().map(|(idx, _score)| *idx).collect();
-        let idxs = self.deleted.filter(&idxs).unwrap();
+        let idxs = served.tombstones.filter(&idxs);
+
+        let mut expired = self.expired.lock().unwrap();
+        if let Err(e) = expired.update(&self.expiry) {
+            error!("Error reading the expired documents: {}", e);
+        }
 
         let raw_results: HashMap<usize, f32> = raw_results.into_iter().collect();
 
-        idxs.into_iter()
-            .map(|idx| {
-                let score = raw_results.get(&idx).unwrap();
-                (self.index_map.get_doc_id(idx).unwrap(), *score)
-            })
-            .collect()
+        let mut results = Vec::new();
+        for idx in idxs {
+            if results.len() == limit {
+                break;
+            }
+            let doc_id = match self.index_map.get_doc_id(idx) {
+                Ok(doc_id) => doc_id,
+                // Vectors of a namespace deleted after this generation no longer map to a document.
+                Err(lmdb::Error::Code(lmdb::error::NOTFOUND)) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if !expired.contains(doc_id) {
+                results.push((doc_id, raw_results[&idx]));
+            }
+        }
+        Ok(results)
+    }
+
+    /// Searches with the sum of the embeddings of `terms`, in an index with
+    /// `Storage::SumEmbeddings`.
+    pub fn search_terms(&self, terms: &[usize]) -> Result<Vec<(usize, f32)>, VectorError> {
+        let served = self.refresh()?;
+        let query_vector = served.index.get_elements().embed(terms)?;
+        Reader::check_dimension(&served, &query_vector)?;
+        self.search_prepared(&served, &query_vector)
     }
 
-    pub fn search_vec(&self, query_vector: Vec<f32>) -> Vec<(usize, f32)> {
-        let query_vector = Vector::from_iter(query_vector.into_iter());
-        self.search(&query_vector)
+    pub fn search_vec(&self, query_vector: Vec<f32>) -> Result<Vec<(usize, f32)>, VectorError> {
+        let metric = self.schema().map(|schema| schema.metric).unwrap_or_default();
+        self.search(&elements::vector_for(metric, query_vector))
     }
 
-    fn load_index<T: Into<PathBuf>>(index_path: T, elements_path: T) -> Result<Granne<'a, Vectors<'a>>, io::Error> {
+    /// Loads the index with the metric and storage of `layout`, the schema of the index.
+    fn load_index(location: &Location, layout: Schema) -> Result<Granne<'a, Elements<'a>>, io::Error> {
         debug!("Loading (memory-mapping) index and vectors.");
-        let index_file = std::fs::File::open(index_path.into())?;
-        let elements_file = std::fs::File::open(elements_path.into())?;
+        let index_file = std::fs::File::open(location.index_path())?;
+        let elements_file = std::fs::File::open(location.elements_path())?;
 
-        let elements = unsafe { angular::Vectors::from_file(&elements_file)? };
-        Ok(unsafe { Granne::from_file(&index_file, elements.clone()).unwrap() })
+        let elements = match layout.storage {
+            Storage::SumEmbeddings => {
+                let embeddings_file = std::fs::File::open(location.embeddings_path())?;
+                unsafe { Elements::sum_embeddings_from_files(&embeddings_file, Some(&elements_file), layout.metric)? }
+            }
+            storage => unsafe { Elements::from_file(&elements_file, layout.metric, storage)? },
+        };
+        Ok(unsafe { Granne::from_file(&index_file, elements).unwrap() })
     }
 
-    pub fn is_dirty(&self) -> bool {
-        self.location.dirty_path().exists()
+    /// Indexes last committed before the tombstones had a file of their own only have them in the
+    /// deleted database, with the deletions made since the commit.
+    fn load_tombstones(location: &Location, deleted: &DeletedDBReader) -> Result<Tombstones, VectorError> {
+        match location.tombstones_path().exists() {
+            true => Ok(Tombstones::load(&location.tombstones_path())?),
+            false => Ok(deleted.tombstones()?),
+        }
     }
 
-    fn clean_dirty(&self) {
-        match std::fs::remove_file(self.location.dirty_path()) {
-            Ok(_) => debug!("Cleaned dirty file"),
-            Err(_) => trace!(
-                "Remove ignored, {:?} doesn't exist",
-                self.location.dirty_path()
-            ),
+    fn load_full_precision(location: &Location, layout: Schema) -> Result<Option<Elements<'a>>, io::Error> {
+        if !layout.full_precision {
+            return Ok(None);
         }
+
+        debug!("Loading (memory-mapping) full-precision vectors.");
+        let file = std::fs::File::open(location.full_precision_path())?;
+        Ok(Some(unsafe { Elements::from_file(&file, layout.metric, Storage::F32)? }))
     }
 
-    fn reload(&self) {
-        debug!("Reloading!");
+    fn load_product_quantization(
+        location: &Location,
+        layout: Schema,
+    ) -> Result<Option<ProductQuantization>, VectorError> {
+        match layout.product_quantization {
+            Some(_) => ProductQuantization::load(&location.pq_path()),
+            None => Ok(None),
+        }
+    }
+
+    fn load_binary_index(location: &Location, layout: Schema) -> Result<Option<BinaryIndex>, VectorError> {
+        match layout.binary_quantization {
+            true => BinaryIndex::load(&location.binary_path(), &location.binary_index_path()),
+            false => Ok(None),
+        }
+    }
+
+    fn load_spaces(location: &Location) -> Result<BTreeMap<String, Space<'a>>, VectorError> {
+        let mut spaces = BTreeMap::new();
+        for (name, id) in SpaceRegistry::load(&location.space_registry_path())?.iter() {
+            let location = spaces::space_location(location, name);
+            let schema = Schema::load(&location.schema_path())?
+                .ok_or_else(|| VectorError::Schema(format!("space {} has no schema", name)))?;
+            let index = Reader::load_index(&location, schema)?;
+            spaces.insert(name.to_string(), Space { id, index, schema });
+        }
+        Ok(spaces)
+    }
+
+    /// Memory-maps the files of the namespace `name` if no search of the served generation did.
+    ///
+    /// The files are mapped outside of the cache lock, concurrent searches of a namespace that
+    /// isn't loaded may both map it.
+    fn load_namespace(&self, served: &Served<'a>, name: &str) -> Result<Arc<Namespace<'a>>, VectorError> {
+        if let Some(namespace) = served.namespaces.lock().unwrap().get(name) {
+            return Ok(namespace.clone());
+        }
+        let id = served.namespace_registry.get(name).ok_or_else(|| VectorError::UnknownNamespace(name.to_string()))?;
 
+        debug!("Loading (memory-mapping) namespace {}.", name);
+        let location = namespaces::namespace_location(&self.location, name);
+        let layout = served.schema.unwrap_or_else(|| Schema::new(0));
         self.commit_lock.lock();
-        self.index.replace(Reader::load_index(
-            self.location.index_path(),
-            self.location.elements_path(),
-        ).unwrap());
+        let loaded = (|| -> io::Result<Namespace<'a>> {
+            let elements_file = std::fs::File::open(location.elements_path())?;
+            let elements = unsafe { Elements::from_file(&elements_file, layout.metric, layout.storage)? };
+            let index = match std::fs::File::open(location.index_path()) {
+                Ok(index_file) => Some(unsafe { Granne::from_file(&index_file, elements.clone())? }),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            Ok(Namespace { id, elements, index })
+        })();
         self.commit_lock.unlock();
+
+        let namespace = Arc::new(loaded?);
+        served.namespaces.lock().unwrap().put(name.to_string(), namespace.clone());
+        Ok(namespace)
+    }
+
+    /// Reloads the index if a commit published another generation, and returns the one to serve.
+    /// Every reader checks the generation file on its own, so readers of the same index don't
+    /// miss each other's reloads. A reload that fails leaves the previous generation served and
+    /// is retried by the next call.
+    fn refresh(&self) -> Result<Arc<Served<'a>>, VectorError> {
+        let served = self.served();
+        if !self.is_dirty_for(&served)? {
+            return Ok(served);
+        }
+        self.reload()
+    }
+
+    /// `refresh` for the accessors that can't fail, which keep the generation served on errors.
+    fn refresh_or_served(&self) -> Arc<Served<'a>> {
+        self.refresh().unwrap_or_else(|e| {
+            error!("Error reloading {:?}: {}", self.location, e);
+            self.served()
+        })
+    }
+
+    /// Whether a commit published a generation the reader doesn't serve yet.
+    pub fn is_dirty(&self) -> bool {
+        self.is_dirty_for(&self.served()).unwrap_or(true)
+    }
+
+    fn is_dirty_for(&self, served: &Served) -> Result<bool, VectorError> {
+        Ok(commit::load_generation(&self.location.generation_path())? != served.generation)
     }
-}
 
-unsafe impl Send for Reader<'_> {}
-unsafe impl Sync for Reader<'_> {}
\ No newline at end of file
+    fn reload(&self) -> Result<Arc<Served<'a>>, VectorError> {
+        debug!("Reloading!");
+
+        let served = Arc::new(Reader::load(&self.location, &self.commit_lock, &self.deleted, self.max_namespaces)?);
+        *self.served.write().unwrap() = served.clone();
+        Ok(served)
+    }
+}
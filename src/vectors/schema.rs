@@ -0,0 +1,235 @@
+use std::{fs::File, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{HalfFormat, InvalidVector, PqConfig, VectorError};
+
+/// Distance used to compare the vectors of an index.
+///
+/// The scores returned by searches are these distances, so lower is always closer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    /// `max(0, 1 - a·b)`, which is `1 - cos(a, b)` in `[0, 2]` for normalized vectors.
+    #[default]
+    Angular,
+    /// `-a·b`, the inner product with the sign flipped. Unbounded.
+    DotProduct,
+    /// `|a - b|`, the L2 distance. Non-negative.
+    Euclidean,
+}
+
+impl Metric {
+    pub fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        self.distance_between(a.iter().copied().zip(b.iter().copied()))
+    }
+
+    /// Distance between two vectors given as the pairs of their components, for vectors that are
+    /// decoded as they are read.
+    pub fn distance_between<I: Iterator<Item = (f32, f32)>>(&self, components: I) -> f32 {
+        match self {
+            Metric::Angular => (1.0 - components.map(|(x, y)| x * y).sum::<f32>()).max(0.0),
+            Metric::DotProduct => -components.map(|(x, y)| x * y).sum::<f32>(),
+            Metric::Euclidean => components.map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt(),
+        }
+    }
+}
+
+/// Format of the vectors in the elements file of an index.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Storage {
+    #[default]
+    F32,
+    /// Vectors quantized to `i8` with `angular_int`, a quarter of the size of `F32`. Only for the
+    /// angular metric.
+    Int8,
+    /// IEEE half-precision components, half the size of `F32`. Vectors with components beyond
+    /// ±65504 are rejected.
+    F16,
+    /// bfloat16 components, half the size of `F32` with the range of `f32` but less precision
+    /// than `F16`.
+    Bf16,
+    /// Vectors stored as the ids of the terms they are the sum of, with a table of term
+    /// embeddings shared by the index. Only for the angular metric.
+    SumEmbeddings,
+}
+
+impl Storage {
+    /// Checks that the storage can represent the components of `vector`, which only fails for
+    /// components beyond the range of `F16`.
+    pub fn check(&self, vector: &[f32]) -> Result<(), InvalidVector> {
+        match self {
+            Storage::F16 => HalfFormat::F16.check(vector),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Properties of an index fixed when it is created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schema {
+    pub dimension: usize,
+    pub metric: Metric,
+    #[serde(default)]
+    pub storage: Storage,
+    /// Whether a full-precision copy of quantized vectors is kept for exact reranking.
+    #[serde(default)]
+    pub full_precision: bool,
+    /// Product quantizer whose codes are updated on every commit, see `ProductQuantization`.
+    #[serde(default)]
+    pub product_quantization: Option<PqConfig>,
+    /// Whether the sign bits of the vectors are stored with a graph over them, see `BinaryIndex`.
+    #[serde(default)]
+    pub binary_quantization: bool,
+}
+
+impl Schema {
+    pub fn new(dimension: usize) -> Self {
+        Schema::with_metric(dimension, Metric::default())
+    }
+
+    pub fn with_metric(dimension: usize, metric: Metric) -> Self {
+        Schema {
+            dimension,
+            metric,
+            storage: Storage::default(),
+            full_precision: false,
+            product_quantization: None,
+            binary_quantization: false,
+        }
+    }
+
+    pub fn storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    pub fn full_precision(mut self, full_precision: bool) -> Self {
+        self.full_precision = full_precision;
+        self
+    }
+
+    pub fn product_quantization(mut self, product_quantization: Option<PqConfig>) -> Self {
+        self.product_quantization = product_quantization;
+        self
+    }
+
+    pub fn binary_quantization(mut self, binary_quantization: bool) -> Self {
+        self.binary_quantization = binary_quantization;
+        self
+    }
+
+    /// Checks that the metric can be used with the storage and the quantizer parameters.
+    pub fn validate(&self) -> Result<(), VectorError> {
+        if let Some(pq) = &self.product_quantization {
+            pq.validate()?;
+        }
+        match (self.storage, self.metric) {
+            (Storage::Int8 | Storage::SumEmbeddings, Metric::DotProduct | Metric::Euclidean) => {
+                Err(VectorError::Schema(format!(
+                    "{:?} storage only supports the angular metric, not {:?}",
+                    self.storage, self.metric
+                )))
+            }
+            (Storage::F32, _) if self.full_precision => {
+                let message = "F32 vectors are already full precision, they have no full-precision copy";
+                Err(VectorError::Schema(message.to_string()))
+            }
+            (_, Metric::DotProduct | Metric::Euclidean) if self.binary_quantization => {
+                let message = "sign codes only approximate the angular metric";
+                Err(VectorError::Schema(message.to_string()))
+            }
+            (Storage::SumEmbeddings, _) if self.full_precision => {
+                let message = "sums of embeddings are computed exactly, they have no full-precision copy";
+                Err(VectorError::Schema(message.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Loads the schema stored at `path`, if there is one.
+    pub fn load(path: &Path) -> Result<Option<Schema>, VectorError> {
+        match File::open(path) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file))
+                .map(Some)
+                .map_err(|e| VectorError::Schema(format!("{:?}: {}", path, e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save<W: io::Write>(&self, writer: W) -> Result<(), VectorError> {
+        serde_json::to_writer_pretty(writer, self).map_err(|e| VectorError::Schema(e.to_string()))
+    }
+
+    /// Checks that `vector` has the dimension of the index.
+    pub fn check(&self, vector: &[f32]) -> Result<(), VectorError> {
+        if vector.len() == self.dimension {
+            Ok(())
+        } else {
+            Err(VectorError::DimensionMismatch {
+                expected: self.dimension,
+                found: vector.len(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::NamedTempFile;
+
+    use super::{Metric, Schema, Storage};
+    use crate::vectors::PqConfig;
+    use crate::vectors::VectorError;
+
+    #[test]
+    fn save_and_load() {
+        let schema = Schema::new(300);
+
+        let file = NamedTempFile::new().unwrap();
+        schema.save(file.as_file()).unwrap();
+
+        let loaded = Schema::load(file.path()).unwrap().unwrap();
+        assert_eq!(loaded, schema);
+        assert_eq!(loaded.metric, Metric::Angular);
+        assert!(Schema::load(&file.path().with_extension("missing")).unwrap().is_none());
+
+        let schema = Schema::with_metric(3, Metric::DotProduct).storage(Storage::Bf16);
+        let file = NamedTempFile::new().unwrap();
+        schema.save(file.as_file()).unwrap();
+        let json = std::fs::read_to_string(file.path()).unwrap();
+        assert!(json.contains("\"dot_product\"") && json.contains("\"bf16\""));
+        assert_eq!(Schema::load(file.path()).unwrap().unwrap(), schema);
+
+        // Schemas recorded before the storage was configurable.
+        std::fs::write(file.path(), r#"{"dimension": 3, "metric": "angular"}"#).unwrap();
+        assert_eq!(Schema::load(file.path()).unwrap().unwrap(), Schema::new(3));
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Schema::new(3).storage(Storage::Int8).validate().is_ok());
+        assert!(Schema::with_metric(3, Metric::Euclidean).validate().is_ok());
+        assert!(Schema::with_metric(3, Metric::Euclidean).storage(Storage::Int8).validate().is_err());
+        assert!(Schema::new(3).product_quantization(Some(PqConfig::new(0))).validate().is_err());
+        assert!(Schema::new(3).storage(Storage::SumEmbeddings).validate().is_ok());
+        assert!(Schema::new(3).storage(Storage::SumEmbeddings).full_precision(true).validate().is_err());
+        assert!(Schema::new(3).full_precision(true).validate().is_err());
+        assert!(Schema::new(3).storage(Storage::Int8).full_precision(true).validate().is_ok());
+        assert!(Schema::new(3).binary_quantization(true).validate().is_ok());
+        assert!(Schema::with_metric(3, Metric::DotProduct).binary_quantization(true).validate().is_err());
+    }
+
+    #[test]
+    fn check() {
+        let schema = Schema::new(3);
+
+        assert!(schema.check(&[1.0, 2.0, 3.0]).is_ok());
+        assert!(matches!(
+            schema.check(&[1.0, 2.0]),
+            Err(VectorError::DimensionMismatch { expected: 3, found: 2 })
+        ));
+    }
+}
@@ -0,0 +1,491 @@
+//! Indexes split in shards, each a complete index with its own graph.
+//!
+//! Documents are routed to a shard by a hash of their id, so all the vectors of a document live
+//! in the same shard and searches merge the results of the shards without duplicates. The shards
+//! of a root are those of a generation, `gen-<generation>/shard-<n>`, recorded in `shards.json`.
+//! Rebalancing builds the shards of the next generation and switches to them by replacing that
+//! file.
+
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use granne::angular::Vector;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use super::{elements, Filter, Metadata, Reader, ReaderConfig, Schema, Storage, VectorError, Writer, WriterConfig};
+
+const SHARD_LAYOUT_PATH: &str = "shards.json";
+
+/// Shard of the documents with id `doc_id` among `num_shards`.
+///
+/// The hash is fixed, unlike the ones of the standard library, so documents stay in their shard
+/// across releases.
+pub fn shard_of(doc_id: usize, num_shards: usize) -> usize {
+    // splitmix64 finalizer
+    let mut x = (doc_id as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    (x % num_shards as u64) as usize
+}
+
+/// Merges the results of the shards into the best `k`, in increasing order of distance.
+fn merge(results: Vec<Vec<(usize, f32)>>, k: usize) -> Vec<(usize, f32)> {
+    let mut merged: Vec<_> = results.into_iter().flatten().collect();
+    merged.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+    merged.truncate(k);
+    merged
+}
+
+/// Generation and number of the shards of a root, stored in `shards.json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct ShardLayout {
+    generation: u64,
+    shards: usize,
+}
+
+impl ShardLayout {
+    fn load(root: &Path) -> Result<Option<Self>, VectorError> {
+        let path = root.join(SHARD_LAYOUT_PATH);
+        match File::open(&path) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file))
+                .map(Some)
+                .map_err(|e| VectorError::Schema(format!("{:?}: {}", path, e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the layout of `root` at once, readers see the old or the new one.
+    fn save(&self, root: &Path) -> Result<(), VectorError> {
+        let tmpfile = NamedTempFile::new_in(root)?;
+        serde_json::to_writer_pretty(tmpfile.as_file(), self).map_err(|e| VectorError::Schema(e.to_string()))?;
+        tmpfile.persist(root.join(SHARD_LAYOUT_PATH)).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// Modification time of the layout of `root`, `None` if it can't be read.
+    fn modified(root: &Path) -> Option<SystemTime> {
+        std::fs::metadata(root.join(SHARD_LAYOUT_PATH)).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    fn generation_path(&self, root: &Path) -> PathBuf {
+        root.join(format!("gen-{}", self.generation))
+    }
+
+    fn shard_paths(&self, root: &Path) -> Vec<PathBuf> {
+        let generation = self.generation_path(root);
+        (0..self.shards).map(|shard| generation.join(format!("shard-{:04}", shard))).collect()
+    }
+}
+
+/// Config of the shards of a new generation, with the layout of the index being rebalanced.
+fn generation_config(config: WriterConfig, schema: Option<Schema>) -> WriterConfig {
+    match schema {
+        Some(schema) => WriterConfig {
+            dimension: Some(schema.dimension),
+            metric: Some(schema.metric),
+            storage: Some(schema.storage),
+            full_precision: schema.full_precision,
+            product_quantization: schema.product_quantization,
+            binary_quantization: schema.binary_quantization,
+            ..config
+        },
+        None => config,
+    }
+}
+
+/// Writes an index split in shards, routing every document to its shard.
+pub struct ShardedWriter<'a> {
+    root: PathBuf,
+    layout: ShardLayout,
+    config: WriterConfig,
+    shards: Vec<Writer<'a>>,
+}
+
+impl<'a> ShardedWriter<'a> {
+    pub fn open<T: Into<PathBuf>>(root: T, num_shards: usize) -> Result<Self, VectorError> {
+        ShardedWriter::open_with_config(root, num_shards, WriterConfig::default())
+    }
+
+    /// Opens the sharded index at `root`, created with `num_shards` shards if it doesn't exist.
+    /// Opening an index with another number of shards fails, see `rebalance`.
+    pub fn open_with_config<T: Into<PathBuf>>(
+        root: T,
+        num_shards: usize,
+        config: WriterConfig,
+    ) -> Result<Self, VectorError> {
+        let root = root.into();
+        if num_shards == 0 {
+            return Err(VectorError::Schema("a sharded index needs at least one shard".to_string()));
+        }
+        std::fs::create_dir_all(&root)?;
+
+        let layout = match ShardLayout::load(&root)? {
+            Some(layout) if layout.shards != num_shards => {
+                let message = format!("the index has {} shards, not {}", layout.shards, num_shards);
+                return Err(VectorError::Schema(message));
+            }
+            Some(layout) => layout,
+            None => {
+                let layout = ShardLayout {
+                    generation: 0,
+                    shards: num_shards,
+                };
+                layout.save(&root)?;
+                layout
+            }
+        };
+
+        let shards = layout
+            .shard_paths(&root)
+            .into_iter()
+            .map(|path| Writer::open_with_config(path, config))
+            .collect::<Result<_, _>>()?;
+
+        Ok(ShardedWriter {
+            root,
+            layout,
+            config,
+            shards,
+        })
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Schema of the index, the one of the first shard that has one.
+    pub fn schema(&self) -> Option<Schema> {
+        self.shards.iter().find_map(|shard| shard.schema())
+    }
+
+    fn shard(&mut self, doc_id: usize) -> &mut Writer<'a> {
+        let shard = shard_of(doc_id, self.shards.len());
+        &mut self.shards[shard]
+    }
+
+    pub fn push(&mut self, doc_id: usize, vector: &Vector) -> Result<(), VectorError> {
+        self.shard(doc_id).push(doc_id, vector)
+    }
+
+    pub fn push_with_metadata(&mut self, doc_id: usize, vector: &Vector, metadata: &Metadata) -> Result<(), VectorError> {
+        self.shard(doc_id).push_with_metadata(doc_id, vector, metadata)
+    }
+
+    pub fn push_vec(&mut self, doc_id: usize, vector: Vec<f32>) -> Result<(), VectorError> {
+        self.shard(doc_id).push_vec(doc_id, vector)
+    }
+
+    pub fn delete(&mut self, doc_id: usize) -> Result<(), VectorError> {
+        self.shard(doc_id).delete(doc_id)
+    }
+
+    pub fn set_expiry(&mut self, doc_id: usize, expires_at: SystemTime) -> Result<(), VectorError> {
+        self.shard(doc_id).set_expiry(doc_id, expires_at)
+    }
+
+    /// Commits all the shards, building their graphs in parallel. Fails if any of them fails; the
+    /// writes of the shards that didn't commit are pending again.
+    pub fn commit(&mut self) -> Result<(), VectorError> {
+        self.shards.par_iter_mut().map(|shard| shard.commit()).collect::<Result<Vec<_>, _>>()?;
+        Ok(())
+    }
+
+    /// Moves the documents to `num_shards` new shards and commits them.
+    ///
+    /// The vectors that are not deleted are copied with their metadata and expiry times, the
+    /// full-precision ones when the index keeps them. Readers switch to the new shards on their
+    /// next search, and the old ones are removed, once all the new shards are committed. If any
+    /// of them fails, the new shards are removed and the index keeps the old ones.
+    ///
+    /// Only the main space is moved: indexes with vector spaces, namespaces or
+    /// `Storage::SumEmbeddings` can't be rebalanced.
+    pub fn rebalance(&mut self, num_shards: usize) -> Result<(), VectorError> {
+        if num_shards == 0 {
+            return Err(VectorError::Schema("a sharded index needs at least one shard".to_string()));
+        }
+        if self.schema().is_some_and(|schema| schema.storage == Storage::SumEmbeddings) {
+            return Err(VectorError::Schema("an index of summed embeddings can't be rebalanced".to_string()));
+        }
+        for shard in &self.shards {
+            if !shard.spaces().is_empty() || !shard.namespaces().is_empty() {
+                let message = "an index with vector spaces or namespaces can't be rebalanced".to_string();
+                return Err(VectorError::Schema(message));
+            }
+        }
+        debug!("Rebalancing {} shards into {}", self.shards.len(), num_shards);
+
+        let layout = ShardLayout {
+            generation: self.layout.generation + 1,
+            shards: num_shards,
+        };
+        // Leftovers of an interrupted rebalance.
+        if layout.generation_path(&self.root).exists() {
+            std::fs::remove_dir_all(layout.generation_path(&self.root))?;
+        }
+
+        let shards = self.fill_shards(&layout);
+        let shards = match shards {
+            Ok(shards) => shards,
+            Err(e) => {
+                if let Err(e) = std::fs::remove_dir_all(layout.generation_path(&self.root)) {
+                    error!("Error removing the shards of generation {}: {}", layout.generation, e);
+                }
+                return Err(e);
+            }
+        };
+
+        layout.save(&self.root)?;
+        let old_generation = self.layout.generation_path(&self.root);
+        self.shards = shards;
+        self.layout = layout;
+        std::fs::remove_dir_all(old_generation)?;
+        Ok(())
+    }
+
+    /// Opens the shards of `layout`, copies the documents to them and commits them.
+    fn fill_shards(&self, layout: &ShardLayout) -> Result<Vec<Writer<'a>>, VectorError> {
+        let num_shards = layout.shards;
+        let config = generation_config(self.config, self.schema());
+        let mut shards: Vec<Writer<'a>> = layout
+            .shard_paths(&self.root)
+            .into_iter()
+            .map(|path| Writer::open_with_config(path, config))
+            .collect::<Result<_, _>>()?;
+
+        for old in &self.shards {
+            old.export(|exported| {
+                let shard = &mut shards[shard_of(exported.doc_id, num_shards)];
+                let vector = Vector(exported.vector.into());
+                shard.push_with_metadata(exported.doc_id, &vector, &exported.metadata)?;
+                if let Some(expires_at) = exported.expires_at {
+                    shard.set_expiry(exported.doc_id, UNIX_EPOCH + Duration::from_secs(expires_at))?;
+                }
+                Ok(())
+            })?;
+        }
+        shards.par_iter_mut().map(|shard| shard.commit()).collect::<Result<Vec<_>, _>>()?;
+        Ok(shards)
+    }
+}
+
+/// The shards of the generation a `ShardedReader` serves.
+struct Shards<'a> {
+    layout: ShardLayout,
+    readers: Vec<Reader<'a>>,
+}
+
+/// Searches all the shards of an index in parallel and merges their results.
+pub struct ShardedReader<'a> {
+    root: PathBuf,
+    config: ReaderConfig,
+    shards: RwLock<Arc<Shards<'a>>>,
+    /// Modification time of the `shards.json` last parsed.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl<'a> ShardedReader<'a> {
+    pub fn open<T: Into<PathBuf>>(root: T) -> Result<Self, VectorError> {
+        ShardedReader::open_with_config(root, ReaderConfig::default())
+    }
+
+    /// Opens the shards of the index at `root` with `config`. Every shard returns up to
+    /// `num_neighbors` results, and the best `num_neighbors` of all of them are kept.
+    pub fn open_with_config<T: Into<PathBuf>>(root: T, config: ReaderConfig) -> Result<Self, VectorError> {
+        let root = root.into();
+        let modified = ShardLayout::modified(&root);
+        let layout = ShardLayout::load(&root)?
+            .ok_or_else(|| VectorError::Schema(format!("{:?} is not a sharded index", root)))?;
+        let readers = ShardedReader::open_shards(&root, layout, config)?;
+
+        Ok(ShardedReader {
+            root,
+            config,
+            shards: RwLock::new(Arc::new(Shards { layout, readers })),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    fn open_shards(root: &Path, layout: ShardLayout, config: ReaderConfig) -> Result<Vec<Reader<'a>>, VectorError> {
+        layout
+            .shard_paths(root)
+            .into_iter()
+            .map(|path| Reader::open_with_config(path, config))
+            .collect()
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.refresh().readers.len()
+    }
+
+    /// Switches to the shards of a new generation after a rebalance, and returns the shards to
+    /// search. `shards.json` is only parsed again when its modification time changes.
+    fn refresh(&self) -> Arc<Shards<'a>> {
+        let current = self.shards.read().unwrap().clone();
+        let modified = ShardLayout::modified(&self.root);
+        if modified == *self.modified.lock().unwrap() {
+            return current;
+        }
+
+        match ShardLayout::load(&self.root) {
+            Ok(Some(layout)) if layout != current.layout => {
+                debug!("Switching to shard generation {}", layout.generation);
+                match ShardedReader::open_shards(&self.root, layout, self.config) {
+                    Ok(readers) => {
+                        let shards = Arc::new(Shards { layout, readers });
+                        *self.shards.write().unwrap() = shards.clone();
+                        *self.modified.lock().unwrap() = modified;
+                        return shards;
+                    }
+                    Err(e) => error!("Error opening shard generation {}: {}", layout.generation, e),
+                }
+            }
+            Ok(_) => *self.modified.lock().unwrap() = modified,
+            Err(e) => error!("Error loading the shard layout: {}", e),
+        }
+        current
+    }
+
+    /// Searches every shard, see `Reader::search`, and merges their results.
+    pub fn search(&self, query_vector: &Vector<'static>) -> Result<Vec<(usize, f32)>, VectorError> {
+        let shards = self.refresh();
+        let results = shards
+            .readers
+            .par_iter()
+            .map(|shard| shard.search(query_vector))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(merge(results, self.config.num_neighbors))
+    }
+
+    pub fn search_vec(&self, query_vector: Vec<f32>) -> Result<Vec<(usize, f32)>, VectorError> {
+        let schema = self.refresh().readers.iter().find_map(|shard| shard.schema());
+        let metric = schema.map(|schema| schema.metric).unwrap_or_default();
+        self.search(&elements::vector_for(metric, query_vector))
+    }
+
+    /// Searches every shard, see `Reader::search_filtered`, and merges their results.
+    pub fn search_filtered(
+        &self,
+        query_vector: &Vector<'static>,
+        filter: &Filter,
+    ) -> Result<Vec<(usize, f32)>, VectorError> {
+        let shards = self.refresh();
+        let results = shards
+            .readers
+            .par_iter()
+            .map(|shard| shard.search_filtered(query_vector, filter))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(merge(results, self.config.num_neighbors))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use granne::angular::Vector;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use tempfile::TempDir;
+
+    use super::{merge, shard_of, ShardedReader, ShardedWriter};
+    use crate::vectors::{Filter, Metadata, Metric, VectorError, Writer};
+
+    #[test]
+    fn routing_and_merge() {
+        let counts = (0..10_000).fold([0; 4], |mut counts, doc_id| {
+            counts[shard_of(doc_id, 4)] += 1;
+            counts
+        });
+        assert!(counts.iter().all(|count| (2300..2700).contains(count)));
+        assert_eq!(shard_of(42, 4), shard_of(42, 4));
+
+        let merged = merge(vec![vec![(1, 0.1), (3, 0.5)], vec![(2, 0.2), (4, 0.5)], vec![]], 3);
+        assert_eq!(merged, [(1, 0.1), (2, 0.2), (3, 0.5)]);
+    }
+
+    #[test]
+    fn search_and_rebalance() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let vectors: Vec<Vector> = (0..300)
+            .map(|_| Vector::from((0..8).map(|_| rng.gen_range(-1.0..1.0)).collect::<Vec<f32>>()))
+            .collect();
+        let exact = |query: &Vector| {
+            let mut distances: Vec<_> = vectors
+                .iter()
+                .enumerate()
+                .map(|(doc_id, v)| (doc_id, Metric::Angular.distance(&v.0, &query.0)))
+                .collect();
+            distances.sort_by(|a, b| a.1.total_cmp(&b.1));
+            distances
+        };
+
+        let root = TempDir::new().unwrap();
+        let mut writer = ShardedWriter::open(root.path(), 3).unwrap();
+        for (doc_id, vector) in vectors.iter().enumerate() {
+            let metadata = Metadata::new().tag("parity", if doc_id % 2 == 0 { "even" } else { "odd" });
+            writer.push_with_metadata(doc_id, vector, &metadata).unwrap();
+        }
+        writer.delete(7).unwrap();
+        writer.commit().unwrap();
+        assert!(ShardedWriter::open(root.path(), 4).is_err());
+
+        let reader = ShardedReader::open(root.path()).unwrap();
+        assert_eq!(reader.num_shards(), 3);
+        let res = reader.search(&vectors[10]).unwrap();
+        assert_eq!(res.len(), 30);
+        assert_eq!(res[0].0, 10);
+        assert!(res.windows(2).all(|w| w[0].1 <= w[1].1));
+        let expected: Vec<_> = exact(&vectors[10]).into_iter().filter(|(doc_id, _)| *doc_id != 7).take(5).collect();
+        assert_eq!(res[..5].iter().map(|r| r.0).collect::<Vec<_>>(), expected.iter().map(|r| r.0).collect::<Vec<_>>());
+        assert!(reader.search(&vectors[7]).unwrap().iter().all(|(doc_id, _)| *doc_id != 7));
+
+        // Deletions and metadata survive a rebalance.
+        writer.rebalance(5).unwrap();
+        assert!(!root.path().join("gen-0").exists());
+        assert_eq!(reader.num_shards(), 5);
+        assert_eq!(reader.search(&vectors[10]).unwrap(), res);
+        let odd = reader.search_filtered(&vectors[10], &Filter::tag("parity", "odd")).unwrap();
+        assert!(!odd.is_empty() && odd.iter().all(|(doc_id, _)| doc_id % 2 == 1));
+
+        writer.push(1000, &vectors[0]).unwrap();
+        writer.commit().unwrap();
+        drop(writer);
+        let writer = ShardedWriter::open(root.path(), 5).unwrap();
+        assert_eq!(writer.num_shards(), 5);
+        assert!(reader.search(&vectors[0]).unwrap()[..2].iter().any(|(doc_id, _)| *doc_id == 1000));
+    }
+
+    #[test]
+    fn rebalance_main_space_only() {
+        let root = TempDir::new().unwrap();
+        let mut writer = ShardedWriter::open(root.path(), 2).unwrap();
+        writer.push_vec(1, vec![1.0, 0.0]).unwrap();
+        writer.commit().unwrap();
+        drop(writer);
+
+        let shard = root.path().join("gen-0").join("shard-0000");
+        let mut shard = Writer::open(shard).unwrap();
+        shard.push_to_namespace("tenant", 1, &Vector::from(vec![0.0, 1.0])).unwrap();
+        shard.commit().unwrap();
+        drop(shard);
+
+        let mut writer = ShardedWriter::open(root.path(), 2).unwrap();
+        assert!(matches!(writer.rebalance(3), Err(VectorError::Schema(_))));
+        assert!(root.path().join("gen-0").exists());
+        assert!(!root.path().join("gen-1").exists());
+
+        // Searches from several threads share the reader.
+        let reader = ShardedReader::open(root.path()).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| assert_eq!(reader.search_vec(vec![1.0, 0.0]).unwrap()[0].0, 1));
+            }
+        });
+    }
+}
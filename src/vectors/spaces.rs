@@ -0,0 +1,163 @@
+//! Named vector spaces hosted by an index next to its main space.
+//!
+//! Each space has its own vectors, graph and schema under `spaces/<name>/`, while the id map,
+//! the metadata and the tombstones of the index are shared. Vectors of a space get ids in a range
+//! of their own, `space_id << SPACE_SHIFT` onwards, so they never collide with the ids of the
+//! main space (`0`) or of other spaces.
+
+use std::{collections::BTreeMap, fs::File, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use super::{directory::Location, Schema, Storage, VectorError};
+
+/// Bits of a vector id used for its position in its space.
+const SPACE_SHIFT: u32 = 40;
+
+/// Id of the vector at `position` in the space `space_id`.
+pub const fn vec_id(space_id: u64, position: usize) -> usize {
+    ((space_id << SPACE_SHIFT) as usize) | position
+}
+
+/// Position in its space of the vector `vec_id`.
+pub fn position(vec_id: usize) -> usize {
+    vec_id & ((1 << SPACE_SHIFT) - 1)
+}
+
+/// Names of the spaces of an index and their ids, stored in `spaces.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpaceRegistry(BTreeMap<String, u64>);
+
+impl SpaceRegistry {
+    /// Loads the registry stored at `path`, empty if there is none.
+    pub fn load(path: &Path) -> Result<Self, VectorError> {
+        match File::open(path) {
+            Ok(file) => serde_json::from_reader(io::BufReader::new(file))
+                .map_err(|e| VectorError::Schema(format!("{:?}: {}", path, e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(SpaceRegistry::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save<W: io::Write>(&self, writer: W) -> Result<(), VectorError> {
+        serde_json::to_writer_pretty(writer, self).map_err(|e| VectorError::Schema(e.to_string()))
+    }
+
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.0.get(name).copied()
+    }
+
+    /// Registers `name` with the next free id, `1` for the first space, and returns it.
+    pub fn add(&mut self, name: &str) -> u64 {
+        let id = self.0.values().max().map_or(1, |id| id + 1);
+        *self.0.entry(name.to_string()).or_insert(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.0.iter().map(|(name, id)| (name.as_str(), *id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Checks that `name` can be used as the directory of a space or a namespace.
+pub fn check_name(name: &str) -> Result<(), VectorError> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if name.is_empty() || !name.chars().all(valid) {
+        let message = format!("names are made of letters, digits, '_' and '-', not {:?}", name);
+        return Err(VectorError::Schema(message));
+    }
+    Ok(())
+}
+
+/// Checks that a space can be created with `schema`. Spaces hold plain vectors, without the
+/// auxiliary structures of the main space.
+pub fn check_schema(schema: &Schema) -> Result<(), VectorError> {
+    schema.validate()?;
+    let auxiliary = schema.full_precision || schema.product_quantization.is_some() || schema.binary_quantization;
+    if schema.storage == Storage::SumEmbeddings || auxiliary {
+        let message = format!("named spaces only store vectors, {:?} is not supported", schema);
+        return Err(VectorError::Schema(message));
+    }
+    if schema.dimension == 0 {
+        return Err(VectorError::Schema("named spaces need a dimension".to_string()));
+    }
+    Ok(())
+}
+
+/// Location of the files of the space `name` of the index at `location`.
+pub fn space_location(location: &Location, name: &str) -> Location {
+    Location(location.spaces_path().join(name))
+}
+
+/// Query of one space in a fused search.
+#[derive(Debug, Clone, Copy)]
+pub struct SpaceQuery<'q> {
+    pub space: &'q str,
+    pub vector: &'q [f32],
+    pub weight: f32,
+}
+
+impl<'q> SpaceQuery<'q> {
+    pub fn new(space: &'q str, vector: &'q [f32]) -> Self {
+        SpaceQuery {
+            space,
+            vector,
+            weight: 1.0,
+        }
+    }
+
+    /// Factor applied to the distances of this space in the fused score, `1.0` by default.
+    pub fn weight(mut self, weight: f32) -> Self {
+        self.weight = weight;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::NamedTempFile;
+
+    use super::{check_name, check_schema, position, vec_id, SpaceRegistry};
+    use crate::vectors::{Metric, Schema, Storage};
+
+    #[test]
+    fn ids() {
+        assert_eq!(vec_id(0, 7), 7);
+        assert_ne!(vec_id(1, 7), vec_id(2, 7));
+        assert_eq!(position(vec_id(3, 12345)), 12345);
+    }
+
+    #[test]
+    fn registry() {
+        let mut registry = SpaceRegistry::default();
+        assert_eq!(registry.add("title"), 1);
+        assert_eq!(registry.add("image"), 2);
+        assert_eq!(registry.add("title"), 1);
+        assert_eq!(registry.get("image"), Some(2));
+        assert_eq!(registry.get("body"), None);
+
+        let file = NamedTempFile::new().unwrap();
+        registry.save(file.as_file()).unwrap();
+        assert_eq!(SpaceRegistry::load(file.path()).unwrap(), registry);
+        assert!(SpaceRegistry::load(&file.path().with_extension("missing")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn checks() {
+        assert!(check_name("image_2-small").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("../title").is_err());
+
+        assert!(check_schema(&Schema::with_metric(3, Metric::Euclidean)).is_ok());
+        assert!(check_schema(&Schema::new(0)).is_err());
+        assert!(check_schema(&Schema::new(3).storage(Storage::SumEmbeddings)).is_err());
+        assert!(check_schema(&Schema::new(3).binary_quantization(true)).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    ops::{Deref, Range},
+    path::Path,
+    sync::{Arc, Condvar, Mutex, OnceLock},
+};
+
+extern crate lmdb_zero as lmdb;
+
+/// Maximum number of named databases inside the environment of an index.
+const MAX_DBS: u32 = 16;
+
+/// Initial size of the memory map of the environment, shared by all the databases of an index.
+pub const DEFAULT_MAP_SIZE: usize = 1 << 28;
+
+/// Width in bytes of the encoded ids.
+pub const ID_SIZE: usize = 8;
+
+/// Encodes an id (vec id or doc id) as a fixed-width big-endian integer.
+///
+/// LMDB compares keys as byte strings, so this encoding keeps the numeric order of the ids and
+/// allows range scans. `INTEGERKEY`/`INTEGERDUP` would require native-endian integers, so the
+/// databases rely on the default comparator and `DUPFIXED` instead.
+pub fn encode_id(id: usize) -> [u8; ID_SIZE] {
+    (id as u64).to_be_bytes()
+}
+
+pub fn decode_id(bytes: &[u8]) -> usize {
+    u64::from_be_bytes(bytes.try_into().unwrap()) as usize
+}
+
+/// Opens the LMDB environment holding all the databases of an index.
+///
+/// Every database of the index (id maps, tombstones, metadata...) is a named database inside this
+/// environment, so they can be updated together in a single write transaction.
+///
+/// `map_size` is only the initial size of the map, it grows when a write transaction fills it. An
+/// existing environment bigger than `map_size` keeps its size.
+pub fn open_env(path: &Path, map_size: usize) -> Result<Arc<lmdb::Environment>, lmdb::Error> {
+    std::fs::create_dir_all(path).unwrap();
+
+    let mut builder = lmdb::EnvBuilder::new()?;
+    builder.set_maxdbs(MAX_DBS)?;
+    builder.set_mapsize(map_size)?;
+
+    let env = unsafe { builder.open(path.to_str().unwrap(), lmdb::open::Flags::empty(), 0o666)? };
+
+    Ok(Arc::new(env))
+}
+
+/// Opens (creating it if needed) the named database `name` inside `env`.
+pub fn open_db<'a>(
+    env: &Arc<lmdb::Environment>,
+    name: &str,
+    flags: lmdb::db::Flags,
+) -> Result<lmdb::Database<'a>, lmdb::Error> {
+    let options = lmdb::DatabaseOptions::new(flags | lmdb::db::CREATE);
+    lmdb::Database::open(env.clone(), Some(name), &options)
+}
+
+/// Lock of the memory map of an environment. Transactions hold it shared and resizing the map
+/// holds it exclusively, since LMDB only allows it with no transaction alive in the environment,
+/// and environments are shared by the threads of a reader or a writer.
+struct MapLock {
+    /// Transactions alive, or `None` while the map is resized.
+    transactions: Mutex<Option<usize>>,
+    changed: Condvar,
+}
+
+impl Default for MapLock {
+    fn default() -> Self {
+        MapLock {
+            transactions: Mutex::new(Some(0)),
+            changed: Condvar::new(),
+        }
+    }
+}
+
+impl MapLock {
+    /// The lock of `env`, by address.
+    fn of(env: &lmdb::Environment) -> Arc<MapLock> {
+        static LOCKS: OnceLock<Mutex<HashMap<usize, Arc<MapLock>>>> = OnceLock::new();
+        let mut locks = LOCKS.get_or_init(Default::default).lock().unwrap();
+        // A lock nobody holds is as good as a new one, so the ones of dropped environments go.
+        locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        locks.entry(env as *const lmdb::Environment as usize).or_default().clone()
+    }
+
+    fn shared(env: &lmdb::Environment) -> MapGuard {
+        let lock = MapLock::of(env);
+        let mut transactions = lock.transactions.lock().unwrap();
+        loop {
+            match transactions.as_mut() {
+                Some(count) => {
+                    *count += 1;
+                    break;
+                }
+                None => transactions = lock.changed.wait(transactions).unwrap(),
+            }
+        }
+        drop(transactions);
+        MapGuard { lock }
+    }
+
+    /// Runs `f` once the transactions of the other threads are over, keeping new ones from
+    /// starting until it returns.
+    fn exclusive<T>(env: &lmdb::Environment, f: impl FnOnce() -> T) -> T {
+        let lock = MapLock::of(env);
+        let mut transactions = lock.transactions.lock().unwrap();
+        while *transactions != Some(0) {
+            transactions = lock.changed.wait(transactions).unwrap();
+        }
+        *transactions = None;
+        drop(transactions);
+
+        let result = f();
+        *lock.transactions.lock().unwrap() = Some(0);
+        lock.changed.notify_all();
+        result
+    }
+}
+
+/// Shared hold of the `MapLock` of an environment, released when dropped.
+struct MapGuard {
+    lock: Arc<MapLock>,
+}
+
+impl Drop for MapGuard {
+    fn drop(&mut self) {
+        if let Some(count) = self.lock.transactions.lock().unwrap().as_mut() {
+            *count -= 1;
+        }
+        self.lock.changed.notify_all();
+    }
+}
+
+/// A read transaction, which keeps the memory map of its environment from being resized.
+pub struct ReadTxn<'env> {
+    txn: lmdb::ReadTransaction<'env>,
+    _map: MapGuard,
+}
+
+impl<'env> Deref for ReadTxn<'env> {
+    type Target = lmdb::ReadTransaction<'env>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.txn
+    }
+}
+
+/// Runs `f` inside a write transaction, which is only committed if `f` succeeds.
+///
+/// If the transaction doesn't fit in the memory map, the map is doubled and `f` is retried in a
+/// new transaction.
+pub fn write<T, F>(env: &lmdb::Environment, mut f: F) -> Result<T, lmdb::Error>
+where
+    F: FnMut(&lmdb::WriteTransaction) -> Result<T, lmdb::Error>,
+{
+    loop {
+        let map = MapLock::shared(env);
+        let result = lmdb::WriteTransaction::new(env).and_then(|txn| {
+            let result = f(&txn)?;
+            txn.commit()?;
+            Ok(result)
+        });
+        drop(map);
+
+        match result {
+            Err(lmdb::Error::Code(lmdb::error::MAP_FULL)) => grow(env)?,
+            Err(lmdb::Error::Code(lmdb::error::MAP_RESIZED)) => adopt_map_size(env)?,
+            result => return result,
+        }
+    }
+}
+
+/// Calls `f` with every entry of `db` whose key is an id in `range`, in increasing order.
+pub fn scan_range<F>(
+    txn: &lmdb::ConstTransaction,
+    db: &lmdb::Database,
+    range: Range<usize>,
+    mut f: F,
+) -> Result<(), lmdb::Error>
+where
+    F: FnMut(usize, &[u8]),
+{
+    if range.is_empty() {
+        return Ok(());
+    }
+
+    let access = txn.access();
+    let mut cursor = txn.cursor(db)?;
+
+    let mut current = cursor.seek_range_k::<[u8], [u8]>(&access, &encode_id(range.start));
+    while let Ok((key, val)) = current {
+        let id = decode_id(key);
+        if id >= range.end {
+            break;
+        }
+        f(id, val);
+        current = cursor.next::<[u8], [u8]>(&access);
+    }
+    Ok(())
+}
+
+/// Begins a read transaction, adopting the new map size if another environment grew it.
+pub fn read_txn(env: &lmdb::Environment) -> Result<ReadTxn<'_>, lmdb::Error> {
+    loop {
+        let map = MapLock::shared(env);
+        match lmdb::ReadTransaction::new(env) {
+            Ok(txn) => return Ok(ReadTxn { txn, _map: map }),
+            Err(lmdb::Error::Code(lmdb::error::MAP_RESIZED)) => {
+                drop(map);
+                adopt_map_size(env)?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Doubles the map of `env`, once the transactions of the other threads are over. Another thread
+/// may have grown it meanwhile, in which case the write is only retried.
+fn grow(env: &lmdb::Environment) -> Result<(), lmdb::Error> {
+    let map_size = env.info()?.mapsize;
+    MapLock::exclusive(env, || {
+        if env.info()?.mapsize != map_size {
+            return Ok(());
+        }
+        warn!("LMDB map is full ({} bytes), growing it to {} bytes", map_size, map_size * 2);
+        // There are no transactions alive in this environment.
+        unsafe { env.set_mapsize(map_size * 2) }
+    })
+}
+
+fn adopt_map_size(env: &lmdb::Environment) -> Result<(), lmdb::Error> {
+    debug!("LMDB map was resized by another environment");
+    // There are no transactions alive in this environment.
+    MapLock::exclusive(env, || unsafe { env.set_mapsize(0) })
+}
+
+#[cfg(test)]
+mod test {
+    use log::LevelFilter;
+    use tempfile::tempdir;
+
+    use super::lmdb;
+    use crate::vectors::{storage, DeletedDBReader, DeletedDBWriter, IndexMap};
+
+    fn init() {
+        let _ = env_logger::builder()
+            .filter_level(LevelFilter::Trace)
+            .is_test(true)
+            .try_init();
+    }
+
+    #[test]
+    fn write_is_atomic_across_databases() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let map = IndexMap::open(&env).unwrap();
+        let deleted = DeletedDBWriter::open(&env).unwrap();
+
+        storage::write(&env, |txn| {
+            map.insert_in(txn, 1, 10)?;
+            deleted.add_batch_in(txn, std::iter::once(10))
+        })
+        .unwrap();
+
+        let result: Result<(), _> = storage::write(&env, |txn| {
+            map.insert_in(txn, 2, 20)?;
+            deleted.add_batch_in(txn, std::iter::once(20))?;
+            Err(lmdb::Error::Code(lmdb::error::NOTFOUND))
+        });
+        assert!(result.is_err());
+
+        let reader_env = storage::open_env(tempdir.path(), storage::DEFAULT_MAP_SIZE).unwrap();
+        let reader = DeletedDBReader::open(&reader_env).unwrap();
+
+        assert_eq!(map.get_vec_ids(1).unwrap(), vec![10]);
+        assert!(map.get_vec_ids(2).unwrap().is_empty());
+        assert!(reader._contains(10).unwrap());
+        assert!(!reader._contains(20).unwrap());
+    }
+
+    #[test]
+    fn map_grows_when_full() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let env = storage::open_env(tempdir.path(), 1 << 16).unwrap();
+        let map = IndexMap::open(&env).unwrap();
+
+        // Opened before the growth, it has to adopt the new size.
+        let reader_env = storage::open_env(tempdir.path(), 1 << 16).unwrap();
+        let reader = DeletedDBReader::open(&reader_env).unwrap();
+
+        let doc_ids: Vec<_> = (0..20_000).collect();
+        let vec_ids: Vec<_> = (0..20_000).collect();
+        map.insert_batch(&doc_ids, &vec_ids).unwrap();
+
+        assert!(env.info().unwrap().mapsize > 1 << 16);
+        assert_eq!(map.get_vec_ids(19_999).unwrap(), vec![19_999]);
+        assert_eq!(map.get_doc_id(12_345).unwrap(), 12_345);
+        assert!(!reader._contains(0).unwrap());
+    }
+
+    #[test]
+    fn map_grows_while_read() {
+        init();
+
+        let tempdir = tempdir().unwrap();
+        let env = storage::open_env(tempdir.path(), 1 << 16).unwrap();
+        let map = IndexMap::open(&env).unwrap();
+        map.insert(0, 0).unwrap();
+
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    assert_eq!(map.get_doc_id(0).unwrap(), 0);
+                }
+            });
+
+            for batch in 0..10 {
+                let doc_ids: Vec<_> = (batch * 2_000 + 1..(batch + 1) * 2_000 + 1).collect();
+                map.insert_batch(&doc_ids, &doc_ids).unwrap();
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+
+        assert!(env.info().unwrap().mapsize > 1 << 16);
+        assert_eq!(map.get_doc_id(20_000).unwrap(), 20_000);
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    ops::Range,
+    path::Path,
+};
+
+use roaring::RoaringTreemap;
+
+/// Compressed set of deleted vec ids.
+///
+/// The `Writer` persists a snapshot with every commit and the `Reader` loads the one of the
+/// generation it is serving, so filtering the results of a search is a bitmap probe.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tombstones(RoaringTreemap);
+
+impl Tombstones {
+    pub fn new() -> Self {
+        Tombstones::default()
+    }
+
+    /// Loads a snapshot, a missing file is an empty set.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match File::open(path) {
+            Ok(file) => Ok(Tombstones(RoaringTreemap::deserialize_from(BufReader::new(file))?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Tombstones::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save<W: io::Write>(&self, writer: W) -> io::Result<()> {
+        self.0.serialize_into(BufWriter::new(writer))
+    }
+
+    pub fn insert(&mut self, idx: usize) -> bool {
+        self.0.insert(idx as u64)
+    }
+
+    pub fn remove(&mut self, idx: usize) -> bool {
+        self.0.remove(idx as u64)
+    }
+
+    pub fn contains(&self, idx: usize) -> bool {
+        self.0.contains(idx as u64)
+    }
+
+    /// Returns the ids in `idxs` that are not deleted, keeping their order.
+    pub fn filter(&self, idxs: &[usize]) -> Vec<usize> {
+        idxs.iter().copied().filter(|idx| !self.contains(*idx)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Number of deleted ids in `range`.
+    pub fn count_in(&self, range: Range<usize>) -> usize {
+        if range.is_empty() {
+            return 0;
+        }
+        let below_end = self.0.rank(range.end as u64 - 1);
+        let below_start = match range.start {
+            0 => 0,
+            start => self.0.rank(start as u64 - 1),
+        };
+        (below_end - below_start) as usize
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().map(|idx| idx as usize)
+    }
+
+    pub fn union(&self, other: &Tombstones) -> Tombstones {
+        Tombstones(&self.0 | &other.0)
+    }
+
+    pub fn intersection(&self, other: &Tombstones) -> Tombstones {
+        Tombstones(&self.0 & &other.0)
+    }
+
+    pub fn difference(&self, other: &Tombstones) -> Tombstones {
+        Tombstones(&self.0 - &other.0)
+    }
+}
+
+impl FromIterator<usize> for Tombstones {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        Tombstones(iter.into_iter().map(|idx| idx as u64).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::NamedTempFile;
+
+    use super::Tombstones;
+
+    #[test]
+    fn filter_and_count() {
+        let tombstones: Tombstones = vec![1, 2, 3, 256, 1 << 33].into_iter().collect();
+
+        assert_eq!(tombstones.filter(&[1, 2, 3, 4, 5, 6, 256]), [4, 5, 6]);
+        assert!(tombstones.contains(1 << 33));
+        assert_eq!(tombstones.len(), 5);
+        assert_eq!(tombstones.count_in(0..4), 3);
+        assert_eq!(tombstones.count_in(2..257), 3);
+        assert_eq!(tombstones.count_in(257..1 << 34), 1);
+        assert_eq!(tombstones.count_in(5..5), 0);
+    }
+
+    #[test]
+    fn set_operations() {
+        let a: Tombstones = vec![1, 2, 3].into_iter().collect();
+        let b: Tombstones = vec![3, 4].into_iter().collect();
+
+        assert_eq!(a.union(&b).iter().collect::<Vec<_>>(), [1, 2, 3, 4]);
+        assert_eq!(a.intersection(&b).iter().collect::<Vec<_>>(), [3]);
+        assert_eq!(a.difference(&b).iter().collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let tombstones: Tombstones = (0..10_000).step_by(3).collect();
+
+        let file = NamedTempFile::new().unwrap();
+        tombstones.save(file.as_file()).unwrap();
+
+        assert_eq!(Tombstones::load(file.path()).unwrap(), tombstones);
+        assert!(Tombstones::load(&file.path().with_extension("missing")).unwrap().is_empty());
+    }
+}
@@ -1,22 +1,89 @@
-use std::{fs::File, path::PathBuf, time::Instant, fmt};
+use std::{collections::BTreeMap, fs::{File, OpenOptions}, io, path::PathBuf, sync::{Arc, Mutex}, time::SystemTime, fmt};
 
 use granne::{
-    angular::{self, Vector, Vectors},
-    BuildConfig, Builder, GranneBuilder, Index,
+    angular::Vector,
+    BuildConfig,
 };
 use log::{debug, error, trace};
 use tempfile::NamedTempFile;
+extern crate lmdb_zero as lmdb;
 
-use super::{directory::Location, DeletedDBWriter, IndexMap, Lock};
+use super::{
+    commit::{self, CommitHandle, CommitProgress, PendingCommit}, directory::Location, CommitPolicy, PendingWrites, elements, expiry, migration, namespaces, spaces, storage, DeletedDBWriter, Elements, ExpiryDB, Metric, Storage, IndexMap, InvalidVector, Lock, Metadata, MetadataDB, NamespaceRegistry, PqConfig, Schema, SpaceRegistry, Tombstones, BatchReport, IngestConfig,
+    VectorError, WriterConfig,
+};
+
+/// Number of vectors `Writer::export` reads at a time.
+const EXPORT_BATCH: usize = 10_000;
+
+/// A named vector space, see `spaces`.
+struct Space<'a> {
+    id: u64,
+    location: Location,
+    elements: Elements<'a>,
+    schema: Schema,
+    /// Whether the space was added or vectors were pushed to it since the last commit.
+    dirty: bool,
+}
+
+/// A live vector of the main space, with what is needed to push it to another index.
+pub(crate) struct ExportedVector {
+    pub(crate) doc_id: usize,
+    pub(crate) vector: Vec<f32>,
+    pub(crate) metadata: Metadata,
+    pub(crate) expires_at: Option<u64>,
+}
+
+/// A tenant namespace, see `namespaces`.
+struct Namespace<'a> {
+    id: u64,
+    location: Location,
+    elements: Elements<'a>,
+    /// Whether vectors were pushed since the last commit.
+    dirty: bool,
+}
+
+/// Names of the spaces and namespaces whose files a commit writes.
+#[derive(Default)]
+struct Written {
+    spaces: Vec<String>,
+    namespaces: Vec<String>,
+}
+
+/// A commit started by `Writer::commit_async`, with what it took from the writer.
+struct InFlight {
+    progress: Arc<CommitProgress>,
+    written: Written,
+    removed_namespaces: Vec<Location>,
+    pending: PendingWrites,
+}
 
 pub struct Writer<'a> {
     location: Location,
-    elements: angular::Vectors<'a>,
+    env: Arc<lmdb::Environment>,
+    elements: Elements<'a>,
+    full_precision: Option<Elements<'a>>,
     build_config: BuildConfig,
-    commit_lock: Lock,
     writer_lock: Lock,
     deleted: DeletedDBWriter<'a>,
+    tombstones: Mutex<Tombstones>,
+    expiry: ExpiryDB<'a>,
     index_map: IndexMap<'a>,
+    metadata: MetadataDB<'a>,
+    schema: Option<Schema>,
+    product_quantization: Option<PqConfig>,
+    binary_quantization: bool,
+    space_registry: SpaceRegistry,
+    spaces: BTreeMap<String, Space<'a>>,
+    namespace_registry: NamespaceRegistry,
+    namespaces: BTreeMap<String, Namespace<'a>>,
+    namespace_graph_threshold: usize,
+    removed_namespaces: Vec<Location>,
+    generation: u64,
+    in_flight: Option<InFlight>,
+    commit_policy: CommitPolicy,
+    pending: Mutex<PendingWrites>,
+    ingest: IngestConfig,
 }
 
 impl fmt::Debug for Writer<'_> {
@@ -24,10 +91,19 @@ impl fmt::Debug for Writer<'_> {
         f.debug_struct("Writer")
         .field("location", &self.location)
         .field("build_config", &self.build_config)
-        .field("commit_lock", &self.commit_lock)
         .field("_writer_lock", &self.writer_lock)
         .field("deleted", &self.deleted)
+        .field("tombstones", &self.tombstones.lock().unwrap().len())
+        .field("expiry", &self.expiry)
         .field("index_map", &self.index_map)
+        .field("metadata", &self.metadata)
+        .field("schema", &self.schema)
+        .field("spaces", &self.space_registry)
+        .field("namespaces", &self.namespace_registry.len())
+        .field("generation", &self.generation)
+        .field("commit_policy", &self.commit_policy)
+        .field("pending", &*self.pending.lock().unwrap())
+        .field("ingest", &self.ingest)
         .finish()
     }
 }
@@ -35,195 +111,1030 @@ impl fmt::Debug for Writer<'_> {
 impl<'a> Drop for Writer<'a> {
     fn drop(&mut self) {
         debug!("Dropping writer");
+        self.settle_commit();
         self.writer_lock.unlock();
     }
 }
 
 impl<'a> Writer<'a> {
-    pub fn open<T: Into<PathBuf>>(location: T) -> Result<Self, String> {
+    pub fn open<T: Into<PathBuf>>(location: T) -> Result<Self, VectorError> {
+        Writer::open_with_config(location, WriterConfig::default())
+    }
+
+    pub fn open_with_config<T: Into<PathBuf>>(location: T, config: WriterConfig) -> Result<Self, VectorError> {
         let location = Location(location.into());
         std::fs::create_dir_all(location.path()).unwrap();
-        let commit_lock = Lock::open(&location.commit_lock_path()).unwrap();
-        let writer_lock = Lock::open(&location.writer_lock_path()).unwrap();
+        let writer_lock = Lock::open(location.writer_lock_path()).unwrap();
 
         if let Err(e) = writer_lock.try_lock() {
-            let message = format!("Adquiring lock for Writer: {}.\nCheck if another instance of nucliadb_node is running.", e);
-            error!("{}", message);
+            let error = VectorError::Locked(format!("{}.\nCheck if another instance of nucliadb_node is running.", e));
+            error!("{}", error);
+            return Err(error);
         }
 
-        let elements = Writer::open_elements(location.elements_path());
+        // The writer lock is released by `Drop` once the writer exists, but not if opening fails.
+        let writer_lock_path = location.writer_lock_path();
+        Writer::open_locked(location, config, writer_lock).map_err(|e| {
+            error!("Error opening writer: {}", e);
+            Lock::open(writer_lock_path).unwrap().unlock();
+            e
+        })
+    }
 
-        let build_config = BuildConfig::default();
+    fn open_locked(
+        location: Location,
+        config: WriterConfig,
+        writer_lock: Lock,
+    ) -> Result<Self, VectorError> {
+        let stored = Schema::load(&location.schema_path())?;
+        let layout = stored.unwrap_or_else(|| {
+            Schema::with_metric(0, config.metric.unwrap_or_default())
+                .storage(config.storage.unwrap_or_default())
+                .full_precision(config.full_precision)
+                .product_quantization(config.product_quantization)
+                .binary_quantization(config.binary_quantization)
+        });
+        layout.validate()?;
 
-        let deleted_path = location.deleted_path();
-        let deleted = DeletedDBWriter::open(deleted_path.to_str().unwrap()).unwrap();
+        let elements = match layout.storage {
+            Storage::SumEmbeddings => Writer::open_sum_embeddings(&location, layout.metric)?,
+            storage => Writer::open_elements(location.elements_path(), layout.metric, storage),
+        };
+        let full_precision = match layout.full_precision {
+            true => Some(Writer::open_elements(location.full_precision_path(), layout.metric, Storage::F32)),
+            false => None,
+        };
+        let schema = Writer::open_schema(&location, stored, layout, &elements, &config)?;
+        let space_registry = SpaceRegistry::load(&location.space_registry_path())?;
+        let spaces = Writer::open_spaces(&location, &space_registry)?;
+        let namespace_registry = NamespaceRegistry::load(&location.namespace_registry_path())?;
+        let namespaces = Writer::open_namespaces(&location, &namespace_registry, layout);
 
-        let index_map_path = location.index_map_path();
-        let index_map = IndexMap::open(index_map_path.to_str().unwrap()).unwrap();
+        let build_config = BuildConfig::default();
+        let generation = commit::load_generation(&location.generation_path())?;
+
+        let env = storage::open_env(&location.lmdb_path(), config.map_size)?;
+        migration::migrate(&env, &location)?;
+        let deleted = DeletedDBWriter::open(&env)?;
+        let tombstones = deleted.tombstones()?;
+        let expiry = ExpiryDB::open(&env)?;
+        let index_map = IndexMap::open(&env)?;
+        let metadata = MetadataDB::open(&env)?;
 
         Ok(Writer {
             location,
+            env,
             elements,
+            full_precision,
             build_config,
-            commit_lock,
             writer_lock,
             deleted,
+            tombstones: Mutex::new(tombstones),
+            expiry,
             index_map,
+            metadata,
+            schema,
+            product_quantization: layout.product_quantization,
+            binary_quantization: layout.binary_quantization,
+            space_registry,
+            spaces,
+            namespace_registry,
+            namespaces,
+            namespace_graph_threshold: config.namespace_graph_threshold,
+            removed_namespaces: Vec::new(),
+            generation,
+            in_flight: None,
+            commit_policy: config.commit_policy,
+            pending: Mutex::default(),
+            ingest: config.ingest,
         })
     }
 
-    fn open_elements<'b, T: Into<PathBuf>>(elements_path: T) -> angular::Vectors<'b> {
-        match File::open(elements_path.into()) {
-            Ok(file) => unsafe { angular::Vectors::from_file(&file).unwrap() },
-            Err(_) => angular::Vectors::new(),
+    /// Checks the schema of the index against the dimension, metric and storage requested in the
+    /// config. `layout` is the stored schema or the one requested for a new index.
+    ///
+    /// Indexes created before the schema was recorded take the dimension of their vectors. A new
+    /// index created with a dimension records its schema right away.
+    fn open_schema(
+        location: &Location,
+        stored: Option<Schema>,
+        layout: Schema,
+        elements: &Elements,
+        config: &WriterConfig,
+    ) -> Result<Option<Schema>, VectorError> {
+        let schema = stored.or_else(|| match elements.dim() {
+            0 => None,
+            dimension => Some(Schema { dimension, ..layout }),
+        });
+
+        if config.metric.unwrap_or(layout.metric) != layout.metric {
+            let message = format!("the index uses the {:?} metric, not {:?}", layout.metric, config.metric);
+            return Err(VectorError::Schema(message));
+        }
+        if config.storage.unwrap_or(layout.storage) != layout.storage {
+            let message = format!("the index uses {:?} storage, not {:?}", layout.storage, config.storage);
+            return Err(VectorError::Schema(message));
+        }
+
+        match (schema, config.dimension) {
+            (Some(schema), Some(dimension)) if schema.dimension != dimension => Err(VectorError::DimensionMismatch {
+                expected: schema.dimension,
+                found: dimension,
+            }),
+            (None, Some(dimension)) => {
+                let schema = Schema { dimension, ..layout };
+                schema.save(File::create(location.schema_path())?)?;
+                Ok(Some(schema))
+            }
+            (schema, _) => Ok(schema),
+        }
+    }
+
+    fn open_spaces(location: &Location, registry: &SpaceRegistry) -> Result<BTreeMap<String, Space<'a>>, VectorError> {
+        let mut spaces = BTreeMap::new();
+        for (name, id) in registry.iter() {
+            let location = spaces::space_location(location, name);
+            let schema = Schema::load(&location.schema_path())?
+                .ok_or_else(|| VectorError::Schema(format!("space {} has no schema", name)))?;
+            let elements = Writer::open_elements(location.elements_path(), schema.metric, schema.storage);
+            spaces.insert(name.to_string(), Space { id, location, elements, schema, dirty: false });
+        }
+        Ok(spaces)
+    }
+
+    fn open_namespaces(
+        location: &Location,
+        registry: &NamespaceRegistry,
+        layout: Schema,
+    ) -> BTreeMap<String, Namespace<'a>> {
+        let mut namespaces = BTreeMap::new();
+        for (name, id) in registry.iter() {
+            let location = namespaces::namespace_location(location, name);
+            let elements = Writer::open_elements(location.elements_path(), layout.metric, layout.storage);
+            let namespace = Namespace {
+                id,
+                location,
+                elements,
+                dirty: false,
+            };
+            namespaces.insert(name.to_string(), namespace);
+        }
+        namespaces
+    }
+
+    /// Runs `vector` through the ingestion pipeline and checks it against `schema`, which is taken
+    /// from the vector itself when the index doesn't have one yet.
+    pub(crate) fn prepare(&self, schema: Option<Schema>, vector: &[f32]) -> Result<(Vector<'static>, Schema), VectorError> {
+        if self.elements.storage() == Storage::SumEmbeddings {
+            return Err(VectorError::Schema("the index stores terms, see Writer::push_terms".to_string()));
+        }
+        let vector = self.ingest.apply(vector)?;
+        let schema = schema.unwrap_or_else(|| {
+            Schema::with_metric(vector.len(), self.elements.metric())
+                .storage(self.elements.storage())
+                .full_precision(self.full_precision.is_some())
+                .product_quantization(self.product_quantization)
+                .binary_quantization(self.binary_quantization)
+        });
+        schema.check(&vector)?;
+        schema.storage.check(&vector)?;
+        Ok((Vector(vector.into()), schema))
+    }
+
+    pub(crate) fn location(&self) -> &Location {
+        &self.location
+    }
+
+    pub(crate) fn elements(&self) -> &Elements<'a> {
+        &self.elements
+    }
+
+    pub(crate) fn full_precision(&self) -> Option<&Elements<'a>> {
+        self.full_precision.as_ref()
+    }
+
+    /// Maps documents to vectors loaded by a `BulkLoader`, which are not in the elements of the
+    /// writer until it hands them over, and indexes their metadata.
+    pub(crate) fn map_loaded(
+        &mut self,
+        doc_ids: &[usize],
+        vec_ids: &[usize],
+        metadata: &[Metadata],
+        bytes: usize,
+    ) -> Result<(), VectorError> {
+        let result = storage::write(&self.env, |txn| {
+            self.index_map.insert_batch_in(txn, doc_ids, vec_ids)?;
+            for (vec_id, metadata) in vec_ids.iter().zip(metadata) {
+                self.metadata.insert_in(txn, *vec_id, metadata)?;
+            }
+            Ok(())
+        });
+        result.map_err(|e| {
+            error!("Error maping vector for document: {}", e);
+            e
+        })?;
+        self.pending.lock().unwrap().record(doc_ids.len(), bytes);
+        Ok(())
+    }
+
+    /// Takes the vectors of the main space from a `BulkLoader`, mapped from its files.
+    pub(crate) fn adopt(&mut self, elements: Elements<'a>, full_precision: Option<Elements<'a>>, schema: Option<Schema>) {
+        self.elements = elements;
+        self.full_precision = full_precision;
+        self.schema = schema.or(self.schema);
+    }
+
+    pub fn schema(&self) -> Option<Schema> {
+        self.schema
+    }
+
+    /// Schema of the vector space `name`, if the index has it.
+    pub fn space_schema(&self, name: &str) -> Option<Schema> {
+        self.spaces.get(name).map(|space| space.schema)
+    }
+
+    /// Adds the vector space `name` to the index, with its own dimension and metric. Adding a
+    /// space that exists with the same schema does nothing.
+    ///
+    /// The space, its schema included, is written and published to readers by the next commit.
+    pub fn add_space(&mut self, name: &str, schema: Schema) -> Result<(), VectorError> {
+        spaces::check_name(name)?;
+        spaces::check_schema(&schema)?;
+        if let Some(space) = self.spaces.get(name) {
+            return match space.schema == schema {
+                true => Ok(()),
+                false => Err(VectorError::Schema(format!("space {} has schema {:?}", name, space.schema))),
+            };
+        }
+
+        let id = self.space_registry.add(name);
+        let location = spaces::space_location(&self.location, name);
+
+        debug!("Added space {} with id {}", name, id);
+        let elements = Elements::new(schema.metric, schema.storage);
+        self.spaces.insert(name.to_string(), Space { id, location, elements, schema, dirty: true });
+        Ok(())
+    }
+
+    /// Maps the vectors in `elements_path`, once the writer has cut what a commit that didn't finish
+    /// left half-written.
+    fn open_elements<'b, T: Into<PathBuf>>(elements_path: T, metric: Metric, storage: Storage) -> Elements<'b> {
+        match OpenOptions::new().read(true).write(true).open(elements_path.into()) {
+            Ok(file) => {
+                Elements::repair(&file, storage).unwrap();
+                unsafe { Elements::from_file(&file, metric, storage).unwrap() }
+            }
+            Err(_) => Elements::new(metric, storage),
+        }
+    }
+
+    /// Maps the term embeddings and the elements made of them. Committed elements without their
+    /// embeddings can't be read, so they fail to open instead of starting an empty index.
+    fn open_sum_embeddings<'b>(location: &Location, metric: Metric) -> Result<Elements<'b>, VectorError> {
+        let elements = match File::open(location.elements_path()) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        match (File::open(location.embeddings_path()), elements) {
+            (Ok(embeddings), elements) => unsafe {
+                Ok(Elements::sum_embeddings_from_files(&embeddings, elements.as_ref(), metric)?)
+            },
+            (Err(e), _) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            (Err(_), None) => Ok(Elements::new(metric, Storage::SumEmbeddings)),
+            (Err(_), Some(_)) => {
+                let message = format!("{:?} has elements but no embeddings", location.path());
+                Err(VectorError::Schema(message))
+            }
         }
     }
 
-    pub fn push(&mut self, doc_id: usize, vector: &Vector) -> Result<(), String> {
+    fn push_element(&mut self, vector: &Vector) -> Result<(), VectorError> {
+        self.elements.push(vector)?;
+        if let Some(full_precision) = &mut self.full_precision {
+            full_precision.push(vector)?;
+        }
+        self.pending.lock().unwrap().record(1, std::mem::size_of_val(&vector.0[..]));
+        Ok(())
+    }
+
+    pub fn push(&mut self, doc_id: usize, vector: &Vector) -> Result<(), VectorError> {
+        self.push_with_metadata(doc_id, vector, &Metadata::default())
+    }
+
+    /// Pushes a vector and indexes its metadata so it can be used for filtering searches.
+    ///
+    /// The id mapping and the metadata are written in the same transaction.
+    pub fn push_with_metadata(&mut self, doc_id: usize, vector: &Vector, metadata: &Metadata) -> Result<(), VectorError> {
         trace!("Pushing vector for doc: {}", doc_id);
-        match self.index_map.insert(doc_id, self.next_idx()) {
+        namespaces::check_doc_id(doc_id)?;
+        let (vector, schema) = self.prepare(self.schema, &vector.0)?;
+        let vec_id = self.next_idx();
+        let result = storage::write(&self.env, |txn| {
+            self.index_map.insert_in(txn, doc_id, vec_id)?;
+            self.metadata.insert_in(txn, vec_id, metadata)
+        });
+
+        match result {
             Ok(()) => {
-                self.elements.push(vector);
+                self.push_element(&vector)?;
+                self.schema = Some(schema);
                 Ok(())
             }
             Err(e) => {
-                error!("Error maping vector for document: {}", e.to_string());
-                Err(e.to_string())
+                error!("Error maping vector for document: {}", e);
+                Err(e.into())
             }
         }
     }
 
-    pub fn push_vec(&mut self, doc_id: usize, vector: Vec<f32>) -> Result<(), String> {
-        let vector = Vector::from_iter(vector.into_iter());
+    pub fn push_vec(&mut self, doc_id: usize, vector: Vec<f32>) -> Result<(), VectorError> {
+        let vector = elements::vector_for(self.elements.metric(), vector);
         self.push(doc_id, &vector)
     }
 
-    pub fn push_batch(&mut self, doc_ids: &[usize], vectors: &[Vector]) -> Result<(), String> {
+    /// Adds a term to the embedding table of an index with `Storage::SumEmbeddings` and returns
+    /// its id. The first embedding fixes the dimension of a new index.
+    pub fn push_embedding(&mut self, embedding: &[f32]) -> Result<usize, VectorError> {
+        if self.elements.sum_embeddings().is_none() {
+            let message = format!("{:?} storage doesn't have embeddings", self.elements.storage());
+            return Err(VectorError::Schema(message));
+        }
+        if let Some(position) = embedding.iter().position(|value| !value.is_finite()) {
+            return Err(InvalidVector::NotFinite(position).into());
+        }
+        let schema = self.schema.unwrap_or_else(|| {
+            Schema::with_metric(embedding.len(), self.elements.metric()).storage(Storage::SumEmbeddings)
+        });
+        schema.check(embedding)?;
+
+        self.schema = Some(schema);
+        let term = self.elements.push_embedding(embedding);
+        // Embeddings are no vectors of the index, but they are written by the next commit.
+        self.pending.lock().unwrap().record(0, std::mem::size_of_val(embedding));
+        Ok(term)
+    }
+
+    pub fn push_terms(&mut self, doc_id: usize, terms: &[usize]) -> Result<(), VectorError> {
+        self.push_terms_with_metadata(doc_id, terms, &Metadata::default())
+    }
+
+    /// Pushes the element made of `terms`, ids returned by `push_embedding`, in an index with
+    /// `Storage::SumEmbeddings`. Its vector is the sum of the embeddings of the terms.
+    pub fn push_terms_with_metadata(
+        &mut self,
+        doc_id: usize,
+        terms: &[usize],
+        metadata: &Metadata,
+    ) -> Result<(), VectorError> {
+        trace!("Pushing {} terms for doc: {}", terms.len(), doc_id);
+        namespaces::check_doc_id(doc_id)?;
+        self.elements.embed(terms)?;
+        let vec_id = self.next_idx();
+        let result = storage::write(&self.env, |txn| {
+            self.index_map.insert_in(txn, doc_id, vec_id)?;
+            self.metadata.insert_in(txn, vec_id, metadata)
+        });
+
+        match result {
+            Ok(()) => {
+                self.pending.lock().unwrap().record(1, std::mem::size_of_val(terms));
+                self.elements.push_terms(terms);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error maping terms for document: {}", e);
+                Err(e.into())
+            }
+        }
+    }
+
+    pub fn push_to_space(&mut self, space: &str, doc_id: usize, vector: Vec<f32>) -> Result<(), VectorError> {
+        self.push_to_space_with_metadata(space, doc_id, vector, &Metadata::default())
+    }
+
+    /// Pushes a vector of `doc_id` to the vector space `space`.
+    ///
+    /// The vector goes through the ingestion pipeline of the writer, except for the truncation,
+    /// which is meant for the dimension of the main space.
+    pub fn push_to_space_with_metadata(
+        &mut self,
+        space: &str,
+        doc_id: usize,
+        vector: Vec<f32>,
+        metadata: &Metadata,
+    ) -> Result<(), VectorError> {
+        trace!("Pushing vector for doc {} to space {}", doc_id, space);
+        namespaces::check_doc_id(doc_id)?;
+        let ingest = IngestConfig {
+            truncate: None,
+            ..self.ingest
+        };
+        let vector = ingest.apply(&vector)?;
+        let space = self
+            .spaces
+            .get_mut(space)
+            .ok_or_else(|| VectorError::UnknownSpace(space.to_string()))?;
+        space.schema.check(&vector)?;
+        space.schema.storage.check(&vector)?;
+
+        let vector = elements::vector_for(space.schema.metric, vector);
+        let vec_id = spaces::vec_id(space.id, space.elements.len());
+        let result = storage::write(&self.env, |txn| {
+            self.index_map.insert_in(txn, doc_id, vec_id)?;
+            self.metadata.insert_in(txn, vec_id, metadata)
+        });
+
+        match result {
+            Ok(()) => {
+                space.elements.push(&vector)?;
+                space.dirty = true;
+                self.pending.lock().unwrap().record(1, std::mem::size_of_val(&vector.0[..]));
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error maping vector for document: {}", e);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Names of the vector spaces of the index, the ones not committed yet included.
+    pub fn spaces(&self) -> Vec<String> {
+        self.spaces.keys().cloned().collect()
+    }
+
+    /// Names of the namespaces of the index, the ones not committed yet included.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.namespaces.keys().cloned().collect()
+    }
+
+    /// Pushes a vector of the document `doc_id` of the namespace `namespace`, which is created if
+    /// it doesn't exist. Documents of different namespaces are unrelated even if they share an id.
+    ///
+    /// Namespaces hold vectors with the schema of the index, without full-precision copies or
+    /// quantization codes.
+    pub fn push_to_namespace(&mut self, namespace: &str, doc_id: usize, vector: &Vector) -> Result<(), VectorError> {
+        trace!("Pushing vector for doc {} of namespace {}", doc_id, namespace);
+        namespaces::check_doc_id(doc_id)?;
+        let (vector, schema) = self.prepare(self.schema, &vector.0)?;
+        if !self.namespaces.contains_key(namespace) {
+            self.add_namespace(namespace)?;
+        }
+
+        let namespace = self.namespaces.get_mut(namespace).unwrap();
+        let doc_key = namespaces::doc_key(namespace.id, doc_id);
+        let vec_id = namespaces::vec_id(namespace.id, namespace.elements.len());
+        let result = storage::write(&self.env, |txn| self.index_map.insert_in(txn, doc_key, vec_id));
+
+        match result {
+            Ok(()) => {
+                namespace.elements.push(&vector)?;
+                self.pending.lock().unwrap().record(1, std::mem::size_of_val(&vector.0[..]));
+                namespace.dirty = true;
+                self.schema = Some(schema);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error maping vector for document: {}", e);
+                Err(e.into())
+            }
+        }
+    }
+
+    fn add_namespace(&mut self, name: &str) -> Result<(), VectorError> {
+        spaces::check_name(name)?;
+        let id = self.namespace_registry.add(name);
+        let location = namespaces::namespace_location(&self.location, name);
+        std::fs::create_dir_all(location.path())?;
+
+        debug!("Added namespace {} with id {}", name, id);
+        let namespace = Namespace {
+            id,
+            location,
+            elements: Elements::new(self.elements.metric(), self.elements.storage()),
+            dirty: false,
+        };
+        self.namespaces.insert(name.to_string(), namespace);
+        Ok(())
+    }
+
+    /// Marks all the vectors of the document `doc_id` of the namespace `namespace` as deleted.
+    pub fn delete_from_namespace(&mut self, namespace: &str, doc_id: usize) -> Result<(), VectorError> {
+        let id = self
+            .namespace_registry
+            .get(namespace)
+            .ok_or_else(|| VectorError::UnknownNamespace(namespace.to_string()))?;
+        self.delete_key(namespaces::doc_key(id, doc_id))
+    }
+
+    /// Deletes the namespace `namespace` with all its documents.
+    ///
+    /// Its documents stop resolving right away, and its files are removed by the next commit.
+    pub fn delete_namespace(&mut self, namespace: &str) -> Result<(), VectorError> {
+        debug!("Deleting namespace {}", namespace);
+        let removed = self
+            .namespaces
+            .get(namespace)
+            .ok_or_else(|| VectorError::UnknownNamespace(namespace.to_string()))?;
+
+        let mut doc_keys: Vec<usize> = self
+            .index_map
+            .doc_ids_in_range(namespaces::doc_keys(removed.id))?
+            .into_iter()
+            .map(|(doc_key, _)| doc_key)
+            .collect();
+        doc_keys.dedup();
+        let first = namespaces::vec_id(removed.id, 0);
+        let vec_ids: Vec<usize> = (first..first + removed.elements.len()).collect();
+
+        storage::write(&self.env, |txn| {
+            for doc_key in &doc_keys {
+                self.index_map.delete_in(txn, *doc_key)?;
+            }
+            self.index_map.delete_vec_ids_in(txn, &vec_ids)?;
+            self.expiry.clear_in(txn, &doc_keys)?;
+            self.deleted.remove_batch_in(txn, vec_ids.iter().copied())
+        })?;
+        for idx in vec_ids {
+            self.tombstones.lock().unwrap().remove(idx);
+        }
+
+        let removed = self.namespaces.remove(namespace).unwrap();
+        self.namespace_registry.remove(namespace);
+        self.removed_namespaces.push(removed.location);
+        self.pending.lock().unwrap().record(0, 0);
+        Ok(())
+    }
+
+    /// Pushes the valid vectors of a batch, reporting the ones rejected by the ingestion pipeline
+    /// or with a wrong dimension instead of failing the whole batch.
+    pub fn push_batch(&mut self, doc_ids: &[usize], vectors: &[Vector]) -> Result<BatchReport, VectorError> {
         trace!("Pushing batch of {} docs", doc_ids.len());
+        if doc_ids.len() != vectors.len() {
+            return Err(VectorError::BatchMismatch { doc_ids: doc_ids.len(), vectors: vectors.len() });
+        }
+
+        let mut report = BatchReport::default();
+        let mut schema = self.schema;
+        let mut valid_doc_ids = Vec::with_capacity(doc_ids.len());
+        let mut valid_vectors = Vec::with_capacity(vectors.len());
+        for (position, (doc_id, vector)) in doc_ids.iter().zip(vectors).enumerate() {
+            match namespaces::check_doc_id(*doc_id).and_then(|_| self.prepare(schema, &vector.0)) {
+                Ok((vector, vector_schema)) => {
+                    schema = Some(vector_schema);
+                    valid_doc_ids.push(*doc_id);
+                    valid_vectors.push(vector);
+                }
+                Err(e) => {
+                    debug!("Rejected vector {} of doc {}: {}", position, doc_id, e);
+                    report.rejected.push((position, e));
+                }
+            }
+        }
+        if valid_vectors.is_empty() {
+            return Ok(report);
+        }
+        self.schema = schema;
 
+        let (doc_ids, vectors) = (&valid_doc_ids[..], &valid_vectors[..]);
         let start_id = self.next_idx();
         let end_id = start_id + doc_ids.len();
 
         let id_list: Vec<_> = (start_id..end_id).collect();
 
         let step = 5000;
-        for i in 0..doc_ids.len()/step {
+        for (i, doc_ids) in doc_ids.chunks(step).enumerate() {
             let start = i*step;
-            let end = (i+1)*step;
-            println!("map batch {} - {}", start, end);
-            self.map_batch(&doc_ids[start..end], &id_list[start..end], vectors)?;
+            let end = start + doc_ids.len();
+            trace!("map batch {} - {}", start, end);
+            self.map_batch(doc_ids, &id_list[start..end], &vectors[start..end])?;
+            report.pushed += doc_ids.len();
         }
 
-        Ok(())
+        Ok(report)
     }
 
-    fn map_batch(&mut self, doc_ids: &[usize], vec_ids: &[usize], vectors: &[Vector]) -> Result<(), String> {
-        match self.index_map.insert_batch(doc_ids, &vec_ids) {
+    fn map_batch(&mut self, doc_ids: &[usize], vec_ids: &[usize], vectors: &[Vector]) -> Result<(), VectorError> {
+        match self.index_map.insert_batch(doc_ids, vec_ids) {
             Ok(()) => {
                 for v in vectors {
-                    self.elements.push(v);
+                    self.push_element(v)?;
                 }
                 Ok(())
             }
             Err(e) => {
-                error!("Error maping vector for document: {}", e.to_string());
-                Err(e.to_string())
+                error!("Error maping vector for document: {}", e);
+                Err(e.into())
             }
         }
     }
 
-    pub fn delete(&self, doc_id: usize) -> Result<(), String> {
+    /// Marks all the vectors of `doc_id` as deleted.
+    ///
+    /// Readers keep returning them until the next commit publishes the new tombstones.
+    pub fn delete(&self, doc_id: usize) -> Result<(), VectorError> {
+        namespaces::check_doc_id(doc_id)?;
+        self.delete_key(doc_id)
+    }
+
+    /// Marks all the vectors mapped to `doc_id`, a key of the id map, as deleted.
+    fn delete_key(&self, doc_id: usize) -> Result<(), VectorError> {
         trace!("Marking all vectors of doc {} as deleted", doc_id);
-        match self.index_map.get_vec_ids(doc_id) {
-            Ok(vec_ids) => match self.deleted.add_batch(vec_ids.into_iter()) {
-                Ok(()) => Ok(()),
-                Err(e) => {
-                    error!(
-                        "Error adding vectors to deleted indexes database: {}",
-                        e.to_string()
-                    );
-                    Err(e.to_string())
+        let result = storage::write(&self.env, |txn| {
+            let vec_ids = self.index_map.get_vec_ids_in(txn, doc_id)?;
+            self.deleted.add_batch_in(txn, vec_ids.iter().copied())?;
+            Ok(vec_ids)
+        });
+
+        match result {
+            Ok(vec_ids) => {
+                for idx in vec_ids {
+                    self.tombstones.lock().unwrap().insert(idx);
                 }
-            },
+                self.pending.lock().unwrap().record(0, 0);
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error marking the vectors of document {} as deleted: {}", doc_id, e);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Lifts the tombstones of all the vectors of `doc_id`.
+    ///
+    /// Deleted vectors are still physically present until they are compacted away, so this restores
+    /// the document as it was. Vectors that expired are restored too, without an expiry time.
+    pub fn undelete(&mut self, doc_id: usize) -> Result<(), VectorError> {
+        trace!("Restoring all vectors of doc {}", doc_id);
+        namespaces::check_doc_id(doc_id)?;
+        let result = storage::write(&self.env, |txn| {
+            let vec_ids = self.index_map.get_vec_ids_in(txn, doc_id)?;
+            self.deleted.remove_batch_in(txn, vec_ids.iter().copied())?;
+            self.expiry.clear_in(txn, &[doc_id])?;
+            Ok(vec_ids)
+        });
+
+        match result {
+            Ok(vec_ids) => {
+                for idx in vec_ids {
+                    self.tombstones.lock().unwrap().remove(idx);
+                }
+                self.pending.lock().unwrap().record(0, 0);
+                Ok(())
+            }
             Err(e) => {
-                error!(
-                    "Error obtaining the indexes of the vectors of the document {}: {}",
+                error!("Error restoring the vectors of document {}: {}", doc_id, e);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Sets the time after which the vectors of `doc_id` are treated as deleted.
+    ///
+    /// Readers stop returning them once expired, and the first commit after that tombstones them.
+    pub fn set_expiry(&mut self, doc_id: usize, expires_at: SystemTime) -> Result<(), VectorError> {
+        let expires_at = expiry::timestamp(expires_at);
+        trace!("Setting expiry of doc {} at {}", doc_id, expires_at);
+        namespaces::check_doc_id(doc_id)?;
+        let result = storage::write(&self.env, |txn| self.expiry.set_in(txn, doc_id, expires_at));
+
+        result.map_err(|e| {
+            error!("Error setting the expiry of document {}: {}", doc_id, e);
+            e.into()
+        })
+    }
+
+    /// Removes the expiry time of `doc_id`, if any.
+    pub fn clear_expiry(&mut self, doc_id: usize) -> Result<(), VectorError> {
+        trace!("Clearing expiry of doc {}", doc_id);
+        namespaces::check_doc_id(doc_id)?;
+        let result = storage::write(&self.env, |txn| self.expiry.clear_in(txn, &[doc_id]));
+
+        result.map_err(|e| {
+            error!("Error clearing the expiry of document {}: {}", doc_id, e);
+            e.into()
+        })
+    }
+
+    /// Turns the vectors of the documents that already expired into tombstones.
+    fn expire(&mut self) {
+        let now = expiry::now();
+        let result = storage::write(&self.env, |txn| {
+            let expired_docs = self.expiry.expired_in(txn, now)?;
+            let mut expired = Vec::new();
+            for doc_id in &expired_docs {
+                expired.extend(self.index_map.get_vec_ids_in(txn, *doc_id)?);
+            }
+            self.deleted.add_batch_in(txn, expired.iter().copied())?;
+            self.expiry.clear_in(txn, &expired_docs)?;
+            Ok(expired)
+        });
+
+        match result {
+            Ok(expired) => {
+                debug!("{} vectors expired", expired.len());
+                let mut tombstones = self.tombstones.lock().unwrap();
+                for idx in expired {
+                    tombstones.insert(idx);
+                }
+            }
+            Err(e) => error!("Error expiring vectors: {}", e),
+        }
+    }
+
+    /// Passes the vectors of the main space that are not deleted to `f`, with their documents,
+    /// metadata and expiry times. The full-precision copies are used when the index keeps them.
+    ///
+    /// The vectors are read `EXPORT_BATCH` at a time, only the metadata and the expiry times are
+    /// loaded whole.
+    pub(crate) fn export<F>(&self, mut f: F) -> Result<(), VectorError>
+    where
+        F: FnMut(ExportedVector) -> Result<(), VectorError>,
+    {
+        let elements = self.full_precision.as_ref().unwrap_or(&self.elements);
+        let mut metadata = self.metadata.all()?;
+        let expirations = self.expiry.all()?;
+
+        for start in (0..self.elements.len()).step_by(EXPORT_BATCH) {
+            let end = (start + EXPORT_BATCH).min(self.elements.len());
+            let live: Vec<_> = {
+                let tombstones = self.tombstones.lock().unwrap();
+                self.index_map
+                    .vec_ids_in_range(start..end)?
+                    .into_iter()
+                    .filter(|(vec_id, _)| !tombstones.contains(*vec_id))
+                    .collect()
+            };
+            for (vec_id, doc_id) in live {
+                f(ExportedVector {
                     doc_id,
-                    e.to_string()
-                );
-                Err(e.to_string())
+                    vector: elements.get_element(vec_id).0.to_vec(),
+                    metadata: metadata.remove(&vec_id).unwrap_or_default(),
+                    expires_at: expirations.get(&doc_id).copied(),
+                })?;
             }
         }
+        Ok(())
+    }
+
+    pub fn commit_policy(&self) -> CommitPolicy {
+        self.commit_policy
+    }
+
+    /// Writes made since the last commit started.
+    pub fn pending(&self) -> PendingWrites {
+        *self.pending.lock().unwrap()
+    }
+
+    /// Whether the pending writes reach one of the limits of the commit policy.
+    pub fn needs_commit(&self) -> bool {
+        self.pending.lock().unwrap().is_due(&self.commit_policy)
+    }
+
+    /// Last generation committed, 0 before the first commit.
+    pub fn generation(&self) -> u64 {
+        match &self.in_flight {
+            Some(in_flight) if in_flight.progress.outcome() == Some(true) => in_flight.progress.generation(),
+            _ => self.generation,
+        }
     }
 
-    pub fn commit(&mut self) {
-        let t0 = Instant::now();
-        let mut builder = GranneBuilder::new(self.build_config, self.elements.clone());
-        debug!("Builder made in {:?}", t0.elapsed());
+    /// Commits the changes as a new generation of the index, waiting for the commit running in
+    /// the background if there is one. Returns the generation committed. When the commit fails,
+    /// its writes are pending again and the next commit writes them.
+    pub fn commit(&mut self) -> Result<u64, VectorError> {
+        self.settle_commit();
+        let (commit, written, pending) = self.snapshot();
+        self.run_commit(commit, written, pending)
+    }
 
-        debug!("Start building index!");
-        let t0 = Instant::now();
-        builder.build();
-        debug!("Index built in {:?}", t0.elapsed());
+    /// Commits with `elements` and `full_precision` as the vectors of the main space, the ones of
+    /// the writer mapped from another file, and maps the files committed.
+    pub(crate) fn commit_mapped(
+        &mut self,
+        elements: Elements<'a>,
+        full_precision: Option<Elements<'a>>,
+    ) -> Result<u64, VectorError> {
+        self.settle_commit();
+        let (commit, written, pending) = self.snapshot_with(elements, full_precision);
+        self.run_commit(commit, written, pending)
+    }
 
-        let tmp_elements = self.save_elements(&builder);
-        let tmp_index = self.save_index(&builder);
+    /// Runs a snapshot on the calling thread. A commit that fails gives back what the snapshot
+    /// took, like `settle_commit` does for the background ones.
+    fn run_commit(
+        &mut self,
+        commit: PendingCommit<'a>,
+        written: Written,
+        pending: PendingWrites,
+    ) -> Result<u64, VectorError> {
+        let progress = CommitProgress::new(commit.generation, commit.num_vectors(), false);
+        let removed_namespaces = commit.removed_namespaces.to_vec();
+        match commit.run(&progress) {
+            Ok(generation) => {
+                self.generation = generation;
+                self.remap(&written);
+                Ok(generation)
+            }
+            Err(e) => {
+                self.mark_dirty(written);
+                self.removed_namespaces.extend(removed_namespaces);
+                self.pending.lock().unwrap().restore(pending);
+                Err(e)
+            }
+        }
+    }
+
+    /// Maps the files of vectors appended to by a commit, so the writer only keeps in memory the
+    /// vectors pushed after it.
+    fn remap(&mut self, written: &Written) {
+        if self.elements.sum_embeddings().is_none() {
+            Writer::remap_elements(&mut self.elements, self.location.elements_path());
+        }
+        if let Some(full_precision) = &mut self.full_precision {
+            Writer::remap_elements(full_precision, self.location.full_precision_path());
+        }
+        for name in &written.spaces {
+            if let Some(space) = self.spaces.get_mut(name) {
+                Writer::remap_elements(&mut space.elements, space.location.elements_path());
+            }
+        }
+        for name in &written.namespaces {
+            let Some(namespace) = self.namespaces.get_mut(name) else {
+                continue;
+            };
+            // A namespace deleted and added again since has a file with the vectors of the old one.
+            if !self.removed_namespaces.iter().any(|removed| removed.path() == namespace.location.path()) {
+                Writer::remap_elements(&mut namespace.elements, namespace.location.elements_path());
+            }
+        }
+    }
 
-        self.commit_files(tmp_elements, tmp_index);
-        self.set_dirty();
+    fn remap_elements(elements: &mut Elements<'a>, elements_path: PathBuf) {
+        let remapped = File::open(&elements_path).and_then(|file| unsafe { elements.remap(&file) });
+        if let Err(e) = remapped {
+            error!("Error mapping {:?}: {}", elements_path, e);
+        }
     }
 
-    fn set_dirty(&self) {
-        match File::create(self.location.dirty_path()) {
-            Ok(_) => debug!("Set dirty file"),
-            Err(e) => error!("Error setting dirty file: {}", e.to_string()),
+    /// Marks the spaces and namespaces of a commit that didn't publish its generation, so the
+    /// next commit writes them.
+    fn mark_dirty(&mut self, written: Written) {
+        for name in written.spaces {
+            if let Some(space) = self.spaces.get_mut(&name) {
+                space.dirty = true;
+            }
+        }
+        for name in written.namespaces {
+            if let Some(namespace) = self.namespaces.get_mut(&name) {
+                namespace.dirty = true;
+            }
         }
     }
 
-    fn commit_files(&mut self, tmp_elements: NamedTempFile, tmp_index: NamedTempFile) {
-        debug!("Adquiring commit lock");
-        self.commit_lock.lock();
-        std::fs::create_dir_all(&self.location.path()).unwrap();
-        self.swap_files(tmp_elements.path(), &self.location.elements_path());
-        self.swap_files(tmp_index.path(), &self.location.index_path());
-        debug!("Releasing commit lock");
-        self.commit_lock.unlock();
+    /// Waits for the commit running in the background, if any. When it didn't publish its
+    /// generation, the next commit writes the spaces and namespaces it had taken, and its writes
+    /// are pending again.
+    pub(crate) fn settle_commit(&mut self) {
+        let Some(in_flight) = self.in_flight.take() else {
+            return;
+        };
+        if in_flight.progress.wait() {
+            self.generation = in_flight.progress.generation();
+            self.remap(&in_flight.written);
+            return;
+        }
+        self.mark_dirty(in_flight.written);
+        self.removed_namespaces.extend(in_flight.removed_namespaces);
+        self.pending.lock().unwrap().restore(in_flight.pending);
     }
 
-    fn swap_files<T: Into<PathBuf>>(&self, orig: T, dest: T) {
-        let orig = orig.into();
-        let dest = dest.into();
-        Writer::remove_file(dest.clone());
-        debug!("Moving {:?} -> {:?}", orig, dest);
-        std::fs::rename(orig, dest).unwrap();
+    /// Takes what the next commit writes, with the names of the spaces and namespaces it writes
+    /// and the pending writes it covers. Only the spaces and namespaces changed since the last
+    /// commit are written. The graphs are built by the commit itself, over clones of the
+    /// elements that share their mapped vectors and only copy the ones pushed since the last
+    /// commit.
+    fn snapshot(&mut self) -> (PendingCommit<'a>, Written, PendingWrites) {
+        self.snapshot_with(self.elements.clone(), self.full_precision.clone())
     }
 
-    fn remove_file<T: Into<PathBuf>>(path: T) {
-        let path = path.into();
-        match std::fs::remove_file(path.clone()) {
-            Ok(_) => debug!("Removed {:?}", path),
-            Err(_) => trace!("Remove ignored, {:?} doesn't exist", path),
+    fn snapshot_with(
+        &mut self,
+        elements: Elements<'a>,
+        full_precision: Option<Elements<'a>>,
+    ) -> (PendingCommit<'a>, Written, PendingWrites) {
+        self.expire();
+
+        let mut files = vec![(self.save_tombstones(), self.location.tombstones_path())];
+        if let Some(tmp_embeddings) = self.save_embeddings() {
+            files.push((tmp_embeddings, self.location.embeddings_path()));
+        }
+        if let Some(tmp_schema) = self.save_schema() {
+            files.push((tmp_schema, self.location.schema_path()));
+        }
+        if !self.space_registry.is_empty() {
+            files.push((self.save_space_registry(), self.location.space_registry_path()));
+        }
+        if !self.namespace_registry.is_empty() || self.location.namespace_registry_path().exists() {
+            files.push((self.save_namespace_registry(), self.location.namespace_registry_path()));
+        }
+
+        let mut graphs = Vec::new();
+        let mut written = Written::default();
+        for (name, space) in self.spaces.iter_mut().filter(|(_, space)| space.dirty) {
+            // Spaces added since the last commit get their schema with their first files.
+            if !space.location.schema_path().exists() {
+                files.push((Writer::save_space_schema(&space.schema), space.location.schema_path()));
+            }
+            graphs.push((space.location.clone(), space.elements.clone()));
+            space.dirty = false;
+            written.spaces.push(name.clone());
+        }
+        let mut appends = Vec::new();
+        for (name, namespace) in self.namespaces.iter_mut().filter(|(_, namespace)| namespace.dirty) {
+            if namespace.elements.len() < self.namespace_graph_threshold {
+                let fresh = self.removed_namespaces.iter().any(|removed| removed.path() == namespace.location.path());
+                appends.push(commit::save_elements(&namespace.elements, namespace.location.elements_path(), fresh));
+            } else {
+                graphs.push((namespace.location.clone(), namespace.elements.clone()));
+            }
+            namespace.dirty = false;
+            written.namespaces.push(name.clone());
         }
+
+        let pending = std::mem::take(self.pending.get_mut().unwrap());
+        let commit = PendingCommit {
+            location: self.location.clone(),
+            generation: self.generation + 1,
+            build_config: self.build_config,
+            elements,
+            full_precision,
+            product_quantization: self.product_quantization,
+            binary_quantization: self.binary_quantization,
+            graphs,
+            files,
+            appends,
+            removed_namespaces: std::mem::take(&mut self.removed_namespaces),
+        };
+        (commit, written, pending)
     }
 
-    fn save_index(&self, builder: &GranneBuilder<Vectors>) -> NamedTempFile {
-        let mut tmpfile = NamedTempFile::new().unwrap();
+    fn save_tombstones(&self) -> NamedTempFile {
+        let tmpfile = NamedTempFile::new().unwrap();
 
-        let t0 = Instant::now();
-        debug!("Writing index to file...");
-        builder.write_index(&mut tmpfile).unwrap();
-        trace!("Index wrote in {:?}", t0.elapsed());
+        let tombstones = self.tombstones.lock().unwrap();
+        debug!("Writing {} tombstones to file...", tombstones.len());
+        tombstones.save(tmpfile.as_file()).unwrap();
 
         tmpfile
     }
 
-    fn save_elements(&self, builder: &GranneBuilder<Vectors>) -> NamedTempFile {
+    fn save_embeddings(&self) -> Option<NamedTempFile> {
+        let embeddings = self.elements.sum_embeddings()?;
         let mut tmpfile = NamedTempFile::new().unwrap();
 
-        let t0 = Instant::now();
-        debug!("Writing elements to file...");
-        builder.write_elements(&mut tmpfile).unwrap();
-        trace!("Elements wrote in {:?}", t0.elapsed());
+        debug!("Writing {} embeddings to file...", embeddings.num_embeddings());
+        embeddings.write_embeddings(&mut tmpfile).unwrap();
+
+        Some(tmpfile)
+    }
+
+    fn save_space_registry(&self) -> NamedTempFile {
+        let tmpfile = NamedTempFile::new().unwrap();
+        debug!("Writing {} spaces to file...", self.space_registry.len());
+        self.space_registry.save(tmpfile.as_file()).unwrap();
+
+        tmpfile
+    }
+
+    fn save_namespace_registry(&self) -> NamedTempFile {
+        let tmpfile = NamedTempFile::new().unwrap();
+        debug!("Writing {} namespaces to file...", self.namespace_registry.len());
+        self.namespace_registry.save(tmpfile.as_file()).unwrap();
+
+        tmpfile
+    }
+
+    /// Writes the schema if it is not recorded yet.
+    fn save_schema(&self) -> Option<NamedTempFile> {
+        let schema = self.schema?;
+        if self.location.schema_path().exists() {
+            return None;
+        }
+
+        let tmpfile = NamedTempFile::new().unwrap();
+        debug!("Writing schema {:?} to file...", schema);
+        schema.save(tmpfile.as_file()).unwrap();
+
+        Some(tmpfile)
+    }
+
+    fn save_space_schema(schema: &Schema) -> NamedTempFile {
+        let tmpfile = NamedTempFile::new().unwrap();
+        debug!("Writing space schema {:?} to file...", schema);
+        schema.save(tmpfile.as_file()).unwrap();
 
         tmpfile
     }
@@ -233,6 +1144,33 @@ impl<'a> Writer<'a> {
     }
 }
 
+impl Writer<'static> {
+    /// Starts a commit on a thread of its own and returns its handle. The writer keeps taking
+    /// changes meanwhile, which go to the next commit.
+    ///
+    /// Fails with `VectorError::Locked` while the previous background commit runs.
+    pub fn commit_async(&mut self) -> Result<CommitHandle, VectorError> {
+        if let Some(in_flight) = &self.in_flight {
+            if in_flight.progress.outcome().is_none() {
+                return Err(VectorError::Locked("a commit is running".to_string()));
+            }
+        }
+        self.settle_commit();
+
+        let (commit, written, pending) = self.snapshot();
+        let progress = Arc::new(CommitProgress::new(commit.generation, commit.num_vectors(), true));
+        let removed_namespaces = commit.removed_namespaces.to_vec();
+        let handle = CommitHandle::spawn(commit, progress.clone())?;
+        self.in_flight = Some(InFlight {
+            progress,
+            written,
+            removed_namespaces,
+            pending,
+        });
+        Ok(handle)
+    }
+}
+
 unsafe impl Send for Writer<'_> {}
 unsafe impl Sync for Writer<'_> {}
 